@@ -0,0 +1,47 @@
+#![feature(test)]
+
+#[path = "../src/testdata.rs"]
+mod testdata;
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use crate::testdata;
+    use oozextract::Extractor;
+
+    /// Compressed files and their decompressed sizes, one per codec that pipelined mode splits.
+    fn corpus() -> Vec<(Vec<u8>, usize)> {
+        testdata::xml_streams()
+            .into_iter()
+            .filter(|(path, ..)| {
+                let extension = path.extension().unwrap().to_str().unwrap();
+                ["kraken", "mermaid", "selkie"].contains(&extension)
+            })
+            .map(|(_, stream, len)| (stream, len))
+            .collect()
+    }
+
+    fn bench(b: &mut test::Bencher, pipelined: bool) {
+        let corpus = corpus();
+        let mut outputs: Vec<_> = corpus.iter().map(|(_, len)| vec![0; *len]).collect();
+        b.bytes = corpus.iter().map(|(_, len)| *len as u64).sum();
+        b.iter(|| {
+            for ((input, _), output) in corpus.iter().zip(outputs.iter_mut()) {
+                let mut extractor = Extractor::new(input.as_slice());
+                extractor.set_pipelined(pipelined);
+                extractor.read(output).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    fn sequential_bench(b: &mut test::Bencher) {
+        bench(b, false)
+    }
+
+    #[bench]
+    fn pipelined_bench(b: &mut test::Bencher) {
+        bench(b, true)
+    }
+}
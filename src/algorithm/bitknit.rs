@@ -1,6 +1,7 @@
 use crate::core::error::{
     End, ErrorBuilder, ErrorContext, Res, ResultBuilder, SliceErrors, WithContext,
 };
+use crate::core::state::{persist_fields, Persist};
 use End::Len;
 
 #[derive(Copy, Clone)]
@@ -115,6 +116,22 @@ impl<const F: usize, const A: usize, const L: usize> Default for Base<F, A, L> {
     }
 }
 
+impl<const F: usize, const A: usize, const L: usize> Persist for Base<F, A, L> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.a.save(out);
+        self.freq.save(out);
+        self.adapt_interval.save(out);
+    }
+
+    /// The lookup table is rebuilt from the loaded cumulative frequencies.
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        self.a.load(input)?;
+        self.freq.load(input)?;
+        self.adapt_interval.load(input)?;
+        Ok(self.fill_lut().at(self)?)
+    }
+}
+
 pub(crate) struct BitknitState {
     recent_dist: [u32; 8],
     last_match_dist: u32,
@@ -144,6 +161,21 @@ impl BitknitState {
     }
 }
 
+impl Default for BitknitState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+persist_fields!(BitknitState {
+    recent_dist,
+    last_match_dist,
+    recent_dist_mask,
+    literals,
+    distance_lsb,
+    distance_bits,
+});
+
 pub(crate) struct Bitknit<'a> {
     state: &'a mut BitknitState,
     input: &'a [u8],
@@ -387,6 +419,7 @@ impl<'a> Bitknit<'a> {
                 recent_mask = (recent_mask & mask) | ((idx + 8 * recent_mask) & !mask);
             }
 
+            self.assert_le(match_dist as usize, self.dst)?;
             if match_dist == 1 {
                 let v = self.output.get_copy(self.dst - 1)?;
                 self.output.slice_mut(self.dst, Len(copy_length))?.fill(v);
@@ -1,4 +1,4 @@
-use crate::algorithm::Algorithm;
+use crate::algorithm::{Algorithm, TwoPhase};
 use crate::core::error::{ErrorContext, Res, ResultBuilder, SliceErrors, WithContext};
 use crate::core::pointer::Pointer;
 use crate::core::Core;
@@ -7,7 +7,7 @@ use crate::core::Core;
 // all the literals and copy lengths using huffman and second
 // phase runs the copy loop. This holds the tables needed by stage 2.
 #[derive(Default)]
-pub(crate) struct KrakenLzTable {
+pub struct KrakenLzTable {
     // Stream of (literal, match) pairs. The flag u8 contains
     // the length of the match, the length of the literal and whether
     // to use a recent offset.
@@ -44,10 +44,62 @@ impl Algorithm for Kraken {
         dst: Pointer,
         dst_size: usize,
     ) -> Res<()> {
+        let lz = self.read_tables(core, mode, src, src_used, dst_start, dst, dst_size)?;
+        self.copy(core, lz, mode, src, src_used, dst_start, dst, dst_size)
+    }
+
+    fn chunk_len(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        offset: usize,
+        max_size: usize,
+    ) -> Res<usize> {
+        let mut lz = KrakenLzTable::default();
+        lz.assert_le(mode, 1)?;
+        lz.read_lz_table(core, src, src + src_used, core.dst, max_size, offset)?;
+        lz.output_len(core, offset)
+    }
+}
+
+impl TwoPhase for Kraken {
+    type Tables = KrakenLzTable;
+
+    fn read_tables(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        dst_start: Pointer,
+        dst: Pointer,
+        dst_size: usize,
+    ) -> Res<KrakenLzTable> {
         let mut lz = KrakenLzTable::default();
         lz.assert_le(mode, 1)?;
         let offset = (dst - dst_start)?;
         lz.read_lz_table(core, src, src + src_used, dst, dst_size, offset)?;
+        Ok(lz)
+    }
+
+    fn copy(
+        &self,
+        core: &mut Core,
+        mut lz: KrakenLzTable,
+        mode: usize,
+        src: Pointer,
+        _src_used: usize,
+        dst_start: Pointer,
+        dst: Pointer,
+        dst_size: usize,
+    ) -> Res<()> {
+        let offset = (dst - dst_start)?;
+        if offset == 0 {
+            // the first bytes of the output are stored raw
+            core.copy_bytes(dst, src, 8).at(&lz)?;
+        }
         lz.process_lz_runs(core, mode, dst, dst_size, offset)
     }
 }
@@ -72,7 +124,7 @@ impl KrakenLzTable {
         self.assert_le(13, (src_end - src)?)?;
 
         if offset == 0 {
-            core.copy_bytes(dst, src, 8).at(self)?;
+            // copied to the output with the matches
             dst += 8;
             src += 8;
         }
@@ -225,6 +277,41 @@ impl KrakenLzTable {
         Ok(())
     }
 
+    /// Number of bytes the literals and matches add up to.
+    fn output_len(&self, core: &mut Core, offset: usize) -> Res<usize> {
+        let (_, matches) = self.run_lens(core)?;
+        Ok(if offset == 0 { 8 } else { 0 } + self.lit_stream_size + matches)
+    }
+
+    /// Number of bytes the commands copy from the literal stream, not counting the literals
+    /// after the last command, and from matches.
+    fn run_lens(&self, core: &mut Core) -> Res<(usize, usize)> {
+        let mut literals = 0;
+        let mut matches = 0;
+        let mut len_stream = self.len_stream.iter().copied();
+        let mut offsets = 0;
+        for &f in core.get_slice(self.cmd_stream, self.cmd_stream_size)? {
+            let f = f as usize;
+            literals += if f & 3 == 3 {
+                usize::try_from(len_stream.next().err()?).at(self)?
+            } else {
+                f & 3
+            };
+            if f >> 6 == 3 {
+                offsets += 1;
+            }
+            let matchlen = (f >> 2) & 0xF;
+            matches += if matchlen != 15 {
+                matchlen + 2
+            } else {
+                usize::try_from(14 + len_stream.next().err()?).at(self)?
+            };
+        }
+        self.assert_eq(offsets, self.offs_stream.len())?;
+        self.assert_eq(len_stream.len(), 0)?;
+        Ok((literals, matches))
+    }
+
     fn process_lz_runs(
         &mut self,
         core: &mut Core,
@@ -267,7 +354,7 @@ impl KrakenLzTable {
             recent_offs[6] = offs_stream.peek().copied().unwrap_or_default();
 
             if mode == 0 {
-                core.copy_64_add(dst, lit_stream, dst + last_offset, litlen)
+                core.copy_64_add(dst, lit_stream, dst.offset(last_offset)?, litlen)
                     .at(self)?;
             } else {
                 core.copy_bytes(dst, lit_stream, litlen).at(self)?;
@@ -284,7 +371,7 @@ impl KrakenLzTable {
                 offs_stream.next();
             }
 
-            copyfrom = dst + offset;
+            copyfrom = dst.offset(offset)?;
             if matchlen != 15 {
                 core.repeat_copy_64(dst, copyfrom, matchlen + 2).at(self)?;
                 dst += matchlen + 2;
@@ -304,7 +391,7 @@ impl KrakenLzTable {
         self.assert_eq(final_len, (lit_stream_end - lit_stream)?)?;
 
         if mode == 0 {
-            core.copy_64_add(dst, lit_stream, dst + last_offset, final_len)
+            core.copy_64_add(dst, lit_stream, dst.offset(last_offset)?, final_len)
                 .at(self)?;
         } else {
             core.copy_bytes(dst, lit_stream, final_len).at(self)?;
@@ -36,6 +36,20 @@ impl Algorithm for Leviathan {
         lz.read_lz_table(core, mode, src, src + src_used, dst, dst_size, offset)?;
         lz.process_lz_runs(core, mode, dst, dst_size, offset)
     }
+
+    fn chunk_len(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        offset: usize,
+        max_size: usize,
+    ) -> Res<usize> {
+        let mut lz = LeviathanLzTable::default();
+        lz.read_lz_table(core, mode, src, src + src_used, core.dst, max_size, offset)?;
+        lz.output_len(core, offset)
+    }
 }
 
 impl LeviathanLzTable {
@@ -242,6 +256,49 @@ impl LeviathanLzTable {
 
         Ok(())
     }
+    /// The command stream, or the 8 streams of multi command chunks, with their sizes.
+    fn cmd_streams(&self) -> Vec<(Pointer, usize)> {
+        if self.cmd_stream.is_null() {
+            self.multi_cmd_ptr
+                .iter()
+                .copied()
+                .zip(self.multi_cmd_end.iter().copied())
+                .collect()
+        } else {
+            vec![(self.cmd_stream, self.cmd_stream_size)]
+        }
+    }
+
+    /// Number of bytes the literals and matches add up to. The order of the commands
+    /// doesn't matter, since long matches take their lengths from the end of the length stream.
+    fn output_len(&self, core: &mut Core, offset: usize) -> Res<usize> {
+        let mut len = if offset == 0 { 8 } else { 0 } + self.lit_stream_total;
+        let mut offsets = 0;
+        let mut long_literals = 0;
+        let mut long_matches = 0;
+        for (stream, size) in self.cmd_streams() {
+            for &cmd in core.get_slice(stream, size)? {
+                if cmd >> 5 == 7 {
+                    offsets += 1;
+                }
+                if cmd & 0x18 == 0x18 {
+                    long_literals += 1;
+                }
+                if cmd & 7 == 7 {
+                    long_matches += 1;
+                } else {
+                    len += usize::from(cmd & 7) + 2;
+                }
+            }
+        }
+        self.assert_eq(offsets, self.offs_stream.len())?;
+        self.assert_eq(long_literals + long_matches, self.len_stream.len())?;
+        for &matchlen in self.len_stream.get(long_literals..).err()? {
+            len += usize::try_from(matchlen + 6).at(self)?;
+        }
+        Ok(len)
+    }
+
     pub fn process_lz_runs(
         &mut self,
         core: &mut Core,
@@ -295,14 +352,22 @@ impl LeviathanLzTable {
 
         let mut cmd_stream_left = 0;
         let mut multi_cmd_stream = [Pointer::null(); 8];
+        // the ends of the streams, so that one can't run into the next
+        let mut multi_cmd_limit = [Pointer::null(); 8];
+        let mut cmd_stream_limit = Pointer::null();
         let mut cmd_stream_ptr = &mut multi_cmd_stream[0];
         if multi_cmd {
-            for (i, p) in multi_cmd_stream.iter_mut().enumerate() {
-                *p = self
-                    .multi_cmd_ptr
-                    .get_copy(i.wrapping_sub(dst_start.index) & 7)?;
+            for (i, (p, limit)) in multi_cmd_stream
+                .iter_mut()
+                .zip(&mut multi_cmd_limit)
+                .enumerate()
+            {
+                let stream = i.wrapping_sub(dst_start.index) & 7;
+                *p = self.multi_cmd_ptr.get_copy(stream)?;
+                *limit = *p + self.multi_cmd_end.get_copy(stream)?;
             }
             cmd_stream_left = self.cmd_stream_size;
+            cmd_stream_limit = multi_cmd_limit.get_copy(dst.index & 7)?;
             cmd_stream_ptr = &mut multi_cmd_stream[dst.index & 7];
             cmd_stream = *cmd_stream_ptr;
         }
@@ -321,6 +386,7 @@ impl LeviathanLzTable {
                     break;
                 }
                 cmd_stream_left -= 1;
+                self.assert_lt(cmd_stream, cmd_stream_limit)?;
                 cmd = core.get_byte(cmd_stream).at(self)? as usize;
                 *cmd_stream_ptr = cmd_stream + 1;
             }
@@ -343,7 +409,7 @@ impl LeviathanLzTable {
                 offs_stream.next();
             }
 
-            copyfrom = dst + offset;
+            copyfrom = dst.offset(offset)?;
             self.assert_le(window_base, copyfrom)?;
 
             if matchlen == 9 {
@@ -354,6 +420,7 @@ impl LeviathanLzTable {
                 core.repeat_copy_64(dst, copyfrom, matchlen).at(self)?;
                 dst += matchlen;
                 if multi_cmd {
+                    cmd_stream_limit = multi_cmd_limit.get_copy(dst.index & 7)?;
                     cmd_stream_ptr = &mut multi_cmd_stream[dst.index & 7];
                     cmd_stream = *cmd_stream_ptr;
                 }
@@ -361,6 +428,7 @@ impl LeviathanLzTable {
                 core.repeat_copy_64(dst, copyfrom, matchlen).at(self)?;
                 dst += matchlen;
                 if multi_cmd {
+                    cmd_stream_limit = multi_cmd_limit.get_copy(dst.index & 7)?;
                     cmd_stream_ptr = &mut multi_cmd_stream[dst.index & 7];
                     cmd_stream = *cmd_stream_ptr;
                 }
@@ -428,7 +496,7 @@ impl LeviathanMode for LeviathanModeSub {
         if litlen == 3 {
             litlen = (len_stream.next().err()? & 0xffffff) as usize;
         }
-        core.copy_64_add(*dst, self.lit_stream, *dst + last_offset, litlen)
+        core.copy_64_add(*dst, self.lit_stream, dst.offset(last_offset)?, litlen)
             .at(self)?;
         *dst += litlen;
         self.lit_stream += litlen;
@@ -442,7 +510,7 @@ impl LeviathanMode for LeviathanModeSub {
         dst: &mut Pointer,
         last_offset: i32,
     ) -> Res<()> {
-        core.copy_64_add(*dst, self.lit_stream, *dst + last_offset, final_len)
+        core.copy_64_add(*dst, self.lit_stream, dst.offset(last_offset)?, final_len)
             .at(self)?;
         *dst += final_len;
         Ok(())
@@ -540,12 +608,12 @@ impl LeviathanMode for LeviathanModeLamSub {
 
         let lam_byte = core
             .get_byte(self.lam_lit_stream)?
-            .wrapping_add(core.get_byte(*dst + last_offset).at(self)?);
+            .wrapping_add(core.get_byte(dst.offset(last_offset)?).at(self)?);
         core.set(*dst, lam_byte).at(self)?;
         self.lam_lit_stream += 1;
         *dst += 1;
 
-        core.copy_64_add(*dst, self.lit_stream, *dst + last_offset, litlen)
+        core.copy_64_add(*dst, self.lit_stream, dst.offset(last_offset)?, litlen)
             .at(self)?;
         *dst += litlen;
         self.lit_stream += litlen;
@@ -561,12 +629,12 @@ impl LeviathanMode for LeviathanModeLamSub {
     ) -> Res<()> {
         let lam_byte = core
             .get_byte(self.lam_lit_stream)?
-            .wrapping_add(core.get_byte(*dst + last_offset).at(self)?);
+            .wrapping_add(core.get_byte(dst.offset(last_offset)?).at(self)?);
         core.set(*dst, lam_byte).at(self)?;
         self.lam_lit_stream += 1;
         *dst += 1;
         final_len -= 1;
-        core.copy_64_add(*dst, self.lit_stream, *dst + last_offset, final_len)
+        core.copy_64_add(*dst, self.lit_stream, dst.offset(last_offset)?, final_len)
             .at(self)?;
         *dst += final_len;
         Ok(())
@@ -586,7 +654,7 @@ impl<const NUM: usize> LeviathanModeSubAnd<NUM> {
         core.set(
             *dst,
             core.get_byte(*v)?
-                .wrapping_add(core.get_byte(*dst + last_offset)?),
+                .wrapping_add(core.get_byte(dst.offset(last_offset)?)?),
         )?;
         *v += 1;
         *dst += 1;
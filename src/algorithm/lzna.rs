@@ -1,4 +1,5 @@
 use crate::core::error::{ErrorContext, Res};
+use crate::core::state::persist_fields;
 use std::array;
 use wide::{i16x16, i16x8, CmpGt};
 
@@ -124,6 +125,52 @@ impl Default for LznaFarDistModel {
     }
 }
 
+persist_fields!(LznaNibbleModel { prob });
+persist_fields!(Lzna3bitModel { prob });
+persist_fields!(LznaLiteralModel {
+    upper,
+    lower,
+    nomatch
+});
+persist_fields!(LznaFarDistModel {
+    first_lo,
+    first_hi,
+    second,
+    third
+});
+persist_fields!(LznaNearDistModel {
+    first,
+    second,
+    third
+});
+persist_fields!(LznaLowBitsDistanceModel { d, v });
+persist_fields!(LznaShortLengthRecentModel { a });
+persist_fields!(LznaLongLengthModel {
+    first,
+    second,
+    third
+});
+persist_fields!(LznaState {
+    match_history,
+    literal,
+    is_literal,
+    typ,
+    short_length_recent,
+    long_length_recent,
+    low_bits_of_distance,
+    short_length,
+    near_dist,
+    medium_length,
+    long_length,
+    far_distance,
+});
+
+impl Default for LznaState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LznaState {
     pub fn new() -> Self {
         Self {
@@ -207,24 +254,29 @@ impl<'a> Lzna<'a> {
         (v << 4) | (d & 0xF) as u64
     }
 
+    #[inline(always)]
     fn read_byte(&mut self) -> u8 {
         let v = self.input[self.src];
         self.src += 1;
         v
     }
 
+    #[inline(always)]
     fn read(&mut self) -> u32 {
         let v = u32::from_le_bytes(*self.input[self.src..].first_chunk().unwrap());
         self.src += 4;
         v
     }
 
+    #[inline(always)]
     fn write(&mut self, v: u8) {
         self.output[self.dst] = v;
         self.dst += 1;
     }
 
-    fn copy_offset(&mut self, dist: usize, length: usize) {
+    #[inline(always)]
+    fn copy_offset(&mut self, dist: usize, length: usize) -> Res<()> {
+        self.assert_le(dist, self.dst)?;
         let src = self.dst - dist;
         if dist == 1 {
             let v = self.output[src];
@@ -238,9 +290,11 @@ impl<'a> Lzna<'a> {
             }
         }
         self.dst += length;
+        Ok(())
     }
 
     /// Renormalize by filling up the RANS state and swapping the two streams
+    #[inline(always)]
     fn renormalize(&mut self) {
         let mut x = self.bits_a;
         if x < 0x80000000 {
@@ -251,6 +305,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a single bit with a uniform distribution.
+    #[inline(always)]
     fn read_bool(&mut self) -> bool {
         let r = self.bits_a & 1;
         self.bits_a >>= 1;
@@ -259,6 +314,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a number of bits with a uniform distribution.
+    #[inline(always)]
     fn read_n_bits(&mut self, bits: usize) -> usize {
         let rv = self.bits_a & ((1 << bits) - 1);
         self.bits_a >>= bits;
@@ -267,6 +323,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a 4-bit value using an adaptive RANS model
+    #[inline(always)]
     fn read_nibble(&mut self, model: &mut LznaNibbleModel) -> usize {
         let x = self.bits_a;
 
@@ -289,6 +346,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a 3-bit value using an adaptive RANS model
+    #[inline(always)]
     fn read_3_bits(&mut self, model: &mut Lzna3bitModel) -> usize {
         let x = self.bits_a;
 
@@ -311,6 +369,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a 1-bit value using an adaptive RANS model
+    #[inline(always)]
     fn read_1_bit(&mut self, model: &mut LznaBitModel, nbits: i32, shift: i32) -> usize {
         assert!(nbits < 32);
         let magn = 1u64 << nbits;
@@ -329,6 +388,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a far distance using the far distance model
+    #[inline(always)]
     fn read_far_distance(&mut self, lut: &mut LznaState) -> usize {
         let mut n = self.read_nibble(&mut lut.far_distance.first_lo);
         let mut hi;
@@ -353,6 +413,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a near distance using a near distance model
+    #[inline(always)]
     fn read_near_distance(&mut self, lut: &mut LznaState, idx: usize) -> usize {
         let model = &mut lut.near_dist[idx];
         let nb = self.read_nibble(&mut model.first);
@@ -374,6 +435,7 @@ impl<'a> Lzna<'a> {
     }
 
     /// Read a length using the length model.
+    #[inline(always)]
     fn read_length(&mut self, model: &mut LznaLongLengthModel) -> usize {
         let mut length = self.read_nibble(&mut model.first[self.dst & 3]);
         if length >= 12 {
@@ -393,6 +455,27 @@ impl<'a> Lzna<'a> {
     }
 
     pub(crate) fn decode_quantum(&mut self, lut: &mut LznaState) -> Res<usize> {
+        (crate::core::cpu::kernels().lzna_decode)(self, lut)
+    }
+
+    pub(crate) fn decode_quantum_generic(&mut self, lut: &mut LznaState) -> Res<usize> {
+        self.decode_quantum_impl(lut)
+    }
+
+    /// The generic decoder compiled with AVX2 and BMI enabled, for the compiler to use in its
+    /// own code generation. The `wide` vectors of the 16 lane model updates keep the
+    /// implementation picked for the build target, so they don't get 256 bit registers.
+    ///
+    /// # Safety
+    /// The CPU must support AVX2, BMI1 and BMI2.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2,bmi1,bmi2")]
+    pub(crate) unsafe fn decode_quantum_avx2(&mut self, lut: &mut LznaState) -> Res<usize> {
+        self.decode_quantum_impl(lut)
+    }
+
+    #[inline(always)]
+    fn decode_quantum_impl(&mut self, lut: &mut LznaState) -> Res<usize> {
         lut.preprocess_match_history();
         self.init();
         let mut dist = lut.match_history[4] as usize;
@@ -417,6 +500,7 @@ impl<'a> Lzna<'a> {
             self.write(x as u8);
         }
         while self.dst < dst_end {
+            self.assert_le(dist, self.dst)?;
             let match_val = self.output[self.dst - dist];
 
             if self.read_1_bit(&mut lut.is_literal[(self.dst & 7) + 8 * state], 13, 5) != 0 {
@@ -431,17 +515,17 @@ impl<'a> Lzna<'a> {
                         let length =
                             3 + self.read_1_bit(&mut lut.short_length[state][self.dst & 3], 14, 4);
                         dist = self.read_near_distance(lut, length - 3);
-                        self.copy_offset(dist, length);
+                        self.copy_offset(dist, length)?;
                     } else if x == 2 {
                         // Copy count 5-12
                         let length = 5 + self.read_3_bits(&mut lut.medium_length);
                         dist = self.read_far_distance(lut);
-                        self.copy_offset(dist, length);
+                        self.copy_offset(dist, length)?;
                     } else {
                         // Copy count 13-
                         let length = self.read_length(&mut lut.long_length) + 13;
                         dist = self.read_far_distance(lut);
-                        self.copy_offset(dist, length);
+                        self.copy_offset(dist, length)?;
                     }
                     state = if state >= 7 { 10 } else { 7 };
                     lut.match_history[7] = lut.match_history[6];
@@ -456,7 +540,7 @@ impl<'a> Lzna<'a> {
                     lut.match_history[3 + idx] = lut.match_history[2 + idx];
                     lut.match_history[2 + idx] = lut.match_history[1 + idx];
                     lut.match_history[4] = dist as u32;
-                    self.copy_offset(dist, 2);
+                    self.copy_offset(dist, 2)?;
                     state = if state >= 7 { 11 } else { 8 };
                 } else {
                     let idx = (x - 4) >> 1;
@@ -468,12 +552,12 @@ impl<'a> Lzna<'a> {
                     if x & 1 == 1 {
                         // Copy 11- bytes from recent distance
                         let length = 11 + self.read_length(&mut lut.long_length_recent);
-                        self.copy_offset(dist, length);
+                        self.copy_offset(dist, length)?;
                     } else {
                         // Copy 3-10 bytes from recent distance
                         let length =
                             3 + self.read_3_bits(&mut lut.short_length_recent[idx].a[self.dst & 3]);
-                        self.copy_offset(dist, length);
+                        self.copy_offset(dist, length)?;
                     }
                     state = if state >= 7 { 11 } else { 8 };
                 }
@@ -1,4 +1,4 @@
-use crate::algorithm::Algorithm;
+use crate::algorithm::{Algorithm, TwoPhase};
 use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
 use crate::core::pointer::Pointer;
 use crate::core::Core;
@@ -18,10 +18,82 @@ impl Algorithm for Mermaid {
         dst: Pointer,
         dst_size: usize,
     ) -> Res<()> {
+        let lz = self.read_tables(core, mode, src, src_used, dst_start, dst, dst_size)?;
+        self.copy(core, lz, mode, src, src_used, dst_start, dst, dst_size)
+    }
+
+    fn chunk_len(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        offset: usize,
+        max_size: usize,
+    ) -> Res<usize> {
+        // chunks over 64k split the commands in two, which changes the table layout
+        let mut lz = MermaidLzTable::default();
+        let result = lz
+            .read_lz_table(
+                core,
+                mode,
+                src,
+                src + src_used,
+                core.dst,
+                max_size.min(0x10000),
+                offset,
+            )
+            .and_then(|_| lz.output_len(core, src + src_used, offset));
+        match result {
+            Ok(len) if len <= 0x10000 => return Ok(len),
+            _ if max_size <= 0x10000 => return result,
+            _ => {}
+        }
+        let mut lz = MermaidLzTable::default();
+        lz.read_lz_table(core, mode, src, src + src_used, core.dst, max_size, offset)?;
+        let len = lz.output_len(core, src + src_used, offset)?;
+        lz.assert_lt(0x10000, len)?;
+        Ok(len)
+    }
+}
+
+impl TwoPhase for Mermaid {
+    type Tables = MermaidLzTable;
+
+    fn read_tables(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        dst_start: Pointer,
+        dst: Pointer,
+        dst_size: usize,
+    ) -> Res<MermaidLzTable> {
         let offset = (dst - dst_start)?;
         let mut lz = MermaidLzTable::default();
         lz.read_lz_table(core, mode, src, src + src_used, dst, dst_size, offset)?;
-        lz.process_lz_runs(core, mode, src + src_used, dst, dst_size, offset)
+        Ok(lz)
+    }
+
+    fn copy(
+        &self,
+        core: &mut Core,
+        mut lz: MermaidLzTable,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        dst_start: Pointer,
+        dst: Pointer,
+        dst_size: usize,
+    ) -> Res<()> {
+        let offset = (dst - dst_start)?;
+        if offset == 0 {
+            // the first bytes of the output are stored raw
+            core.copy_bytes(dst, src, 8).at(&lz)?;
+        }
+        let src_end = src + src_used;
+        lz.process_lz_runs(core, mode, src_end, dst, dst_size, offset)
     }
 }
 
@@ -37,7 +109,7 @@ enum Chunk {
 /// Both Mermaid and Selkie use the same on-disk format, only the compressor
 /// differs.
 #[derive(Default)]
-struct MermaidLzTable {
+pub struct MermaidLzTable {
     // Flag stream. Format of flags:
     // Read flagbyte from |cmd_stream|
     // If flagbyte >= 24:
@@ -150,6 +222,51 @@ impl MermaidLzTable {
         Ok(())
     }
 
+    /// Number of bytes the literals and matches add up to. The commands have to use the
+    /// whole length stream, which ends at |src_end|, and all the offsets.
+    fn output_len(&self, core: &mut Core, src_end: Pointer, offset: usize) -> Res<usize> {
+        let mut len = if offset == 0 { 8 } else { 0 } + (self.lit_stream_end - self.lit_stream)?;
+        let mut length_stream = self.length_stream;
+        let mut off16_count = 0;
+        let mut off32_count = 0;
+        for i in 0..self.cmd_stream_2_offs_end {
+            let cmd = core.get_byte(self.cmd_stream + i).at(self)? as usize;
+            if cmd >= 24 {
+                len += (cmd >> 3) & 0xF;
+                if (cmd >> 7) == 0 {
+                    off16_count += 1;
+                }
+            } else if cmd > 2 {
+                len += cmd + 5;
+                off32_count += 1;
+            } else {
+                self.assert_lt(length_stream, src_end)?;
+                let mut length = core.get_byte(length_stream).at(self)? as usize;
+                if length > 251 {
+                    self.assert_le(3, (src_end - length_stream)?)?;
+                    length += core.get_le_bytes(length_stream + 1, 2).at(core)? * 4;
+                    length_stream += 2;
+                }
+                length_stream += 1;
+                // literal runs are counted with the literal stream
+                if cmd == 1 {
+                    len += length + 91;
+                    off16_count += 1;
+                } else if cmd == 2 {
+                    len += length + 29;
+                    off32_count += 1;
+                }
+            }
+        }
+        self.assert_eq(length_stream, src_end)?;
+        self.assert_eq(off16_count, self.off16_stream.len())?;
+        self.assert_eq(
+            off32_count,
+            self.off32_stream_1.len() + self.off32_stream_2.len(),
+        )?;
+        Ok(len)
+    }
+
     fn off32(&self) -> &Vec<u32> {
         match self.off32_stream {
             Chunk::Stream1 => &self.off32_stream_1,
@@ -187,7 +304,7 @@ impl MermaidLzTable {
             if cmd >= 24 {
                 let litlen = cmd & 7;
                 if ADD_MODE {
-                    core.copy_64_add(dst, lit_stream, dst + recent_offs, litlen)
+                    core.copy_64_add(dst, lit_stream, dst.offset(recent_offs)?, litlen)
                         .at(self)?;
                 } else {
                     core.repeat_copy_64(dst, lit_stream, litlen).at(self)?;
@@ -197,7 +314,7 @@ impl MermaidLzTable {
                 if (cmd >> 7) == 0 {
                     recent_offs = -(self.off16_stream.pop_front().unwrap() as i32);
                 }
-                offs_ptr = dst + recent_offs;
+                offs_ptr = dst.offset(recent_offs)?;
                 core.repeat_copy_64(dst, offs_ptr, (cmd >> 3) & 0xF)
                     .at(self)?;
                 dst += (cmd >> 3) & 0xF;
@@ -227,7 +344,7 @@ impl MermaidLzTable {
                 assert!((dst_end - dst)? >= length);
                 assert!((lit_stream_end - lit_stream)? >= length);
                 if ADD_MODE {
-                    core.copy_64_add(dst, lit_stream, dst + recent_offs, length)
+                    core.copy_64_add(dst, lit_stream, dst.offset(recent_offs)?, length)
                         .at(self)?;
                 } else {
                     core.repeat_copy_64(dst, lit_stream, length).at(self)?;
@@ -276,7 +393,7 @@ impl MermaidLzTable {
 
         length = (dst_end - dst)?;
         if ADD_MODE {
-            core.copy_64_add(dst, lit_stream, dst + recent_offs, length)
+            core.copy_64_add(dst, lit_stream, dst.offset(recent_offs)?, length)
                 .at(self)?;
         } else {
             core.repeat_copy_64(dst, lit_stream, length).at(self)?;
@@ -307,11 +424,11 @@ impl MermaidLzTable {
         let mut off32_size_1;
         let mut scratch = Pointer::tmp(0);
 
-        assert!(mode <= 1, "{}", mode);
-        assert!((src_end - src)? >= 10);
+        self.assert_le(mode, 1)?;
+        self.assert_le(10, (src_end - src)?)?;
 
         if offset == 0 {
-            core.copy_bytes(dst, src, 8).at(self)?;
+            // copied to the output with the matches
             dst += 8;
             src += 8;
         }
@@ -354,13 +471,13 @@ impl MermaidLzTable {
         if dst_size <= 0x10000 {
             self.cmd_stream_2_offs = decode_count;
         } else {
-            assert!((src_end - src)? >= 2);
+            self.assert_le(2, (src_end - src)?)?;
             self.cmd_stream_2_offs = core.get_le_bytes(src, 2).at(core)?;
             src += 2;
-            assert!(self.cmd_stream_2_offs <= self.cmd_stream_2_offs_end);
+            self.assert_le(self.cmd_stream_2_offs, self.cmd_stream_2_offs_end)?;
         }
 
-        assert!((src_end - src)? >= 2);
+        self.assert_le(2, (src_end - src)?)?;
 
         let off16_count = core.get_le_bytes(src, 2).at(core)?;
         src += 2;
@@ -415,7 +532,7 @@ impl MermaidLzTable {
             src += off16_count * 2;
         }
 
-        assert!((src_end - src)? >= 3);
+        self.assert_le(3, (src_end - src)?)?;
         let tmp = core.get_le_bytes(src, 3).at(core)?;
         src += 3;
 
@@ -423,12 +540,12 @@ impl MermaidLzTable {
             off32_size_1 = tmp >> 12;
             off32_size_2 = tmp & 0xFFF;
             if off32_size_1 == 4095 {
-                assert!((src_end - src)? >= 2);
+                self.assert_le(2, (src_end - src)?)?;
                 off32_size_1 = core.get_le_bytes(src, 2).at(core)?;
                 src += 2;
             }
             if off32_size_2 == 4095 {
-                assert!((src_end - src)? >= 2);
+                self.assert_le(2, (src_end - src)?)?;
                 off32_size_2 = core.get_le_bytes(src, 2).at(core)?;
                 src += 2;
             }
@@ -471,12 +588,13 @@ impl MermaidLzTable {
     ) -> Res<usize> {
         let mut src_cur = src;
 
-        if offset < (0xC00000 - 1) {
+        // the encoding depends on the position in the whole stream
+        if core.dropped + offset < (0xC00000 - 1) {
             for _ in 0..output_size {
-                assert!((src_end - src_cur)? >= 3);
+                self.assert_le(3, (src_end - src_cur)?)?;
                 let off = core.get_le_bytes(src_cur, 3).at(core)?;
                 src_cur += 3;
-                assert!(off <= offset);
+                self.assert_le(off, offset)?;
                 if stream1 {
                     self.off32_stream_1.push(off as u32)
                 } else {
@@ -486,16 +604,16 @@ impl MermaidLzTable {
             Ok((src_cur - src)?)
         } else {
             for _ in 0..output_size {
-                assert!((src_end - src_cur)? >= 3);
+                self.assert_le(3, (src_end - src_cur)?)?;
                 let mut off = core.get_le_bytes(src_cur, 3).at(core)?;
                 src_cur += 3;
 
                 if off >= 0xc00000 {
-                    assert_ne!(src_cur, src_end);
+                    self.assert_lt(src_cur, src_end)?;
                     off += (core.get_byte(src_cur).at(self)? as usize) << 22;
                     src_cur += 1;
                 }
-                assert!(off <= offset);
+                self.assert_le(off, offset)?;
                 if stream1 {
                     self.off32_stream_1.push(off as u32)
                 } else {
@@ -9,10 +9,10 @@ use crate::core::pointer::Pointer;
 use crate::core::Core;
 
 pub(crate) use bitknit::*;
-pub(crate) use kraken::Kraken;
+pub(crate) use kraken::{Kraken, KrakenLzTable};
 pub(crate) use leviathan::Leviathan;
 pub(crate) use lzna::*;
-pub(crate) use mermaid::Mermaid;
+pub(crate) use mermaid::{Mermaid, MermaidLzTable};
 
 pub trait Algorithm {
     fn process(
@@ -25,4 +25,46 @@ pub trait Algorithm {
         dst: Pointer,
         dst_size: usize,
     ) -> Res<()>;
+
+    /// Number of bytes the LZ chunk of |src_used| bytes at |src| decodes to, worked out from
+    /// its tables without copying any matches. |offset| is the output before the chunk.
+    fn chunk_len(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        offset: usize,
+        max_size: usize,
+    ) -> Res<usize>;
+}
+
+/// An algorithm that decodes each chunk in two phases: entropy decoding the streams,
+/// which only reads the input and the scratch buffers, then the copy loop writing the
+/// output. The first phase of a chunk can run while the previous chunk is copied.
+pub trait TwoPhase: Algorithm {
+    type Tables: Send;
+
+    fn read_tables(
+        &self,
+        core: &mut Core,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        dst_start: Pointer,
+        dst: Pointer,
+        dst_size: usize,
+    ) -> Res<Self::Tables>;
+
+    fn copy(
+        &self,
+        core: &mut Core,
+        tables: Self::Tables,
+        mode: usize,
+        src: Pointer,
+        src_used: usize,
+        dst_start: Pointer,
+        dst: Pointer,
+        dst_size: usize,
+    ) -> Res<()>;
 }
@@ -0,0 +1,259 @@
+//! A common interface to the containers, and a virtual file system over several of them.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Error, ErrorKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Oodle,
+    /// A method this crate can't decompress, such as Zlib.
+    Other(String),
+}
+
+/// A file in an [Archive].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    /// Stored size, or the decompressed size if the container doesn't record it.
+    pub compressed_size: u64,
+    pub size: u64,
+    pub compression: Compression,
+}
+
+/// A container of files. Reading an entry is split in two, so that the decompression,
+/// which only needs a shared reference, can run on several threads in [Archive::extract_all].
+pub trait Archive {
+    /// The files, in the order of the container. Entries are referred to by their index.
+    fn list(&self) -> Vec<ArchiveEntry>;
+
+    /// Reads the bytes of the entry at |index| as they're stored. Containers that are held
+    /// in memory may return nothing, and decode from their own data.
+    fn read_stored(&mut self, index: usize) -> std::io::Result<Vec<u8>>;
+
+    /// Decompresses |stored|, from [Archive::read_stored] for the same entry.
+    fn decode(&self, index: usize, stored: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Reads and decompresses the entry at |index|.
+    fn extract(&mut self, index: usize) -> std::io::Result<Vec<u8>> {
+        let stored = self.read_stored(index)?;
+        self.decode(index, &stored)
+    }
+
+    /// The entry at |index|, decompressed in memory.
+    fn open_entry(&mut self, index: usize) -> std::io::Result<Cursor<Vec<u8>>> {
+        Ok(Cursor::new(self.extract(index)?))
+    }
+
+    /// Decompresses every entry on |threads| threads, reading the stored data on this one.
+    /// |sink| gets the entries in order, and stops the extraction by returning an error.
+    fn extract_all<F>(&mut self, threads: usize, mut sink: F) -> std::io::Result<()>
+    where
+        Self: Sized + Sync,
+        F: FnMut(&ArchiveEntry, Vec<u8>) -> std::io::Result<()>,
+    {
+        let entries = self.list();
+        let threads = threads.max(1);
+        // bounds the stored data held at once
+        let batch = threads * 4;
+        for (start, batch) in (0..entries.len()).step_by(batch).zip(entries.chunks(batch)) {
+            let mut stored = Vec::with_capacity(batch.len());
+            for index in start..start + batch.len() {
+                stored.push((index, self.read_stored(index)?));
+            }
+            let this = &*self;
+            let per_thread = stored.len().div_ceil(threads);
+            let decoded: Vec<std::io::Result<Vec<u8>>> = std::thread::scope(|scope| {
+                let workers: Vec<_> = stored
+                    .chunks(per_thread)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|(index, stored)| this.decode(*index, stored))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|worker| match worker.join() {
+                        Ok(decoded) => decoded,
+                        Err(_) => vec![Err(Error::other("Decoder thread panicked"))],
+                    })
+                    .collect()
+            });
+            for (entry, data) in batch.iter().zip(decoded) {
+                sink(entry, data?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Archives mounted on top of each other, a file in a later archive hiding the file with
+/// the same path in the earlier ones, as with patch paks.
+#[derive(Default)]
+pub struct Vfs<'a> {
+    archives: Vec<Box<dyn Archive + Sync + 'a>>,
+    /// The visible files, with the archive they're in and their index there.
+    entries: Vec<(usize, usize, ArchiveEntry)>,
+    paths: HashMap<String, usize>,
+}
+
+impl<'a> Vfs<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds |archive|, whose files take precedence over those of the archives mounted before.
+    pub fn mount(&mut self, archive: impl Archive + Sync + 'a) {
+        let archive_index = self.archives.len();
+        for (index, entry) in archive.list().into_iter().enumerate() {
+            let file = (archive_index, index, entry);
+            match self.paths.get(&file.2.path) {
+                Some(&i) => {
+                    if let Some(visible) = self.entries.get_mut(i) {
+                        *visible = file;
+                    }
+                }
+                None => {
+                    self.paths.insert(file.2.path.clone(), self.entries.len());
+                    self.entries.push(file);
+                }
+            }
+        }
+        self.archives.push(Box::new(archive));
+    }
+
+    pub fn find(&self, path: &str) -> Option<&ArchiveEntry> {
+        let &i = self.paths.get(path)?;
+        self.entries.get(i).map(|(_, _, entry)| entry)
+    }
+
+    /// Reads the visible file at |path|.
+    pub fn read(&mut self, path: &str) -> std::io::Result<Vec<u8>> {
+        let i = *self
+            .paths
+            .get(path)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, path))?;
+        self.extract(i)
+    }
+
+    fn file(&self, index: usize) -> std::io::Result<(usize, usize)> {
+        match self.entries.get(index) {
+            Some(&(archive, index, _)) => Ok((archive, index)),
+            None => Err(Error::new(ErrorKind::NotFound, format!("entry {}", index))),
+        }
+    }
+}
+
+impl Archive for Vfs<'_> {
+    fn list(&self) -> Vec<ArchiveEntry> {
+        self.entries.iter().map(|(_, _, e)| e.clone()).collect()
+    }
+
+    fn read_stored(&mut self, index: usize) -> std::io::Result<Vec<u8>> {
+        let (archive, index) = self.file(index)?;
+        match self.archives.get_mut(archive) {
+            Some(archive) => archive.read_stored(index),
+            None => Err(Error::other(format!("archive {}", archive))),
+        }
+    }
+
+    fn decode(&self, index: usize, stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        let (archive, index) = self.file(index)?;
+        match self.archives.get(archive) {
+            Some(archive) => archive.decode(index, stored),
+            None => Err(Error::other(format!("archive {}", archive))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dcx::{tests::container, Dcx};
+    use crate::pak::tests::{write_pak, File};
+    use crate::pak::Pak;
+    use crate::tests::sample;
+    use std::io::{Read, Seek, SeekFrom};
+
+    fn file(path: &str, data: Vec<u8>) -> File<'_> {
+        File {
+            path,
+            data,
+            method: 1,
+            flags: 0,
+        }
+    }
+
+    #[test_log::test]
+    fn mounts_archives() {
+        let base = [
+            file("Game/a.bin", sample(100_000, 1)),
+            file("Game/b.bin", sample(2000, 2)),
+            file("Game/c.bin", sample(70_000, 3)),
+        ];
+        let patch = [
+            file("Game/b.bin", sample(3000, 4)),
+            file("Game/d.bin", sample(500, 5)),
+        ];
+        let settings = sample(300_000, 6);
+
+        let mut vfs = Vfs::new();
+        vfs.mount(Pak::open(Cursor::new(write_pak(11, &base))).unwrap());
+        vfs.mount(Pak::open(Cursor::new(write_pak(8, &patch))).unwrap());
+        vfs.mount(Dcx::new(container(&settings), "settings.bin").unwrap());
+
+        let expected = [
+            ("Game/a.bin", &base[0].data),
+            ("Game/b.bin", &patch[0].data),
+            ("Game/c.bin", &base[2].data),
+            ("Game/d.bin", &patch[1].data),
+            ("settings.bin", &settings),
+        ];
+        let entries = vfs.list();
+        assert_eq!(entries.len(), expected.len());
+        for (entry, (path, data)) in entries.iter().zip(expected) {
+            assert_eq!(entry.path, path);
+            assert_eq!(entry.size, data.len() as u64);
+            assert_eq!(entry.compression, Compression::Oodle);
+            assert_eq!(vfs.read(path).unwrap(), *data, "{}", path);
+        }
+        assert_eq!(vfs.find("Game/b.bin").unwrap().size, 3000);
+        assert!(vfs.find("Game/e.bin").is_none());
+        assert_eq!(
+            vfs.read("Game/e.bin").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let mut entry = vfs.open_entry(4).unwrap();
+        entry.seek(SeekFrom::Start(1000)).unwrap();
+        let mut buf = [0; 10];
+        entry.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, settings[1000..1010]);
+
+        for threads in [1, 3] {
+            let mut extracted = Vec::new();
+            vfs.extract_all(threads, |entry, data| {
+                extracted.push((entry.path.clone(), data));
+                Ok(())
+            })
+            .unwrap();
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|(path, data)| (path.to_string(), data.to_vec()))
+                .collect();
+            assert_eq!(extracted, expected);
+        }
+
+        let mut count = 0;
+        let error = vfs.extract_all(2, |_, _| {
+            count += 1;
+            Err(Error::other("stop"))
+        });
+        assert!(error.is_err());
+        assert_eq!(count, 1);
+    }
+}
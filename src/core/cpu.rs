@@ -0,0 +1,177 @@
+use crate::algorithm::{Lzna, LznaState};
+use crate::core::error::Res;
+use crate::core::huffman::{HuffReader, HuffRevLut};
+use crate::core::tans::TansDecoder;
+use crate::core::Core;
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::sync::OnceLock;
+
+/// Instruction set level the hot loops are compiled for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Portable code, vectorized by `wide` with whatever the build target enables.
+    Generic,
+    /// x86 SSE2
+    Sse2,
+    /// x86 AVX2 together with BMI1 and BMI2
+    Avx2,
+}
+
+impl Level {
+    /// The best level the running CPU supports.
+    fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx2")
+                && is_x86_feature_detected!("bmi1")
+                && is_x86_feature_detected!("bmi2")
+            {
+                return Level::Avx2;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return Level::Sse2;
+            }
+        }
+        Level::Generic
+    }
+
+    /// Whether the running CPU can execute code compiled for this level.
+    pub fn is_supported(self) -> bool {
+        self <= Self::detect()
+    }
+}
+
+/// Implementations of the hot kernels for one [Level].
+pub struct Kernels {
+    pub level: Level,
+    /// Bit reverses the index of a Huffman lookup table, see `reverse_lut`.
+    pub reverse_lut: fn(&[u64; 258]) -> [u8; 2048],
+    /// Decodes the three interleaved Huffman streams.
+    pub huff_decode: fn(&mut HuffReader, &mut Core, &HuffRevLut) -> Res<()>,
+    /// Decodes the five interleaved tANS states.
+    pub tans_decode: fn(&mut TansDecoder, &mut Core) -> Res<()>,
+    /// `dst[i] = lhs[i] + rhs[i]`, the inner loop of `Core::copy_64_add`.
+    pub add_bytes: fn(&mut [u8], &[u8], &[u8]),
+    /// Decodes one LZNA quantum, dominated by the 16 lane nibble model updates.
+    pub lzna_decode: fn(&mut Lzna, &mut LznaState) -> Res<usize>,
+}
+
+impl Kernels {
+    /// The kernels for |level|.
+    ///
+    /// # Panics
+    /// If the running CPU doesn't support |level|, as the kernels would be undefined behavior.
+    pub fn new(level: Level) -> Self {
+        // a check in release builds too, it's what makes the unsafe kernels below sound
+        assert!(level.is_supported(), "{:?} isn't supported", level);
+        match level {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Level::Avx2 => Kernels {
+                level,
+                // SAFETY: the CPU has SSE2, AVX2 and BMI, as asserted above
+                reverse_lut: |input| unsafe { crate::core::huffman::reverse_sse(input) },
+                huff_decode: |hr, core, lut| unsafe { hr.decode_bytes_avx2(core, lut) },
+                tans_decode: |tans, core| unsafe { tans.decode_avx2(core) },
+                add_bytes: |dst, lhs, rhs| unsafe { add_bytes_avx2(dst, lhs, rhs) },
+                lzna_decode: |lzna, lut| unsafe { lzna.decode_quantum_avx2(lut) },
+            },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Level::Sse2 => Kernels {
+                level,
+                // SAFETY: the CPU has SSE2, as asserted above
+                reverse_lut: |input| unsafe { crate::core::huffman::reverse_sse(input) },
+                huff_decode: HuffReader::decode_bytes_generic,
+                tans_decode: TansDecoder::decode_generic,
+                add_bytes: |dst, lhs, rhs| unsafe { add_bytes_sse2(dst, lhs, rhs) },
+                lzna_decode: |lzna, lut| lzna.decode_quantum_generic(lut),
+            },
+            _ => Kernels {
+                level: Level::Generic,
+                reverse_lut: crate::core::huffman::reverse_simd,
+                huff_decode: HuffReader::decode_bytes_generic,
+                tans_decode: TansDecoder::decode_generic,
+                add_bytes: add_bytes_generic,
+                lzna_decode: |lzna, lut| lzna.decode_quantum_generic(lut),
+            },
+        }
+    }
+}
+
+/// The kernels for the running CPU, detected on first use.
+pub fn kernels() -> &'static Kernels {
+    static KERNELS: OnceLock<Kernels> = OnceLock::new();
+    KERNELS.get_or_init(|| {
+        let kernels = Kernels::new(Level::detect());
+        log::debug!("Using {:?} kernels", kernels.level);
+        kernels
+    })
+}
+
+pub fn add_bytes_generic(dst: &mut [u8], lhs: &[u8], rhs: &[u8]) {
+    for ((d, &l), &r) in dst.iter_mut().zip(lhs).zip(rhs) {
+        *d = l.wrapping_add(r);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn add_bytes_sse2(dst: &mut [u8], lhs: &[u8], rhs: &[u8]) {
+    let n = dst.len().min(lhs.len()).min(rhs.len());
+    let mut i = 0;
+    while i + 16 <= n {
+        let l = _mm_loadu_si128(lhs.as_ptr().add(i).cast());
+        let r = _mm_loadu_si128(rhs.as_ptr().add(i).cast());
+        _mm_storeu_si128(dst.as_mut_ptr().add(i).cast(), _mm_add_epi8(l, r));
+        i += 16;
+    }
+    if let (Some(dst), Some(lhs), Some(rhs)) = (dst.get_mut(i..), lhs.get(i..), rhs.get(i..)) {
+        add_bytes_generic(dst, lhs, rhs);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn add_bytes_avx2(dst: &mut [u8], lhs: &[u8], rhs: &[u8]) {
+    let n = dst.len().min(lhs.len()).min(rhs.len());
+    let mut i = 0;
+    while i + 32 <= n {
+        let l = _mm256_loadu_si256(lhs.as_ptr().add(i).cast());
+        let r = _mm256_loadu_si256(rhs.as_ptr().add(i).cast());
+        _mm256_storeu_si256(dst.as_mut_ptr().add(i).cast(), _mm256_add_epi8(l, r));
+        i += 32;
+    }
+    if let (Some(dst), Some(lhs), Some(rhs)) = (dst.get_mut(i..), lhs.get(i..), rhs.get(i..)) {
+        add_bytes_sse2(dst, lhs, rhs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supported() -> Vec<Kernels> {
+        [Level::Generic, Level::Sse2, Level::Avx2]
+            .into_iter()
+            .filter(|l| l.is_supported())
+            .map(Kernels::new)
+            .collect()
+    }
+
+    #[test_log::test]
+    fn add_bytes() {
+        let lhs: Vec<u8> = (0..100).map(|i| (i * 37) as u8).collect();
+        let rhs: Vec<u8> = (0..100).map(|i| (i * 91 + 7) as u8).collect();
+        let mut expected = [0; 100];
+        add_bytes_generic(&mut expected, &lhs, &rhs);
+        for kernels in supported() {
+            for n in [0, 1, 15, 16, 33, 100] {
+                let mut dst = [0; 100];
+                (kernels.add_bytes)(&mut dst[..n], &lhs[..n], &rhs[..n]);
+                assert_eq!(dst[..n], expected[..n], "{:?}", kernels.level);
+            }
+        }
+    }
+}
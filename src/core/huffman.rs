@@ -31,6 +31,23 @@ impl ErrorContext for HuffReader {}
 
 impl HuffReader {
     pub fn decode_bytes(&mut self, core: &mut Core, lut: &HuffRevLut) -> Res<()> {
+        (crate::core::cpu::kernels().huff_decode)(self, core, lut)
+    }
+
+    pub fn decode_bytes_generic(&mut self, core: &mut Core, lut: &HuffRevLut) -> Res<()> {
+        self.decode_bytes_impl(core, lut)
+    }
+
+    /// # Safety
+    /// The CPU must support AVX2, BMI1 and BMI2.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2,bmi1,bmi2")]
+    pub unsafe fn decode_bytes_avx2(&mut self, core: &mut Core, lut: &HuffRevLut) -> Res<()> {
+        self.decode_bytes_impl(core, lut)
+    }
+
+    #[inline(always)]
+    fn decode_bytes_impl(&mut self, core: &mut Core, lut: &HuffRevLut) -> Res<()> {
         let mut src = self.src;
         let mut src_bits = self.src_bits;
         let mut src_bitpos = self.src_bitpos;
@@ -234,11 +251,8 @@ impl Core<'_> {
     }
 }
 
-#[allow(unreachable_code)]
 pub fn reverse_lut(input: &[u64; 258]) -> [u8; 2048] {
-    #[cfg(all(feature = "x86_sse", any(target_arch = "x86", target_arch = "x86_64")))]
-    return reverse_sse(bytemuck::cast_slice(input).try_into().unwrap());
-    return reverse_simd(input);
+    (crate::core::cpu::kernels().reverse_lut)(input)
 }
 
 /// 2567.903645833333 ns/iter (+/- 149.404296875) on my machine
@@ -253,13 +267,17 @@ const OFFSETS: [usize; 32] = [
 ];
 
 /// 136.1971197119712 ns/iter (+/- 13.9047404740474) on my machine
-#[allow(dead_code)]
+///
+/// # Safety
+/// The CPU must support SSE2.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub fn reverse_sse(input: &[u8; 2048 + 16]) -> [u8; 2048] {
+#[target_feature(enable = "sse2")]
+pub unsafe fn reverse_sse(input: &[u64; 258]) -> [u8; 2048] {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
     use std::arch::x86_64::*;
+    let input: &[u8] = bytemuck::cast_slice(input);
     let mut result = [0; 2048];
     let mut output = &mut result[..];
     for j in OFFSETS {
@@ -371,12 +389,19 @@ mod tests {
         let input: [u8; 2064] = std::array::from_fn(|i| (i as u8).bitxor((i >> 8) as u8));
         let naive = reverse_naive(&input);
         let simd = reverse_simd(bytemuck::cast_slice(input.as_slice()).try_into().unwrap());
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        let sse = reverse_sse(&input);
+        let dispatched = reverse_lut(bytemuck::cast_slice(input.as_slice()).try_into().unwrap());
         for i in 1..2048 {
-            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-            assert_eq!(naive[i], sse[i], "{}", i);
             assert_eq!(naive[i], simd[i], "{}", i);
+            assert_eq!(naive[i], dispatched[i], "{}", i);
+        }
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if crate::core::cpu::Level::Sse2.is_supported() {
+            // SAFETY: checked above
+            let sse =
+                unsafe { reverse_sse(bytemuck::cast_slice(input.as_slice()).try_into().unwrap()) };
+            for i in 1..2048 {
+                assert_eq!(naive[i], sse[i], "{}", i);
+            }
         }
     }
 }
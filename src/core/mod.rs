@@ -1,10 +1,13 @@
 pub(crate) mod bit_reader;
+pub(crate) mod cpu;
 pub(crate) mod error;
 pub(crate) mod huffman;
 pub(crate) mod pointer;
+pub(crate) mod reader;
+pub(crate) mod state;
 pub(crate) mod tans;
 
-use crate::algorithm::Algorithm;
+use crate::algorithm::{Algorithm, TwoPhase};
 use bit_reader::{BitReader, BitReader2};
 use error::End::Idx;
 use error::{ErrorContext, Res, ResultBuilder, WithContext};
@@ -13,6 +16,37 @@ use pointer::{Pointer, PointerDest};
 use std::fmt::Debug;
 use tans::TansDecoder;
 
+/// A chunk between the two phases of a [TwoPhase] algorithm.
+pub(crate) struct PhasedChunk<T> {
+    src: Pointer,
+    src_used: usize,
+    dst: Pointer,
+    dst_count: usize,
+    scratch: Vec<u8>,
+    tmp: Vec<u8>,
+    tables: ChunkTables<T>,
+}
+
+enum ChunkTables<T> {
+    /// Entropy coded bytes, decoded to the scratch buffer or stored in the input
+    Entropy(Pointer),
+    /// Uncompressed bytes in the input
+    Stored(Pointer),
+    Lz {
+        mode: usize,
+        tables: T,
+    },
+}
+
+/// Sizes from the header of entropy coded bytes, see [Core::decode_bytes].
+struct BytesHeader {
+    chunk_type: usize,
+    /// Length of the header itself
+    len: usize,
+    src_size: usize,
+    dst_size: usize,
+}
+
 pub(crate) struct Core<'a> {
     pub input: &'a [u8],
     pub output: &'a mut [u8],
@@ -21,6 +55,8 @@ pub(crate) struct Core<'a> {
     pub src: Pointer,
     pub dst: Pointer,
     pub dst_end: Pointer,
+    /// Output bytes dropped before |output|, when only a window of the history is kept.
+    pub dropped: usize,
 }
 
 impl Core<'_> {
@@ -38,6 +74,7 @@ impl Core<'_> {
             src: Pointer::input(0),
             dst: Pointer::output(offset),
             dst_end: Pointer::output(offset + out_len),
+            dropped: 0,
         }
     }
 
@@ -98,6 +135,155 @@ impl Core<'_> {
         Ok(self.src.index)
     }
 
+    /// Number of bytes the quantum in the input decodes to, at most |max_size|, after |offset|
+    /// bytes of output. Only the chunk headers are read, and the tables of the last chunk,
+    /// since every chunk before it is a whole 128k.
+    pub fn quantum_len<T: Algorithm>(
+        &mut self,
+        algorithm: &T,
+        offset: usize,
+        max_size: usize,
+    ) -> Res<usize> {
+        let src_end = Pointer::input(self.input.len());
+        let mut len = 0;
+        while self.src < src_end {
+            let max_count = std::cmp::min(max_size - len, 0x20000);
+            self.assert_lt(0, max_count)?;
+            self.assert_le(4, (src_end - self.src)?)?;
+            let chunkhdr = self.get_be_bytes(self.src, 3).at(self)?;
+            let (src_used, count) = if (chunkhdr & 0x800000) == 0 {
+                let header = self.bytes_header(self.src, src_end, max_count)?;
+                (header.len + header.src_size, header.dst_size)
+            } else {
+                self.src += 3;
+                let src_used = chunkhdr & 0x7FFFF;
+                let mode = (chunkhdr >> 19) & 0xF;
+                self.assert_le(src_used, (src_end - self.src)?)?;
+                let count = if self.src + src_used < src_end {
+                    max_count
+                } else {
+                    // a stored chunk is as long as its input, an LZ chunk is longer
+                    match algorithm.chunk_len(
+                        self,
+                        mode,
+                        self.src,
+                        src_used,
+                        offset + len,
+                        max_count,
+                    ) {
+                        Ok(count) if count > src_used => count,
+                        _ if mode == 0 && src_used <= max_count => src_used,
+                        result => self.raise(format!(
+                            "Bad data. src_used: {}, chunk length: {:?}, mode: {}",
+                            src_used,
+                            result.ok(),
+                            mode
+                        ))?,
+                    }
+                };
+                (src_used, count)
+            };
+            self.assert_le(count, max_count)?;
+            self.src += src_used;
+            len += count;
+        }
+        Ok(len)
+    }
+
+    /// First phase of [Core::decode_quantum] for two phase algorithms, which only reads the
+    /// input. Passes each chunk to |emit|, with the scratch buffers its tables point into.
+    /// Returns the number of input bytes used.
+    pub fn read_chunks<T: TwoPhase>(
+        &mut self,
+        algorithm: &T,
+        mut emit: impl FnMut(PhasedChunk<T::Tables>) -> Res<()>,
+    ) -> Res<usize> {
+        let src_end = Pointer::input(self.input.len());
+        let dst_start = Pointer::output(0);
+
+        while self.dst_end > self.dst {
+            let dst_count = std::cmp::min((self.dst_end - self.dst)?, 0x20000);
+            self.assert_le(4, (src_end - self.src)?)?;
+            let chunkhdr = self.get_be_bytes(self.src, 3).at(self)?;
+            let (src_used, tables) = if (chunkhdr & 0x800000) == 0 {
+                let mut out = Pointer::scratch(0);
+                let mut written_bytes = 0;
+                let src_used = self
+                    .decode_bytes(
+                        &mut out,
+                        self.src,
+                        src_end,
+                        &mut written_bytes,
+                        dst_count,
+                        false,
+                        Pointer::scratch(0),
+                    )
+                    .at(self)?;
+                self.assert_eq(written_bytes, dst_count)?;
+                (src_used, ChunkTables::Entropy(out))
+            } else {
+                self.src += 3;
+                let src_used = chunkhdr & 0x7FFFF;
+                let mode = (chunkhdr >> 19) & 0xF;
+                self.assert_le(src_used, (src_end - self.src)?)?;
+                if src_used < dst_count {
+                    let tables = algorithm
+                        .read_tables(
+                            self, mode, self.src, src_used, dst_start, self.dst, dst_count,
+                        )
+                        .at(self)?;
+                    (src_used, ChunkTables::Lz { mode, tables })
+                } else if src_used > dst_count || mode != 0 {
+                    self.raise(format!(
+                        "Bad data. src_used: {}, dst_count: {}, mode: {}",
+                        src_used, dst_count, mode
+                    ))?
+                } else {
+                    (src_used, ChunkTables::Stored(self.src))
+                }
+            };
+            emit(PhasedChunk {
+                src: self.src,
+                src_used,
+                dst: self.dst,
+                dst_count,
+                scratch: std::mem::take(&mut self.scratch),
+                tmp: std::mem::take(&mut self.tmp),
+                tables,
+            })?;
+            self.src += src_used;
+            self.dst += dst_count;
+        }
+
+        Ok(self.src.index)
+    }
+
+    /// Second phase of [Core::decode_quantum], writing a chunk from [Core::read_chunks]
+    /// to the output. Chunks have to be copied in order.
+    pub fn copy_chunk<T: TwoPhase>(
+        &mut self,
+        algorithm: &T,
+        chunk: PhasedChunk<T::Tables>,
+    ) -> Res<()> {
+        self.scratch = chunk.scratch;
+        self.tmp = chunk.tmp;
+        match chunk.tables {
+            ChunkTables::Entropy(data) | ChunkTables::Stored(data) => {
+                Ok(self.copy_bytes(chunk.dst, data, chunk.dst_count).at(self)?)
+            }
+            ChunkTables::Lz { mode, tables } => algorithm.copy(
+                self,
+                tables,
+                mode,
+                chunk.src,
+                chunk.src_used,
+                Pointer::output(0),
+                chunk.dst,
+                chunk.dst_count,
+            ),
+        }
+    }
+
     /// Unpacks the packed 8 bit offset and lengths into 32 bit.
     pub fn unpack_offsets(
         &mut self,
@@ -245,28 +431,11 @@ impl Core<'_> {
         mut scratch: Pointer,
     ) -> Res<usize> {
         let src_org = src;
-        let src_size;
-        let dst_size;
-
-        self.assert_le(2, (src_end - src)?)?;
-
-        let chunk_type = (self.get_byte(src + 0)? as usize >> 4) & 0x7;
+        let header = self.bytes_header(src, src_end, output_size)?;
+        src += header.len;
+        let (src_size, dst_size) = (header.src_size, header.dst_size);
+        let chunk_type = header.chunk_type;
         if chunk_type == 0 {
-            if (self.get_byte(src + 0)? as usize) >= 0x80 {
-                // In this mode, memcopy stores the length in the bottom 12 bits.
-                src_size = (((self.get_byte(src + 0)? as usize) << 8)
-                    | (self.get_byte(src + 1)? as usize))
-                    & 0xFFF;
-                src += 2;
-            } else {
-                self.assert_le(3, (src_end - src)?)?;
-                src_size = self.get_be_bytes(src, 3).at(self)?;
-                // reserved bits must not be set
-                self.assert_eq(src_size & !0x3ffff, 0)?;
-                src += 3;
-            }
-            self.assert_le(src_size, output_size)?;
-            self.assert_le(src_size, (src_end - src)?)?;
             *decoded_size = src_size;
             if force_memmove {
                 self.copy_bytes(*output, src, src_size).at(self)?;
@@ -276,28 +445,6 @@ impl Core<'_> {
             return Ok((src + src_size - src_org)?);
         }
 
-        // In all the other modes, the initial bytes encode
-        // the src_size and the dst_size
-        if self.get_byte(src)? >= 0x80 {
-            self.assert_le(3, (src_end - src)?)?;
-
-            // short mode, 10 bit sizes
-            let bits = self.get_be_bytes(src, 3).at(self)?;
-            src_size = bits & 0x3ff;
-            dst_size = src_size + ((bits >> 10) & 0x3ff) + 1;
-            src += 3;
-        } else {
-            // long mode, 18 bit sizes
-            self.assert_le(5, (src_end - src)?)?;
-            let bits = self.get_be_bytes(src + 1, 4).at(self)?;
-            src_size = bits & 0x3ffff;
-            dst_size = (((bits >> 18) | ((self.get_byte(src + 0)? as usize) << 14)) & 0x3FFFF) + 1;
-            self.assert_lt(src_size, dst_size)?;
-            src += 5;
-        }
-        self.assert_le(src_size, (src_end - src)?)?;
-        self.assert_le(dst_size, output_size)?;
-
         let dst = *output;
         if dst.into == PointerDest::Scratch {
             scratch += dst_size;
@@ -317,6 +464,61 @@ impl Core<'_> {
         Ok((src + src_size - src_org)?)
     }
 
+    /// Reads the header in front of the entropy coded bytes at |src|, which decode to
+    /// at most |output_size| bytes.
+    fn bytes_header(
+        &mut self,
+        src: Pointer,
+        src_end: Pointer,
+        output_size: usize,
+    ) -> Res<BytesHeader> {
+        self.assert_le(2, (src_end - src)?)?;
+
+        let chunk_type = (self.get_byte(src + 0)? as usize >> 4) & 0x7;
+        let (len, src_size, dst_size) = if chunk_type == 0 {
+            let (len, src_size) = if (self.get_byte(src + 0)? as usize) >= 0x80 {
+                // In this mode, memcopy stores the length in the bottom 12 bits.
+                let src_size = (((self.get_byte(src + 0)? as usize) << 8)
+                    | (self.get_byte(src + 1)? as usize))
+                    & 0xFFF;
+                (2, src_size)
+            } else {
+                self.assert_le(3, (src_end - src)?)?;
+                let src_size = self.get_be_bytes(src, 3).at(self)?;
+                // reserved bits must not be set
+                self.assert_eq(src_size & !0x3ffff, 0)?;
+                (3, src_size)
+            };
+            (len, src_size, src_size)
+        } else if self.get_byte(src)? >= 0x80 {
+            // In all the other modes, the initial bytes encode
+            // the src_size and the dst_size
+            self.assert_le(3, (src_end - src)?)?;
+
+            // short mode, 10 bit sizes
+            let bits = self.get_be_bytes(src, 3).at(self)?;
+            let src_size = bits & 0x3ff;
+            (3, src_size, src_size + ((bits >> 10) & 0x3ff) + 1)
+        } else {
+            // long mode, 18 bit sizes
+            self.assert_le(5, (src_end - src)?)?;
+            let bits = self.get_be_bytes(src + 1, 4).at(self)?;
+            let src_size = bits & 0x3ffff;
+            let dst_size =
+                (((bits >> 18) | ((self.get_byte(src + 0)? as usize) << 14)) & 0x3FFFF) + 1;
+            self.assert_lt(src_size, dst_size)?;
+            (5, src_size, dst_size)
+        };
+        self.assert_le(src_size, (src_end - (src + len))?)?;
+        self.assert_le(dst_size, output_size)?;
+        Ok(BytesHeader {
+            chunk_type,
+            len,
+            src_size,
+            dst_size,
+        })
+    }
+
     fn decode_bytes_type12(
         &mut self,
         mut src: Pointer,
@@ -74,6 +74,14 @@ impl Pointer {
     pub fn is_null(&self) -> bool {
         self.into == PointerDest::Null
     }
+    /// Moves by a signed match offset, failing rather than reaching before the buffer.
+    pub fn offset(self, rhs: i32) -> Result<Pointer, ErrorBuilder> {
+        isize::try_from(rhs)
+            .ok()
+            .and_then(|v| self.index.checked_add_signed(v))
+            .map(|index| Pointer { index, ..self })
+            .msg_of(&(self.index, rhs))
+    }
     pub fn debug(&self, _: usize) {
         // do nothing (there are no bugs)
     }
@@ -303,6 +311,46 @@ impl Core<'_> {
     }
 
     pub fn copy_64_add(&mut self, dest: Pointer, lhs: Pointer, rhs: Pointer, n: usize) -> Res<()> {
+        if dest.into == PointerDest::Output
+            && rhs.into == PointerDest::Output
+            && lhs.into != PointerDest::Output
+            && rhs.index < dest.index
+        {
+            dest.debug(n);
+            self.assert_le(dest.index + n, self.output.len())?;
+            let lhs_bytes = match lhs.into {
+                PointerDest::Scratch => {
+                    self.ensure_scratch(lhs.index + n);
+                    self.scratch.get(lhs.index..lhs.index + n)
+                }
+                PointerDest::Temp => {
+                    self.ensure_tmp(lhs.index + n);
+                    self.tmp.get(lhs.index..lhs.index + n)
+                }
+                PointerDest::Input => self.input.get(lhs.index..lhs.index + n),
+                _ => None,
+            }
+            .msg_of(&(lhs, n))?;
+            // rhs may overlap the bytes being written, so go at most one distance at a time
+            let add_bytes = crate::core::cpu::kernels().add_bytes;
+            let distance = dest.index - rhs.index;
+            let mut done = 0;
+            while done < n {
+                let len = distance.min(n - done);
+                let (head, tail) = self
+                    .output
+                    .split_at_mut_checked(dest.index + done)
+                    .msg_of(&dest)?;
+                add_bytes(
+                    tail.get_mut(..len).msg_of(&dest)?,
+                    lhs_bytes.get(done..done + len).msg_of(&lhs)?,
+                    head.get(rhs.index + done..rhs.index + done + len)
+                        .msg_of(&rhs)?,
+                );
+                done += len;
+            }
+            return Ok(());
+        }
         for i in 0..n {
             self.set(
                 dest + i,
@@ -0,0 +1,116 @@
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+
+/// Values from the headers and indexes of the containers, such as [crate::pak].
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ErrorContext for Reader<'_> {
+    fn describe(&self) -> Option<String> {
+        Some(format!("byte {} of {}", self.pos, self.data.len()))
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Res<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .message(|_| format!("{} bytes past the end", len))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Res<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().at(self)?)
+    }
+
+    pub(crate) fn u8(&mut self) -> Res<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Res<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn i32(&mut self) -> Res<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Res<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32_be(&mut self) -> Res<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    /// Checks that the next bytes are |magic|.
+    pub(crate) fn magic(&mut self, magic: &[u8]) -> Res<()> {
+        let found = self.bytes(magic.len())?;
+        if found != magic {
+            self.raise(format!(
+                "Expected {:?}, found {:?}",
+                String::from_utf8_lossy(magic),
+                String::from_utf8_lossy(found)
+            ))?
+        }
+        Ok(())
+    }
+
+    /// A length prefixed string with a terminating zero. Negative lengths count UTF-16 units.
+    pub(crate) fn string(&mut self) -> Res<String> {
+        let len = self.i32()?;
+        let s = if len >= 0 {
+            String::from_utf8_lossy(self.bytes(len as usize)?).into_owned()
+        } else {
+            let units: Vec<u16> = self
+                .bytes(len.unsigned_abs() as usize * 2)?
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes(c.try_into().unwrap_or_default()))
+                .collect();
+            String::from_utf16_lossy(&units)
+        };
+        Ok(s.trim_end_matches('\0').to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Writes |s| the way [Reader::string] reads it.
+    pub(crate) fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as i32 + 1).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+        out.push(0);
+    }
+
+    #[test_log::test]
+    fn reads_strings() {
+        let mut data = Vec::new();
+        string(&mut data, "Game/");
+        data.extend_from_slice(&(-3i32).to_le_bytes());
+        data.extend(
+            ['é' as u16, 'x' as u16, 0]
+                .iter()
+                .flat_map(|u| u.to_le_bytes()),
+        );
+        data.extend_from_slice(b"DCX\0");
+        let mut reader = Reader::new(&data);
+        assert_eq!(reader.string().unwrap(), "Game/");
+        assert_eq!(reader.string().unwrap(), "éx");
+        assert!(reader.magic(b"DCA\0").is_err());
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.u8().is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::core::error::{Res, ResultBuilder};
+use wide::{i16x16, i16x8};
+
+/// Decoder state that can be written to bytes and read back, see `Extractor::save_state`.
+/// Values are little endian, with no padding or field names.
+pub(crate) trait Persist {
+    fn save(&self, out: &mut Vec<u8>);
+    /// Overwrites self with the value at the start of |input|, and advances |input| past it.
+    fn load(&mut self, input: &mut &[u8]) -> Res<()>;
+}
+
+/// Implements [Persist] for a struct by saving the listed fields in order.
+macro_rules! persist_fields {
+    ($t:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::core::state::Persist for $t {
+            fn save(&self, out: &mut Vec<u8>) {
+                $($crate::core::state::Persist::save(&self.$field, out);)*
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> $crate::core::error::Res<()> {
+                $($crate::core::state::Persist::load(&mut self.$field, input)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use persist_fields;
+
+/// Splits the next |N| bytes off |input|.
+pub(crate) fn take<const N: usize>(input: &mut &[u8]) -> Res<[u8; N]> {
+    let (bytes, rest) = input
+        .split_first_chunk()
+        .message(|_| format!("Saved state ends {} bytes early", N - input.len()))?;
+    *input = rest;
+    Ok(*bytes)
+}
+
+macro_rules! persist_int {
+    ($($t:ty),*) => {
+        $(impl Persist for $t {
+            fn save(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+                *self = <$t>::from_le_bytes(take(input)?);
+                Ok(())
+            }
+        })*
+    };
+}
+persist_int!(u8, u16, u32, u64, i16);
+
+impl Persist for usize {
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self as u64).save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let mut v = 0u64;
+        v.load(input)?;
+        *self = usize::try_from(v)
+            .ok()
+            .message(|_| format!("{} doesn't fit in usize", v))?;
+        Ok(())
+    }
+}
+
+impl Persist for bool {
+    fn save(&self, out: &mut Vec<u8>) {
+        u8::from(*self).save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let [b] = take(input)?;
+        *self = match b {
+            0 => false,
+            1 => true,
+            _ => None.message(|_| format!("Invalid bool {}", b))?,
+        };
+        Ok(())
+    }
+}
+
+impl<T: Persist, const N: usize> Persist for [T; N] {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.iter().for_each(|v| v.save(out))
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        self.iter_mut().try_for_each(|v| v.load(input))
+    }
+}
+
+impl Persist for i16x8 {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.to_array().save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let mut v = [0i16; 8];
+        v.load(input)?;
+        *self = i16x8::from(v);
+        Ok(())
+    }
+}
+
+impl Persist for i16x16 {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.to_array().save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let mut v = [0i16; 16];
+        v.load(input)?;
+        *self = i16x16::from(v);
+        Ok(())
+    }
+}
+
+/// Byte strings are saved with their length in front.
+impl Persist for Vec<u8> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.len().save(out);
+        out.extend_from_slice(self);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let mut len = 0usize;
+        len.load(input)?;
+        let (bytes, rest) = input
+            .split_at_checked(len)
+            .message(|_| format!("Saved state ends {} bytes early", len - input.len()))?;
+        *self = bytes.to_vec();
+        *input = rest;
+        Ok(())
+    }
+}
+
+/// Saved as a presence flag followed by the value.
+impl<T: Persist + Default> Persist for Option<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.is_some().save(out);
+        if let Some(v) = self {
+            v.save(out)
+        }
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let mut present = false;
+        present.load(input)?;
+        *self = if present {
+            let mut v = T::default();
+            v.load(input)?;
+            Some(v)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_log::test]
+    fn round_trip() {
+        let value = (
+            [1u16, 0xFFFF],
+            0x1234_5678u32,
+            true,
+            Some(7u8),
+            vec![1u8, 2, 3],
+        );
+        let mut out = Vec::new();
+        value.0.save(&mut out);
+        value.1.save(&mut out);
+        value.2.save(&mut out);
+        value.3.save(&mut out);
+        value.4.save(&mut out);
+
+        let mut loaded = ([0u16; 2], 0u32, false, None::<u8>, Vec::new());
+        let mut input = out.as_slice();
+        loaded.0.load(&mut input).unwrap();
+        loaded.1.load(&mut input).unwrap();
+        loaded.2.load(&mut input).unwrap();
+        loaded.3.load(&mut input).unwrap();
+        loaded.4.load(&mut input).unwrap();
+        assert_eq!(value, loaded);
+        assert!(input.is_empty());
+
+        let mut truncated = &out[..out.len() - 1];
+        let mut v = ([0u16; 2], 0u32, false, None::<u8>, Vec::new());
+        v.0.load(&mut truncated).unwrap();
+        v.1.load(&mut truncated).unwrap();
+        v.2.load(&mut truncated).unwrap();
+        v.3.load(&mut truncated).unwrap();
+        assert!(v.4.load(&mut truncated).is_err());
+    }
+}
@@ -21,6 +21,23 @@ impl ErrorContext for TansDecoder {}
 
 impl TansDecoder {
     pub fn decode(&mut self, core: &mut Core) -> Res<()> {
+        (crate::core::cpu::kernels().tans_decode)(self, core)
+    }
+
+    pub fn decode_generic(&mut self, core: &mut Core) -> Res<()> {
+        self.decode_impl(core)
+    }
+
+    /// # Safety
+    /// The CPU must support AVX2, BMI1 and BMI2.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2,bmi1,bmi2")]
+    pub unsafe fn decode_avx2(&mut self, core: &mut Core) -> Res<()> {
+        self.decode_impl(core)
+    }
+
+    #[inline(always)]
+    fn decode_impl(&mut self, core: &mut Core) -> Res<()> {
         assert!(
             self.ptr_f <= self.ptr_b,
             "{:?} > {:?}",
@@ -57,6 +74,7 @@ impl TansDecoder {
         Ok(())
     }
 
+    #[inline(always)]
     fn tans_forward_bits(&mut self, core: &mut Core) -> Res<()> {
         self.bits_f |= core.get_le_bytes(self.ptr_f, 4).at(core)? << self.bitpos_f;
         self.ptr_f += (31 - self.bitpos_f) >> 3;
@@ -64,6 +82,7 @@ impl TansDecoder {
         Ok(())
     }
 
+    #[inline(always)]
     fn tans_forward_round(&mut self, core: &mut Core, i: usize) -> Res<()> {
         let &TansLutEnt {
             symbol,
@@ -79,6 +98,7 @@ impl TansDecoder {
         Ok(())
     }
 
+    #[inline(always)]
     fn tans_backward_bits(&mut self, core: &mut Core) -> Res<()> {
         self.bits_b |= core.get_be_bytes((self.ptr_b - 4)?, 4).at(core)? << self.bitpos_b;
         self.ptr_b -= (31 - self.bitpos_b) >> 3;
@@ -86,6 +106,7 @@ impl TansDecoder {
         Ok(())
     }
 
+    #[inline(always)]
     fn tans_backward_round(&mut self, core: &mut Core, i: usize) -> Res<()> {
         let &TansLutEnt {
             symbol,
@@ -371,10 +392,10 @@ impl TansDecoder {
 
 #[derive(Default, Copy, Clone)]
 pub struct TansLutEnt {
-    x: u32,
-    bits_x: u8,
-    symbol: u8,
-    w: u16,
+    pub x: u32,
+    pub bits_x: u8,
+    pub symbol: u8,
+    pub w: u16,
 }
 
 pub struct TansData {
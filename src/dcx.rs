@@ -0,0 +1,238 @@
+//! Reader for FromSoftware `DCX` containers, which hold a single compressed file.
+//!
+//! The header is a chain of big endian sections: `DCX` with the offsets of the next two,
+//! `DCS` with the sizes, `DCP` with the compression method and its parameters, and `DCA`
+//! in front of the payload. Method `KRAK` is a Kraken stream without a size prefix.
+
+use crate::archive::{Archive, ArchiveEntry, Compression};
+use crate::core::error::{ErrorContext, Res, WithContext};
+use crate::core::reader::Reader;
+
+const DCS_OFFSET: u32 = 0x18;
+const DCP_OFFSET: u32 = 0x24;
+/// Size of the `DCP` section without the parameters.
+const DCP_LEN: u32 = 12;
+const DCA_LEN: u32 = 8;
+
+/// The header of a `DCX` container, kept whole so the file can be written back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DcxHeader {
+    /// 0x11000 for KRAK.
+    pub version: u32,
+    /// The last two fields of the `DCX` section, kept as is.
+    /// For KRAK they're the offsets of the `DCA` section and of the payload.
+    pub unknown: [u32; 2],
+    pub uncompressed_size: u32,
+    pub compressed_size: u32,
+    /// `KRAK`, or `DFLT`, `ZSTD` and `EDGE`, which this crate can't decompress.
+    pub method: [u8; 4],
+    /// Parameters of the method, starting with the compression level.
+    pub parameters: Vec<u8>,
+}
+
+impl DcxHeader {
+    /// Parses and checks the header at the start of |data|.
+    pub fn parse(data: &[u8]) -> std::io::Result<Self> {
+        Ok(Self::read(&mut Reader::new(data))?)
+    }
+
+    /// Offset of the payload, the size of the header.
+    pub fn payload_offset(&self) -> usize {
+        (DCP_OFFSET + DCP_LEN + DCA_LEN) as usize + self.parameters.len()
+    }
+
+    pub fn level(&self) -> Option<u8> {
+        self.parameters.first().copied()
+    }
+
+    /// The header as stored, to be followed by |compressed_size| bytes of payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload_offset());
+        out.extend_from_slice(b"DCX\0");
+        for value in [self.version, DCS_OFFSET, DCP_OFFSET] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        for value in self.unknown {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        out.extend_from_slice(b"DCS\0");
+        out.extend_from_slice(&self.uncompressed_size.to_be_bytes());
+        out.extend_from_slice(&self.compressed_size.to_be_bytes());
+        out.extend_from_slice(b"DCP\0");
+        out.extend_from_slice(&self.method);
+        let dcp_len = DCP_LEN as usize + self.parameters.len();
+        out.extend_from_slice(&(dcp_len as u32).to_be_bytes());
+        out.extend_from_slice(&self.parameters);
+        out.extend_from_slice(b"DCA\0");
+        out.extend_from_slice(&DCA_LEN.to_be_bytes());
+        out
+    }
+
+    fn read(data: &mut Reader) -> Res<Self> {
+        data.magic(b"DCX\0")?;
+        let version = data.u32_be()?;
+        let dcs_offset = data.u32_be()?;
+        data.assert_eq(dcs_offset, DCS_OFFSET)?;
+        let dcp_offset = data.u32_be()?;
+        data.assert_eq(dcp_offset, DCP_OFFSET)?;
+        let unknown = [data.u32_be()?, data.u32_be()?];
+        data.magic(b"DCS\0")?;
+        let uncompressed_size = data.u32_be()?;
+        let compressed_size = data.u32_be()?;
+        data.magic(b"DCP\0")?;
+        let method = data.array()?;
+        let dcp_len = data.u32_be()?;
+        data.assert_le(DCP_LEN, dcp_len)?;
+        let parameters = data.bytes((dcp_len - DCP_LEN) as usize)?.to_vec();
+        data.magic(b"DCA\0")?;
+        let dca_len = data.u32_be()?;
+        data.assert_eq(dca_len, DCA_LEN)?;
+        Ok(DcxHeader {
+            version,
+            unknown,
+            uncompressed_size,
+            compressed_size,
+            method,
+            parameters,
+        })
+    }
+}
+
+impl ErrorContext for DcxHeader {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "DCX {} of {} bytes from {}",
+            String::from_utf8_lossy(&self.method),
+            self.uncompressed_size,
+            self.compressed_size
+        ))
+    }
+}
+
+/// A `DCX` container, as an [Archive] of one file.
+pub struct Dcx {
+    pub header: DcxHeader,
+    data: Vec<u8>,
+    path: String,
+}
+
+impl Dcx {
+    /// Parses the header of the container in |data|, which holds the file at |path|.
+    pub fn new(data: Vec<u8>, path: impl Into<String>) -> std::io::Result<Self> {
+        Ok(Dcx {
+            header: DcxHeader::parse(&data)?,
+            data,
+            path: path.into(),
+        })
+    }
+}
+
+impl Archive for Dcx {
+    fn list(&self) -> Vec<ArchiveEntry> {
+        vec![ArchiveEntry {
+            path: self.path.clone(),
+            compressed_size: self.header.compressed_size.into(),
+            size: self.header.uncompressed_size.into(),
+            compression: match &self.header.method {
+                b"KRAK" => Compression::Oodle,
+                method => Compression::Other(String::from_utf8_lossy(method).into_owned()),
+            },
+        }]
+    }
+
+    /// The container is in memory, see [decompress].
+    fn read_stored(&mut self, _index: usize) -> std::io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn decode(&self, index: usize, _stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        if index != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("entry {}", index),
+            ));
+        }
+        decompress(&self.data).map(|(_, out)| out)
+    }
+}
+
+/// Decompresses the `DCX` container in |data|. Returns the header, and the file it holds.
+pub fn decompress(data: &[u8]) -> std::io::Result<(DcxHeader, Vec<u8>)> {
+    Ok(decompress_dcx(data)?)
+}
+
+fn decompress_dcx(data: &[u8]) -> Res<(DcxHeader, Vec<u8>)> {
+    let header = DcxHeader::read(&mut Reader::new(data))?;
+    if &header.method != b"KRAK" {
+        header.raise("Unsupported compression".into())?
+    }
+    let start = header.payload_offset();
+    let payload = data.get(start..).unwrap_or_default();
+    header.assert_le(header.compressed_size as usize, payload.len())?;
+    let payload = payload
+        .get(..header.compressed_size as usize)
+        .unwrap_or_default();
+    let mut out = vec![0; header.uncompressed_size as usize];
+    let written = crate::decompress(payload, &mut out).at(&header)?;
+    header.assert_eq(written, out.len())?;
+    Ok((header, out))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tests::{kraken_stream, sample};
+
+    /// Header of an Elden Ring KRAK container, with the sizes in the DCS section zeroed.
+    const KRAK_HEADER: [u8; 0x4C] = [
+        0x44, 0x43, 0x58, 0x00, 0x00, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00,
+        0x24, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00, 0x4C, 0x44, 0x43, 0x53, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x43, 0x50, 0x00, 0x4B, 0x52, 0x41, 0x4B, 0x00,
+        0x00, 0x00, 0x20, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x44, 0x43, 0x41, 0x00, 0x00, 0x00, 0x00,
+        0x08,
+    ];
+
+    pub(crate) fn container(data: &[u8]) -> Vec<u8> {
+        let payload = kraken_stream(data);
+        let mut header = DcxHeader::parse(&KRAK_HEADER).unwrap();
+        header.uncompressed_size = data.len() as u32;
+        header.compressed_size = payload.len() as u32;
+        let mut out = header.to_bytes();
+        out.extend(payload);
+        out
+    }
+
+    #[test_log::test]
+    fn parses_header() {
+        let header = DcxHeader::parse(&KRAK_HEADER).unwrap();
+        assert_eq!(header.version, 0x11000);
+        assert_eq!(header.unknown, [0x44, 0x4C]);
+        assert_eq!(&header.method, b"KRAK");
+        assert_eq!(header.level(), Some(6));
+        assert_eq!(header.payload_offset(), KRAK_HEADER.len());
+        assert_eq!(header.to_bytes(), KRAK_HEADER);
+    }
+
+    #[test_log::test]
+    fn decompresses_krak() {
+        let data = sample(300_000, 0);
+        let dcx = container(&data);
+        let (header, out) = decompress(&dcx).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(header.uncompressed_size as usize, data.len());
+        assert_eq!(header.to_bytes(), dcx[..header.payload_offset()]);
+
+        // cut off payload
+        assert!(decompress(&dcx[..dcx.len() - 1]).is_err());
+        // wrong decompressed size
+        let mut wrong = dcx.clone();
+        wrong[0x1C..0x20].copy_from_slice(&(data.len() as u32 - 1).to_be_bytes());
+        assert!(decompress(&wrong).is_err());
+        let mut deflate = dcx.clone();
+        deflate[0x28..0x2C].copy_from_slice(b"DFLT");
+        assert!(DcxHeader::parse(&deflate).is_ok());
+        assert!(decompress(&deflate).is_err());
+        assert!(decompress(&dcx[4..]).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+/// Writes bits most significant first, the order consumed by `BitReader`.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    pub buf: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Write the low |n| bits of |v|, n may be zero.
+    pub fn write(&mut self, v: u32, n: u32) {
+        debug_assert!(n <= 32 && (n == 32 || v >> n == 0));
+        self.bits = (self.bits << n) | v as u64;
+        self.count += n;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.buf.push((self.bits >> self.count) as u8);
+        }
+    }
+
+    pub fn write_bit(&mut self, v: bool) {
+        self.write(v.into(), 1)
+    }
+
+    /// Write |zeros| zero bits followed by a one bit.
+    pub fn write_unary(&mut self, zeros: u32) {
+        for _ in 0..zeros / 24 {
+            self.write(0, 24);
+        }
+        self.write(1, zeros % 24 + 1);
+    }
+
+    /// Write a value >= 2 as an Elias gamma code: as many zeros as the value
+    /// has bits after the first two, followed by the value itself.
+    pub fn write_gamma(&mut self, v: u32) {
+        debug_assert!(v >= 2);
+        let n = 32 - v.leading_zeros();
+        self.write(0, n - 2);
+        self.write(v, n);
+    }
+
+    /// Write the number of range values with the truncated binary code read by
+    /// `BitReader::read_fluff`. Returns false if |fluff| is out of range.
+    pub fn write_fluff(&mut self, fluff: usize, num_symbols: usize) -> bool {
+        if num_symbols == 256 {
+            return fluff == 0;
+        }
+        let x = (257 - num_symbols).min(num_symbols) * 2;
+        if fluff >= x {
+            return false;
+        }
+        let y = (x - 1).ilog2() + 1;
+        let z = (1 << y) - x;
+        if fluff < z {
+            self.write(fluff as u32, y - 1);
+        } else {
+            self.write((fluff + z) as u32, y);
+        }
+        true
+    }
+
+    pub fn bit_len(&self) -> usize {
+        self.buf.len() * 8 + self.count as usize
+    }
+
+    /// Pad to a whole byte and return the buffer.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.buf.push((self.bits << (8 - self.count)) as u8);
+        }
+        self.buf
+    }
+}
+
+/// Writes bits least significant first, the order of the forward streams read by
+/// `HuffReader` and `TansDecoder`. A backwards stream is written with this and
+/// then has its bytes reversed.
+#[derive(Default)]
+pub(crate) struct LsbWriter {
+    pub buf: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl LsbWriter {
+    /// Write the low |n| bits of |v|, n may be zero.
+    pub fn write(&mut self, v: u32, n: u32) {
+        debug_assert!(n <= 32 && (n == 32 || v >> n == 0));
+        self.bits |= (v as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.buf.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Pad to a whole byte and return the buffer.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.buf.push(self.bits as u8);
+        }
+        self.buf
+    }
+}
@@ -0,0 +1,340 @@
+use crate::core::error::{ErrorBuilder, ErrorContext, Res};
+use crate::entropy::bit_writer::{BitWriter, LsbWriter};
+use crate::entropy::CodeLengths;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Longest code the decoder's 11 bit lookup table supports.
+pub(crate) const MAX_CODE_LEN: usize = 11;
+
+#[allow(clippy::indexing_slicing)] // u8 can safely index [_; 256]
+pub(crate) fn histogram(src: &[u8]) -> [u32; 256] {
+    let mut histo = [0; 256];
+    for &b in src {
+        histo[b as usize] += 1;
+    }
+    histo
+}
+
+/// Canonical length-limited Huffman code, in the symbol order used by `Core::make_lut`.
+pub(crate) struct HuffmanCode {
+    pub lengths: [u8; 256],
+    /// Codes with the bits reversed, ready to be written least significant bit first.
+    pub codes: [u16; 256],
+}
+
+impl ErrorContext for HuffmanCode {}
+
+// Tables are indexed by symbol or code length, both bounded by construction.
+#[allow(clippy::indexing_slicing)]
+impl HuffmanCode {
+    /// Builds a complete code with lengths of at most [MAX_CODE_LEN].
+    /// Returns None if fewer than two symbols are used, which can't be coded.
+    pub fn new(histo: &[u32; 256]) -> Option<Self> {
+        let mut used: Vec<(u32, u8)> = (0..=255u8)
+            .filter(|&s| histo[s as usize] != 0)
+            .map(|s| (histo[s as usize], s))
+            .collect();
+        if used.len() < 2 {
+            return None;
+        }
+
+        // Plain Huffman code depths
+        let mut parent = vec![0usize; used.len() * 2 - 1];
+        let mut heap: BinaryHeap<_> = used
+            .iter()
+            .enumerate()
+            .map(|(i, &(f, _))| Reverse((f as u64, i)))
+            .collect();
+        let mut next = used.len();
+        while let (Some(Reverse((a, i))), Some(Reverse((b, j)))) = (heap.pop(), heap.pop()) {
+            parent[i] = next;
+            parent[j] = next;
+            heap.push(Reverse((a + b, next)));
+            next += 1;
+        }
+        let root = next - 1;
+        let mut depth = vec![0usize; parent.len()];
+        for i in (0..root).rev() {
+            depth[i] = depth[parent[i]] + 1;
+        }
+
+        // Clamp to the maximum length, then take codes away from the longest
+        // lengths until the kraft sum is exactly one again.
+        let mut count = [0usize; MAX_CODE_LEN + 1];
+        for &d in &depth[..used.len()] {
+            count[d.min(MAX_CODE_LEN)] += 1;
+        }
+        let mut total: usize = (1..=MAX_CODE_LEN)
+            .map(|i| count[i] << (MAX_CODE_LEN - i))
+            .sum();
+        while total > 1 << MAX_CODE_LEN {
+            count[MAX_CODE_LEN] -= 1;
+            if let Some(i) = (1..MAX_CODE_LEN).rev().find(|&i| count[i] != 0) {
+                count[i] -= 1;
+                count[i + 1] += 2;
+            }
+            total -= 1;
+        }
+
+        // Most frequent symbols get the shortest codes
+        used.sort_by_key(|&(f, s)| (Reverse(f), s));
+        let mut lengths = [0; 256];
+        let mut syms = used.iter();
+        for (len, &n) in count.iter().enumerate() {
+            for (_, s) in syms.by_ref().take(n) {
+                lengths[*s as usize] = len as u8;
+            }
+        }
+        Some(Self::from_lengths(lengths))
+    }
+
+    /// Assigns canonical codes, shorter lengths first and ascending symbols within a length.
+    pub fn from_lengths(lengths: [u8; 256]) -> Self {
+        let mut codes = [0; 256];
+        let mut code = 0u32;
+        for len in 1..=MAX_CODE_LEN as u8 {
+            for (sym, _) in lengths.iter().enumerate().filter(|(_, &l)| l == len) {
+                codes[sym] = (code.reverse_bits() >> (32 - len)) as u16;
+                code += 1;
+            }
+            code <<= 1;
+        }
+        Self { lengths, codes }
+    }
+
+    /// Number of bits needed for the symbols counted in |histo|, excluding the table.
+    pub fn cost(&self, histo: &[u32; 256]) -> usize {
+        histo
+            .iter()
+            .zip(self.lengths.iter())
+            .map(|(&f, &l)| f as usize * l as usize)
+            .sum()
+    }
+
+    fn used(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.lengths
+            .iter()
+            .enumerate()
+            .filter(|(_, &l)| l != 0)
+            .map(|(s, &l)| (s, l))
+    }
+
+    fn ranges(&self) -> Vec<(usize, usize)> {
+        symbol_ranges(self.used().map(|(s, _)| s))
+    }
+
+    /// Writes the code lengths in the format read by `huff_read_code_lengths_old`
+    /// or `huff_read_code_lengths_new`. Returns false if the format can't represent this code.
+    pub fn write_table(&self, bits: &mut BitWriter, format: CodeLengths, forced_bits: u32) -> bool {
+        match format {
+            CodeLengths::Sparse => self.write_sparse(bits),
+            CodeLengths::Gamma => self.write_gamma(bits, forced_bits),
+            CodeLengths::GolombRice => self.write_golomb_rice(bits, forced_bits),
+        }
+    }
+
+    fn write_sparse(&self, bits: &mut BitWriter) -> bool {
+        let num_symbols = self.used().count();
+        if num_symbols > 255 {
+            return false;
+        }
+        let max_len = self.used().map(|(_, l)| l as u32).max().unwrap_or(1);
+        let codelen_bits = 32 - (max_len - 1).leading_zeros();
+        bits.write_bit(false);
+        bits.write_bit(false);
+        bits.write(num_symbols as u32, 8);
+        bits.write(codelen_bits, 3);
+        for (sym, len) in self.used() {
+            bits.write(sym as u32, 8);
+            bits.write(len as u32 - 1, codelen_bits);
+        }
+        true
+    }
+
+    fn write_gamma(&self, bits: &mut BitWriter, forced_bits: u32) -> bool {
+        let max_zeros = 20 >> forced_bits;
+        bits.write_bit(false);
+        bits.write_bit(true);
+        bits.write(forced_bits, 2);
+        bits.write_bit(self.lengths[0] != 0);
+
+        let mut avg_bits_x4 = 32;
+        let mut sym = 0;
+        for (start, n) in self.ranges() {
+            if start != 0 {
+                bits.write_gamma((start - sym + 1) as u32);
+            }
+            bits.write_gamma(n as u32 + 1);
+            for &len in &self.lengths[start..start + n] {
+                let delta = len as i32 - ((avg_bits_x4 + 2) >> 2);
+                let v = ((delta << 1) ^ (delta >> 31)) as u32;
+                if v >> forced_bits > max_zeros {
+                    return false;
+                }
+                bits.write_unary(v >> forced_bits);
+                bits.write(v & ((1 << forced_bits) - 1), forced_bits);
+                avg_bits_x4 = len as i32 + ((3 * avg_bits_x4 + 2) >> 2);
+            }
+            sym = start + n;
+        }
+        if sym != 256 {
+            bits.write_gamma((256 - sym + 1) as u32);
+        }
+        true
+    }
+
+    fn write_golomb_rice(&self, bits: &mut BitWriter, forced_bits: u32) -> bool {
+        let num_symbols = self.used().count();
+        let ranges = self.ranges();
+        if ranges.is_empty() {
+            return false;
+        }
+
+        let params = range_params(&ranges);
+        let fluff = params.len();
+
+        bits.write_bit(true);
+        bits.write_bit(false);
+        bits.write(forced_bits, 2);
+        bits.write(num_symbols as u32 - 1, 8);
+        if !bits.write_fluff(fluff, num_symbols) {
+            return false;
+        }
+
+        let mut running_sum = 0x1e;
+        let mut low_bits = Vec::with_capacity(num_symbols);
+        for (_, len) in self.used() {
+            let delta = len as i32 - 1 - (running_sum >> 2);
+            running_sum += delta;
+            let v = ((delta << 1) ^ (delta >> 31)) as u32;
+            bits.write_unary(v >> forced_bits);
+            low_bits.push(v & ((1 << forced_bits) - 1));
+        }
+        for &(p, _, _) in &params {
+            bits.write_unary(p);
+        }
+        for v in low_bits {
+            bits.write(v, forced_bits);
+        }
+        for (_, v, n) in params {
+            bits.write(v, n);
+        }
+        true
+    }
+
+    /// Writes the symbols of |src| to three streams, interleaved as the decoder reads them:
+    /// two forward streams and one stream that is stored reversed at the end.
+    pub fn write_streams(&self, src: &[u8], out: &mut Vec<u8>) -> Res<()> {
+        let mut streams: [LsbWriter; 3] = Default::default();
+        for (chunk, &sym) in src.iter().enumerate() {
+            let stream = &mut streams[[0, 2, 1][chunk % 3]];
+            stream.write(
+                self.codes[sym as usize] as u32,
+                self.lengths[sym as usize] as u32,
+            );
+        }
+        let [a, b, c] = streams.map(LsbWriter::finish);
+        if a.len() > 0xFFFF {
+            self.raise(format!("First stream too long: {}", a.len()))?
+        }
+        out.extend_from_slice(&(a.len() as u16).to_le_bytes());
+        out.extend_from_slice(&a);
+        out.extend_from_slice(&b);
+        out.extend(c.iter().rev());
+        Ok(())
+    }
+}
+
+/// Runs of consecutive symbols as (first symbol, count)
+pub(crate) fn symbol_ranges(symbols: impl Iterator<Item = usize>) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for sym in symbols {
+        match ranges.last_mut() {
+            Some((start, n)) if *start + *n == sym => *n += 1,
+            _ => ranges.push((sym, 1)),
+        }
+    }
+    ranges
+}
+
+/// The values read by `Core::convert_to_ranges` as (unary coded bit count, value, bit count):
+/// the space before the first range, then the size of each range but the last one and the
+/// space after it.
+pub(crate) fn range_params(ranges: &[(usize, usize)]) -> Vec<(u32, u32, u32)> {
+    let space = |v: usize| {
+        let n = (v + 1).ilog2();
+        (n - 1, (v + 1 - (1 << n)) as u32, n)
+    };
+    let mut params = Vec::new();
+    if let Some(&(first, _)) = ranges.first().filter(|(first, _)| *first != 0) {
+        params.push(space(first));
+    }
+    for (&(start, n), &(next, _)) in ranges.iter().zip(ranges.iter().skip(1)) {
+        let n_bits = n.ilog2();
+        params.push((n_bits, (n - (1 << n_bits)) as u32, n_bits));
+        params.push(space(next - start - n));
+    }
+    params
+}
+
+/// The smallest code length table for |code|, trying all formats unless |format| is given.
+fn best_table(code: &HuffmanCode, format: Option<CodeLengths>) -> Option<BitWriter> {
+    let formats = match format {
+        Some(f) => vec![f],
+        None => vec![
+            CodeLengths::Sparse,
+            CodeLengths::Gamma,
+            CodeLengths::GolombRice,
+        ],
+    };
+    formats
+        .into_iter()
+        .flat_map(|f| (0..4).map(move |forced_bits| (f, forced_bits)))
+        .filter_map(|(f, forced_bits)| {
+            let mut bits = BitWriter::default();
+            code.write_table(&mut bits, f, forced_bits).then_some(bits)
+        })
+        .min_by_key(BitWriter::bit_len)
+}
+
+/// Estimated size of the payload [encode] produces for |src|, None if it can't be encoded.
+pub(crate) fn estimate(src: &[u8], split: bool) -> Option<usize> {
+    let histo = histogram(src);
+    let code = HuffmanCode::new(&histo)?;
+    let table = best_table(&code, None)?;
+    // stream sizes and padding
+    let overhead = if split { 3 + 2 * 5 } else { 5 };
+    Some(table.bit_len().div_ceil(8) + code.cost(&histo).div_ceil(8) + overhead)
+}
+
+/// Encodes the payload of a type 2 (or type 4 if |split|) block, without the block header.
+pub(crate) fn encode(
+    src: &[u8],
+    split: bool,
+    format: Option<CodeLengths>,
+    out: &mut Vec<u8>,
+) -> Res<()> {
+    let Some(code) = HuffmanCode::new(&histogram(src)) else {
+        Err(ErrorBuilder {
+            message: Some("Huffman coding needs at least two symbols".into()),
+            ..Default::default()
+        })?
+    };
+
+    match best_table(&code, format) {
+        Some(bits) => out.extend(bits.finish()),
+        None => code.raise("Code lengths can't be represented".into())?,
+    }
+
+    if split {
+        let (left, right) = src.split_at((src.len() + 1) >> 1);
+        let mut first = Vec::new();
+        code.write_streams(left, &mut first)?;
+        out.extend_from_slice(&first.len().to_le_bytes()[..3]);
+        out.extend(first);
+        code.write_streams(right, out)
+    } else {
+        code.write_streams(src, out)
+    }
+}
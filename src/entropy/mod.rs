@@ -0,0 +1,684 @@
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::pointer::Pointer;
+use crate::core::Core;
+
+mod bit_writer;
+mod huffman;
+mod multi_array;
+mod rle;
+mod tans;
+
+/// Sub-codec of an entropy block, taken from bits 4..7 of its first byte.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockType {
+    /// Type 0, the payload is stored as-is.
+    Memcopy,
+    /// Type 1, tANS with five interleaved states.
+    Tans,
+    /// Type 2 (three bitstreams) or type 4 (`split`, two halves of three bitstreams each).
+    Huffman {
+        split: bool,
+        code_lengths: CodeLengths,
+    },
+    /// Type 3, RLE command stream.
+    Rle,
+    /// Type 5, a sequence of nested entropy blocks.
+    Recursive,
+    /// Type 5 with the high bit set, nested blocks interleaved by interval indexes.
+    MultiArray,
+}
+
+/// How the code lengths of a Huffman block are transmitted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CodeLengths {
+    /// Explicit list of (symbol, length) pairs.
+    Sparse,
+    /// Gamma coded runs over all 256 symbols.
+    Gamma,
+    /// Golomb-Rice coded lengths with symbol ranges.
+    GolombRice,
+}
+
+/// Metadata of a single entropy block.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub block_type: BlockType,
+    /// Bytes of input used by the block, including its header.
+    pub compressed_size: usize,
+    /// Bytes of output produced by the block.
+    pub decompressed_size: usize,
+}
+
+struct Header<'a> {
+    src: &'a [u8],
+}
+
+impl ErrorContext for Header<'_> {
+    fn describe(&self) -> Option<String> {
+        Some(format!("entropy block of {} bytes", self.src.len()))
+    }
+}
+
+impl Header<'_> {
+    fn byte(&self, i: usize) -> Res<usize> {
+        Ok(self
+            .src
+            .get(i)
+            .copied()
+            .message(|_| format!("Block truncated at {}", i))? as usize)
+    }
+
+    fn be_bytes(&self, start: usize, n: usize) -> Res<usize> {
+        (start..start + n).try_fold(0, |v, i| Ok((v << 8) | self.byte(i)?))
+    }
+
+    fn parse(&self) -> Res<BlockInfo> {
+        let chunk_type = (self.byte(0)? >> 4) & 0x7;
+        let (header_size, src_size, dst_size) = if chunk_type == 0 {
+            if self.byte(0)? >= 0x80 {
+                let size = self.be_bytes(0, 2)? & 0xFFF;
+                (2, size, size)
+            } else {
+                let size = self.be_bytes(0, 3)?;
+                self.assert_eq(size & !0x3ffff, 0)?;
+                (3, size, size)
+            }
+        } else if self.byte(0)? >= 0x80 {
+            let bits = self.be_bytes(0, 3)?;
+            let src_size = bits & 0x3ff;
+            (3, src_size, src_size + ((bits >> 10) & 0x3ff) + 1)
+        } else {
+            let bits = self.be_bytes(1, 4)?;
+            let src_size = bits & 0x3ffff;
+            let dst_size = (((bits >> 18) | (self.byte(0)? << 14)) & 0x3FFFF) + 1;
+            self.assert_lt(src_size, dst_size)?;
+            (5, src_size, dst_size)
+        };
+        self.assert_le(header_size + src_size, self.src.len())?;
+
+        let block_type = match chunk_type {
+            0 => BlockType::Memcopy,
+            1 => BlockType::Tans,
+            2 | 4 => {
+                let first = self.byte(header_size)?;
+                BlockType::Huffman {
+                    split: chunk_type == 4,
+                    code_lengths: match first >> 6 {
+                        0 => CodeLengths::Sparse,
+                        1 => CodeLengths::Gamma,
+                        2 => CodeLengths::GolombRice,
+                        _ => self.raise("Invalid code length format".into())?,
+                    },
+                }
+            }
+            3 => BlockType::Rle,
+            5 if self.byte(header_size)? & 0x80 == 0 => BlockType::Recursive,
+            5 => BlockType::MultiArray,
+            _ => self.raise(format!("Unknown entropy block type {}", chunk_type))?,
+        };
+
+        Ok(BlockInfo {
+            block_type,
+            compressed_size: header_size + src_size,
+            decompressed_size: dst_size,
+        })
+    }
+}
+
+/// Reads the header of the entropy block at the start of `src` without decoding it.
+pub fn inspect(src: &[u8]) -> std::io::Result<BlockInfo> {
+    Ok(Header { src }.parse()?)
+}
+
+/// Decodes the single entropy block at the start of `src` into the start of `dst`.
+/// `dst` must be at least as large as the decompressed size of the block,
+/// which can be found with [inspect]. Bytes in `src` after the block are ignored.
+pub fn decode(src: &[u8], dst: &mut [u8]) -> std::io::Result<BlockInfo> {
+    Ok(decode_block(src, dst)?)
+}
+
+fn decode_block(src: &[u8], dst: &mut [u8]) -> Res<BlockInfo> {
+    let info = Header { src }.parse()?;
+    // The tANS decoder reads a few bytes past its streams, which inside a compressed
+    // stream come from whatever follows the block.
+    let padded: Vec<u8>;
+    let src = match src.get(..info.compressed_size) {
+        Some(block) if src.len() < info.compressed_size + 8 => {
+            padded = [block, &[0; 8]].concat();
+            &padded
+        }
+        _ => src,
+    };
+    let dst_len = dst.len();
+    let mut core = Core::new(src, dst, 0, 0);
+    let mut output = Pointer::output(0);
+    let mut decoded_size = 0;
+    let src_used = core
+        .decode_bytes(
+            &mut output,
+            Pointer::input(0),
+            Pointer::input(src.len()),
+            &mut decoded_size,
+            dst_len,
+            true,
+            Pointer::scratch(0),
+        )
+        .at(&core)?;
+    core.assert_eq(src_used, info.compressed_size)?;
+    core.assert_eq(decoded_size, info.decompressed_size)?;
+    Ok(info)
+}
+
+/// Appends the header of a block of |chunk_type| with a payload of |src_size| bytes
+/// that decodes to |dst_size| bytes.
+fn write_header(out: &mut Vec<u8>, chunk_type: usize, src_size: usize, dst_size: usize) -> Res<()> {
+    let header = Header { src: &[] };
+    header.assert_le(dst_size, 0x40000)?;
+    if chunk_type == 0 {
+        header.assert_eq(src_size, dst_size)?;
+        if src_size <= 0xFFF {
+            out.extend_from_slice(&((0x8000 | src_size) as u16).to_be_bytes());
+        } else {
+            header.assert_le(src_size, 0x3ffff)?;
+            out.extend_from_slice(&src_size.to_be_bytes()[5..]);
+        }
+        return Ok(());
+    }
+    header.assert_lt(src_size, dst_size)?;
+    if src_size < 0x400 && dst_size - src_size <= 0x400 {
+        let bits = 0x800000 | (chunk_type << 20) | ((dst_size - src_size - 1) << 10) | src_size;
+        out.extend_from_slice(&bits.to_be_bytes()[5..]);
+    } else {
+        out.push(((chunk_type << 4) | ((dst_size - 1) >> 14)) as u8);
+        let bits = (((dst_size - 1) & 0x3FFF) << 18) | src_size;
+        out.extend_from_slice(&(bits as u32).to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Appends `src` to `out` as a memcopy block, which holds at most 0x3ffff bytes.
+pub fn encode_memcopy(src: &[u8], out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    let start = out.len();
+    write_header(out, 0, src.len(), src.len())?;
+    out.extend_from_slice(src);
+    Ok(BlockInfo {
+        block_type: BlockType::Memcopy,
+        compressed_size: out.len() - start,
+        decompressed_size: src.len(),
+    })
+}
+
+/// Appends `src` to `out` as a Huffman block, using the code length format
+/// that gives the smallest table. Fails if `src` has fewer than two distinct
+/// bytes or the block would not be smaller than `src`.
+pub fn encode_huffman(src: &[u8], split: bool, out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    Ok(encode_block(src, out, |payload| {
+        huffman::encode(src, split, None, payload)?;
+        Ok(if split { 4 } else { 2 })
+    })?)
+}
+
+/// Appends `src` to `out` as a tANS block. The last five bytes of `src` are stored
+/// as the final decoder states, the rest is coded. Fails if fewer than two distinct
+/// bytes precede them or the block would not be smaller than `src`.
+pub fn encode_tans(src: &[u8], out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    Ok(encode_block(src, out, |payload| {
+        tans::encode(src, payload)?;
+        Ok(1)
+    })?)
+}
+
+/// Estimated size in bytes of the block [encode_huffman] produces for `src`,
+/// or None if it can't encode it. Cheaper than encoding, for choosing a codec.
+pub fn estimate_huffman(src: &[u8], split: bool) -> Option<usize> {
+    huffman::estimate(src, split).map(|size| size + 5)
+}
+
+/// Estimated size in bytes of the block [encode_tans] produces for `src`,
+/// or None if it can't encode it. Cheaper than encoding, for choosing a codec.
+pub fn estimate_tans(src: &[u8]) -> Option<usize> {
+    tans::estimate(src).map(|size| size + 5)
+}
+
+/// Appends `src` to `out` as an RLE block, a memset if all bytes are equal.
+/// Fails if the block would not be smaller than `src`.
+pub fn encode_rle(src: &[u8], out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    Ok(encode_block(src, out, |payload| {
+        rle::encode(src, payload);
+        Ok(3)
+    })?)
+}
+
+/// Appends `src` to `out` as a recursive block, a sequence of nested blocks split where
+/// the byte statistics change. Fails if no useful split is found or the block would not
+/// be smaller than `src`.
+pub fn encode_recursive(src: &[u8], out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    Ok(encode_block(src, out, |payload| {
+        multi_array::encode_recursive(src, payload)?;
+        Ok(5)
+    })?)
+}
+
+/// Appends `src` to `out` as a multi-array block: intervals of `src` with similar byte
+/// statistics are gathered into arrays that are coded separately, as happens with
+/// interleaved records of tables or vertex buffers. Fails if no useful split is found
+/// or the block would not be smaller than `src`.
+pub fn encode_multi_array(src: &[u8], out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    Ok(encode_block(src, out, |payload| {
+        multi_array::encode_multi_array(src, payload)?;
+        Ok(5)
+    })?)
+}
+
+/// Appends the smallest block found for `src` to `out`, falling back to a memcopy block
+/// when nothing compresses. Huffman and tANS are chosen by their estimated size.
+pub fn encode(src: &[u8], out: &mut Vec<u8>) -> std::io::Result<BlockInfo> {
+    let mut best = encode_simple(src)?;
+    // Splitting only pays off once there is enough data to split
+    if src.len() >= 0x800 {
+        for encoder in [
+            multi_array::encode_multi_array,
+            multi_array::encode_recursive,
+        ] {
+            let mut block = Vec::new();
+            let result = encode_block(src, &mut block, |payload| {
+                encoder(src, payload)?;
+                Ok(5)
+            });
+            if result.is_ok() && block.len() < best.len() {
+                best = block;
+            }
+        }
+    }
+    let info = Header { src: &best }.parse()?;
+    out.extend(best);
+    Ok(info)
+}
+
+/// The smallest Huffman or tANS block for |src|, trying them in order of estimated size.
+/// None if neither is smaller than |src|.
+pub(crate) fn encode_statistical(src: &[u8]) -> Option<Vec<u8>> {
+    let mut candidates: Vec<(usize, Option<bool>)> = Vec::new();
+    for split in [false, true] {
+        if let Some(size) = huffman::estimate(src, split) {
+            candidates.push((size + 5, Some(split)));
+        }
+    }
+    if let Some(size) = tans::estimate(src) {
+        candidates.push((size + 5, None));
+    }
+    candidates.sort_by_key(|&(size, _)| size);
+    candidates
+        .into_iter()
+        .take_while(|&(size, _)| size < src.len())
+        .find_map(|(_, huffman_split)| {
+            let mut block = Vec::new();
+            encode_block(src, &mut block, |payload| match huffman_split {
+                Some(split) => {
+                    huffman::encode(src, split, None, payload)?;
+                    Ok(if split { 4 } else { 2 })
+                }
+                None => {
+                    tans::encode(src, payload)?;
+                    Ok(1)
+                }
+            })
+            .ok()
+            .map(|_| block)
+        })
+}
+
+/// The smallest block for |src| without nested blocks: Huffman, tANS, RLE or memcopy.
+pub(crate) fn encode_simple(src: &[u8]) -> Res<Vec<u8>> {
+    let mut best = encode_statistical(src);
+    let mut block = Vec::new();
+    let rle = encode_block(src, &mut block, |payload| {
+        rle::encode(src, payload);
+        Ok(3)
+    });
+    if rle.is_ok() && best.as_ref().is_none_or(|b| block.len() < b.len()) {
+        best = Some(block);
+    }
+    match best {
+        Some(block) => Ok(block),
+        None => {
+            let mut block = Vec::new();
+            write_header(&mut block, 0, src.len(), src.len())?;
+            block.extend_from_slice(src);
+            Ok(block)
+        }
+    }
+}
+
+/// Writes the payload produced by |encoder| behind a header for its chunk type.
+fn encode_block(
+    src: &[u8],
+    out: &mut Vec<u8>,
+    encoder: impl FnOnce(&mut Vec<u8>) -> Res<usize>,
+) -> Res<BlockInfo> {
+    let mut payload = Vec::new();
+    let chunk_type = encoder(&mut payload)?;
+    let header = Header { src: &payload };
+    header.assert_lt(payload.len(), src.len())?;
+    let mut block = Vec::with_capacity(payload.len() + 5);
+    write_header(&mut block, chunk_type, payload.len(), src.len())?;
+    block.extend_from_slice(&payload);
+    let info = Header { src: &block }.parse()?;
+    out.extend_from_slice(&block);
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short_header(chunk_type: usize, src_size: usize, dst_size: usize) -> Vec<u8> {
+        let bits = 0x800000 | (chunk_type << 20) | ((dst_size - src_size - 1) << 10) | src_size;
+        bits.to_be_bytes()[5..].to_vec()
+    }
+
+    #[test_log::test]
+    fn memcopy() {
+        let src = [0x80, 0x03, b'a', b'b', b'c', 0xFF];
+        let mut dst = [0; 4];
+        let info = decode(&src, &mut dst).unwrap();
+        assert_eq!(info.block_type, BlockType::Memcopy);
+        assert_eq!(info.compressed_size, 5);
+        assert_eq!(info.decompressed_size, 3);
+        assert_eq!(&dst[..3], b"abc");
+
+        let src = [0x00, 0x00, 0x02, b'x', b'y'];
+        let info = decode(&src, &mut dst).unwrap();
+        assert_eq!(info.compressed_size, 5);
+        assert_eq!(&dst[..2], b"xy");
+    }
+
+    #[test_log::test]
+    fn rle() {
+        let mut src = short_header(3, 1, 9);
+        src.push(b'z');
+        let mut dst = [0; 9];
+        let info = decode(&src, &mut dst).unwrap();
+        assert_eq!(info.block_type, BlockType::Rle);
+        assert_eq!(&dst, b"zzzzzzzzz");
+
+        // set rle byte to 'x', then copy 2 literals and repeat 10 times
+        let payload = [0x00, b'x', b'a', b'b', 0xAD, 0x01];
+        let mut src = short_header(3, payload.len(), 12);
+        src.extend_from_slice(&payload);
+        let mut dst = [0; 12];
+        let info = decode(&src, &mut dst).unwrap();
+        assert_eq!(info.decompressed_size, 12);
+        assert_eq!(&dst, b"abxxxxxxxxxx");
+    }
+
+    fn round_trip(src: &[u8], block: &[u8]) -> BlockInfo {
+        let mut dst = vec![0; src.len()];
+        let info = decode(block, &mut dst).unwrap();
+        assert_eq!(info.compressed_size, block.len());
+        assert_eq!(info.decompressed_size, src.len());
+        assert!(dst == src);
+        info
+    }
+
+    /// Bytes from a xorshift generator, biased towards low values by |skew|.
+    fn noise(len: usize, symbols: u32, skew: u32) -> Vec<u8> {
+        let mut x = 0x2545F491u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                let mut v = x % symbols;
+                for _ in 0..skew {
+                    v = v.min((x >> (7 + skew)) % symbols);
+                }
+                v as u8
+            })
+            .collect()
+    }
+
+    #[test_log::test]
+    fn huffman() {
+        for (len, symbols, skew) in [
+            (20, 2, 0),
+            (1000, 5, 2),
+            (5000, 40, 1),
+            (0x10000, 256, 1),
+            (0x10000, 256, 3),
+            (0x40000, 200, 2),
+        ] {
+            let src = noise(len, symbols, skew);
+            for split in [false, true] {
+                let mut block = Vec::new();
+                let result = encode_huffman(&src, split, &mut block);
+                if len == 0x40000 && !split {
+                    // the first of the three streams is too long for its 16-bit size
+                    assert!(result.is_err());
+                    continue;
+                }
+                let info = result.unwrap();
+                assert!(
+                    matches!(info.block_type, BlockType::Huffman { split: s, .. } if s == split)
+                );
+                round_trip(&src, &block);
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn huffman_code_lengths() {
+        // few symbols, runs with gaps, and a very skewed set that needs length limiting
+        let mut inputs = vec![b"aaaaaaaaaaaaaabbbbbbbcccddde".repeat(20)];
+        inputs.push(
+            (0..4000)
+                .map(|i| ((i * 7) % 13 + (i % 3) * 100) as u8)
+                .collect(),
+        );
+        inputs.push((0..20).flat_map(|i| vec![i as u8; 1 << (i / 2)]).collect());
+        for src in inputs {
+            let histo = huffman::histogram(&src);
+            let code = huffman::HuffmanCode::new(&histo).unwrap();
+            assert!(code
+                .lengths
+                .iter()
+                .all(|&l| l as usize <= huffman::MAX_CODE_LEN));
+            for format in [
+                CodeLengths::Sparse,
+                CodeLengths::Gamma,
+                CodeLengths::GolombRice,
+            ] {
+                let mut payload = Vec::new();
+                huffman::encode(&src, false, Some(format), &mut payload).unwrap();
+                let mut block = Vec::new();
+                write_header(&mut block, 2, payload.len(), src.len()).unwrap();
+                block.extend_from_slice(&payload);
+                let info = round_trip(&src, &block);
+                assert_eq!(
+                    info.block_type,
+                    BlockType::Huffman {
+                        split: false,
+                        code_lengths: format
+                    }
+                );
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn tans() {
+        for (len, symbols, skew) in [
+            (20, 2, 1),
+            (300, 5, 2),
+            (5000, 40, 1),
+            (0x10000, 256, 2),
+            (0x40000, 200, 3),
+        ] {
+            let src = noise(len, symbols, skew);
+            let mut block = Vec::new();
+            let info = encode_tans(&src, &mut block).unwrap();
+            assert_eq!(info.block_type, BlockType::Tans);
+            round_trip(&src, &block);
+
+            let estimate = estimate_tans(&src).unwrap();
+            assert!(estimate.abs_diff(block.len()) <= 8 + block.len() / 100);
+        }
+    }
+
+    #[test_log::test]
+    fn tans_tables() {
+        let inputs = [
+            b"aaaaaaaaaaaaaabbbbbbbcccddde".repeat(20),
+            (0..4000)
+                .map(|i| ((i * 7) % 13 + (i % 3) * 100) as u8)
+                .collect(),
+            (0..20).flat_map(|i| vec![i as u8; 1 << (i / 2)]).collect(),
+        ];
+        for src in inputs {
+            let histo = huffman::histogram(&src[..src.len() - 5]);
+            for l_bits in 8..=11 {
+                let code = tans::TansCode::new(&histo, l_bits).unwrap();
+                assert_eq!(code.weights.iter().sum::<u32>(), 1 << l_bits);
+                for q in [None, Some(0), Some(3), Some(7)] {
+                    let mut bits = bit_writer::BitWriter::default();
+                    if !code.write_table(&mut bits, q) {
+                        // only small alphabets have a sparse table
+                        assert!(q.is_none());
+                        continue;
+                    }
+                    let mut payload = bits.finish();
+                    code.write_streams(&src, &mut payload).unwrap();
+                    let mut block = Vec::new();
+                    write_header(&mut block, 1, payload.len(), src.len()).unwrap();
+                    block.extend_from_slice(&payload);
+                    round_trip(&src, &block);
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn rle_encode() {
+        let mut src = noise(3000, 4, 1);
+        src.extend_from_slice(&[7; 300]);
+        src.extend_from_slice(&noise(70, 3, 0));
+        src.extend_from_slice(&[0; 0x38000]);
+        src.extend_from_slice(b"abcabc");
+        src.extend_from_slice(&[b'c'; 17]);
+        let mut block = Vec::new();
+        let info = encode_rle(&src, &mut block).unwrap();
+        assert_eq!(info.block_type, BlockType::Rle);
+        round_trip(&src, &block);
+
+        // long literal runs, coded through an entropy block
+        let mut src = noise(0x30000, 4, 0);
+        src.extend_from_slice(&[9; 0x2000]);
+        let mut block = Vec::new();
+        encode_rle(&src, &mut block).unwrap();
+        round_trip(&src, &block);
+
+        let mut block = Vec::new();
+        encode_rle(&[3; 100], &mut block).unwrap();
+        assert_eq!(block.len(), 4);
+        round_trip(&[3; 100], &block);
+    }
+
+    /// Sections of different kinds of data, like the tables of a file format.
+    fn sections() -> Vec<u8> {
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(30);
+        (0..40)
+            .flat_map(|i| match i % 3 {
+                0 => text[i * 7..][..1000].to_vec(),
+                1 => noise(900 + i, 8, 2),
+                _ => noise(1100, 256, 3).iter().map(|b| b | 0x80).collect(),
+            })
+            .collect()
+    }
+
+    #[test_log::test]
+    fn multi_array() {
+        let src = sections();
+        let simple = encode_simple(&src).unwrap();
+
+        let mut block = Vec::new();
+        let info = encode_multi_array(&src, &mut block).unwrap();
+        assert_eq!(info.block_type, BlockType::MultiArray);
+        round_trip(&src, &block);
+        assert!(block.len() < simple.len());
+
+        let mut block = Vec::new();
+        let info = encode_recursive(&src, &mut block).unwrap();
+        assert_eq!(info.block_type, BlockType::Recursive);
+        round_trip(&src, &block);
+
+        let mut block = Vec::new();
+        let info = encode(&src, &mut block).unwrap();
+        assert!(matches!(
+            info.block_type,
+            BlockType::MultiArray | BlockType::Recursive
+        ));
+        round_trip(&src, &block);
+
+        // uniform data has nothing to split
+        assert!(encode_multi_array(&noise(0x4000, 16, 0), &mut Vec::new()).is_err());
+    }
+
+    #[test_log::test]
+    fn multi_array_layouts() {
+        let src = sections();
+        for block_size in [256, 4096] {
+            let intervals = multi_array::split(&src, block_size);
+            assert_eq!(intervals.iter().map(|i| i.len).sum::<usize>(), src.len());
+            for combined in [true, false] {
+                let payload = multi_array::Arrays {
+                    src: &src,
+                    intervals: &intervals,
+                }
+                .encode(combined)
+                .unwrap();
+                let mut block = Vec::new();
+                write_header(&mut block, 5, payload.len(), src.len()).unwrap();
+                block.extend_from_slice(&payload);
+                round_trip(&src, &block);
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn encode_fallback() {
+        let mut block = Vec::new();
+        let info = encode(b"z", &mut block).unwrap();
+        assert_eq!(info.block_type, BlockType::Memcopy);
+        round_trip(b"z", &block);
+
+        let src = noise(0x3000, 256, 0);
+        let mut block = Vec::new();
+        encode(&src, &mut block).unwrap();
+        round_trip(&src, &block);
+
+        let src = noise(0x3000, 4, 1);
+        let mut block = Vec::new();
+        let info = encode(&src, &mut block).unwrap();
+        assert_ne!(info.block_type, BlockType::Memcopy);
+        round_trip(&src, &block);
+
+        // a skewed distribution is cheaper with tANS than with whole bit codes
+        let src: Vec<u8> = noise(0x3000, 2, 3);
+        assert!(estimate_tans(&src).unwrap() < estimate_huffman(&src, false).unwrap());
+        let mut tans_block = Vec::new();
+        encode_tans(&src, &mut tans_block).unwrap();
+        let mut block = Vec::new();
+        encode(&src, &mut block).unwrap();
+        assert!(block.len() <= tans_block.len());
+        round_trip(&src, &block);
+    }
+
+    #[test_log::test]
+    fn errors() {
+        let mut dst = [0; 2];
+        assert!(decode(&[0x80, 0x03, b'a', b'b', b'c'], &mut dst).is_err());
+        assert!(decode(&[0x80, 0x05, b'a'], &mut [0; 8]).is_err());
+        assert!(decode(&[0xE0, 0x00, 0x00, 0x00], &mut [0; 8]).is_err());
+        assert!(inspect(&[]).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use crate::core::error::{ErrorContext, Res};
+use crate::entropy::bit_writer::BitWriter;
+use crate::entropy::encode_simple;
+use crate::entropy::huffman::histogram;
+
+/// Most arrays a combined index byte can refer to.
+const MAX_ARRAYS: usize = 15;
+/// Interval lengths are sent as a number of bits below an implicit leading one.
+const MAX_INTERVAL: usize = 0xFFFF;
+/// Rough cost in bits of the header and table of one more array.
+const ARRAY_BITS: f64 = 96.0;
+/// Rough cost in bits of one more interval index and length.
+const INTERVAL_BITS: f64 = 24.0;
+
+/// A run of bytes taken from one of the arrays.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Interval {
+    pub array: usize,
+    pub len: usize,
+}
+
+struct Model {
+    histo: [u32; 256],
+    total: u64,
+}
+
+impl Model {
+    fn new() -> Self {
+        Self {
+            histo: [0; 256],
+            total: 0,
+        }
+    }
+
+    fn add(&mut self, histo: &[u32; 256]) {
+        for (m, &h) in self.histo.iter_mut().zip(histo.iter()) {
+            *m += h;
+            self.total += h as u64;
+        }
+    }
+
+    /// Bits to code the contents with order-0 statistics of their own.
+    fn bits(&self) -> f64 {
+        bits(&self.histo, self.total)
+    }
+
+    /// Bits to add |histo| to the contents, including the change to the statistics.
+    fn added_bits(&self, histo: &[u32; 256], total: u64) -> f64 {
+        let mut combined = self.histo;
+        for (c, &h) in combined.iter_mut().zip(histo.iter()) {
+            *c += h;
+        }
+        bits(&combined, self.total + total) - self.bits()
+    }
+
+    /// Bits to code |histo| with the statistics of the contents, which stay fixed.
+    fn cost_of(&self, histo: &[u32; 256]) -> f64 {
+        let total = self.total.max(1) as f64;
+        histo
+            .iter()
+            .zip(self.histo.iter())
+            .filter(|(&h, _)| h != 0)
+            .map(|(&h, &m)| h as f64 * (total / (m as f64).max(0.25)).log2())
+            .sum()
+    }
+}
+
+fn bits(histo: &[u32; 256], total: u64) -> f64 {
+    let total = total as f64;
+    histo
+        .iter()
+        .filter(|&&h| h != 0)
+        .map(|&h| h as f64 * (total / h as f64).log2())
+        .sum()
+}
+
+/// Splits |src| into pieces of |block_size| bytes and groups pieces with similar byte
+/// statistics into up to [MAX_ARRAYS] arrays. Returns the array of each piece.
+fn cluster(src: &[u8], block_size: usize) -> Vec<usize> {
+    let blocks: Vec<_> = src
+        .chunks(block_size)
+        .map(|c| (histogram(c), c.len() as u64))
+        .collect();
+
+    // Greedy pass: grow an array or start a new one, whichever is cheaper
+    let mut models: Vec<Model> = Vec::new();
+    let mut assignment = Vec::with_capacity(blocks.len());
+    for (histo, total) in &blocks {
+        let previous = assignment.last().copied();
+        let switch = |i| {
+            if previous == Some(i) {
+                0.0
+            } else {
+                INTERVAL_BITS
+            }
+        };
+        let best = models
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (i, m.added_bits(histo, *total) + switch(i)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let fresh = bits(histo, *total) + ARRAY_BITS + INTERVAL_BITS;
+        let array = match best {
+            Some((i, cost)) if cost <= fresh || models.len() == MAX_ARRAYS => i,
+            _ => {
+                models.push(Model::new());
+                models.len() - 1
+            }
+        };
+        if let Some(m) = models.get_mut(array) {
+            m.add(histo);
+        }
+        assignment.push(array);
+    }
+
+    // Refine: move pieces to the array that codes them cheapest, then rebuild the arrays
+    for _ in 0..2 {
+        let mut previous = None;
+        for ((histo, _), array) in blocks.iter().zip(assignment.iter_mut()) {
+            let switch = |i| {
+                if previous == Some(i) {
+                    0.0
+                } else {
+                    INTERVAL_BITS
+                }
+            };
+            if let Some((i, _)) = models
+                .iter()
+                .enumerate()
+                .map(|(i, m)| (i, m.cost_of(histo) + switch(i)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+            {
+                *array = i;
+            }
+            previous = Some(*array);
+        }
+        let mut used: Vec<usize> = assignment.clone();
+        used.sort_unstable();
+        used.dedup();
+        models = used.iter().map(|_| Model::new()).collect();
+        for ((histo, _), array) in blocks.iter().zip(assignment.iter_mut()) {
+            *array = used.binary_search(array).unwrap_or_default();
+            if let Some(m) = models.get_mut(*array) {
+                m.add(histo);
+            }
+        }
+    }
+    assignment
+}
+
+/// Finds intervals of |src| that are better coded as separate arrays.
+pub(crate) fn split(src: &[u8], block_size: usize) -> Vec<Interval> {
+    let mut intervals: Vec<Interval> = Vec::new();
+    for (chunk, array) in src.chunks(block_size).zip(cluster(src, block_size)) {
+        match intervals.last_mut() {
+            Some(last) if last.array == array && last.len + chunk.len() <= MAX_INTERVAL => {
+                last.len += chunk.len()
+            }
+            _ => intervals.push(Interval {
+                array,
+                len: chunk.len(),
+            }),
+        }
+    }
+    intervals
+}
+
+pub(crate) struct Arrays<'a> {
+    pub src: &'a [u8],
+    pub intervals: &'a [Interval],
+}
+
+impl ErrorContext for Arrays<'_> {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "{} bytes in {} intervals",
+            self.src.len(),
+            self.intervals.len()
+        ))
+    }
+}
+
+impl Arrays<'_> {
+    fn count(&self) -> usize {
+        self.intervals
+            .iter()
+            .map(|i| i.array + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn data(&self) -> Vec<Vec<u8>> {
+        let mut arrays = vec![Vec::new(); self.count()];
+        let mut pos = 0;
+        for &Interval { array, len } in self.intervals {
+            if let (Some(a), Some(bytes)) = (arrays.get_mut(array), self.src.get(pos..pos + len)) {
+                a.extend_from_slice(bytes);
+            }
+            pos += len;
+        }
+        arrays
+    }
+
+    /// Writes the interval indexes, either one byte per interval holding both the array
+    /// and the bit count of the length, or as separate index and bit count arrays.
+    fn write_indexes(&self, combined: bool, out: &mut Vec<u8>) -> Res<()> {
+        let lenlog2: Vec<u8> = self.intervals.iter().map(|i| i.len.ilog2() as u8).collect();
+        let indexes = self.intervals.iter().map(|i| i.array as u8 + 1);
+        if combined {
+            let bytes: Vec<u8> = indexes
+                .zip(lenlog2.iter())
+                .map(|(i, &l)| (l << 4) | i)
+                .chain([0])
+                .collect();
+            out.extend(encode_simple(&bytes)?);
+        } else {
+            let bytes: Vec<u8> = indexes.chain([0]).collect();
+            out.extend(encode_simple(&bytes)?);
+            out.extend(encode_simple(&lenlog2)?);
+        }
+        Ok(())
+    }
+
+    /// Interval lengths below their leading one bit, alternating between a forward
+    /// stream and a backward stream stored reversed at the end.
+    fn write_lengths(&self, combined: bool) -> Vec<u8> {
+        let mut lens: Vec<usize> = self.intervals.iter().map(|i| i.len).collect();
+        if combined {
+            // the terminating index has a length too
+            lens.push(1);
+        }
+        let mut forward = BitWriter::default();
+        let mut backward = BitWriter::default();
+        for (i, &len) in lens.iter().enumerate() {
+            let stream = if i & 1 == 0 {
+                &mut forward
+            } else {
+                &mut backward
+            };
+            let n = len.ilog2();
+            stream.write((len - (1 << n)) as u32, n);
+        }
+        let mut out = forward.finish();
+        out.extend(backward.finish().iter().rev());
+        out
+    }
+
+    pub fn encode(&self, combined: bool) -> Res<Vec<u8>> {
+        let count = self.count();
+        self.assert_le(1, count)?;
+        self.assert_le(count, MAX_ARRAYS)?;
+        let mut out = vec![0x80 | count as u8];
+        for array in self.data() {
+            out.extend(encode_simple(&array)?);
+        }
+        let lengths = self.write_lengths(combined);
+        self.assert_le(lengths.len(), 0x3FFF)?;
+        let q = lengths.len() | if combined { 0x8000 } else { 0 };
+        out.extend_from_slice(&(q as u16).to_le_bytes());
+        self.write_indexes(combined, &mut out)?;
+        out.extend(lengths);
+        Ok(out)
+    }
+}
+
+/// Block sizes tried by the splitter, smaller ones find finer boundaries.
+const BLOCK_SIZES: [usize; 3] = [256, 1024, 4096];
+
+/// Encodes the payload of a type 5 multi-array block, without the block header.
+pub(crate) fn encode_multi_array(src: &[u8], out: &mut Vec<u8>) -> Res<()> {
+    let mut best: Option<Vec<u8>> = None;
+    for block_size in BLOCK_SIZES {
+        let intervals = split(src, block_size);
+        let arrays = Arrays {
+            src,
+            intervals: &intervals,
+        };
+        if arrays.count() < 2 {
+            continue;
+        }
+        for combined in [true, false] {
+            let payload = arrays.encode(combined)?;
+            if best.as_ref().is_none_or(|b| payload.len() < b.len()) {
+                best = Some(payload);
+            }
+        }
+    }
+    match best {
+        Some(payload) => out.extend(payload),
+        None => Arrays {
+            src,
+            intervals: &[],
+        }
+        .raise("No split found".into())?,
+    }
+    Ok(())
+}
+
+/// Encodes the payload of a type 5 block made of consecutive nested blocks,
+/// split where the byte statistics change.
+pub(crate) fn encode_recursive(src: &[u8], out: &mut Vec<u8>) -> Res<()> {
+    let mut best: Option<Vec<u8>> = None;
+    for block_size in BLOCK_SIZES {
+        let mut pieces: Vec<usize> = Vec::new();
+        let mut previous = None;
+        for (chunk, array) in src.chunks(block_size).zip(cluster(src, block_size)) {
+            match pieces.last_mut() {
+                Some(len) if previous == Some(array) => *len += chunk.len(),
+                _ => pieces.push(chunk.len()),
+            }
+            previous = Some(array);
+        }
+        if !(2..0x80).contains(&pieces.len()) {
+            continue;
+        }
+        let mut payload = vec![pieces.len() as u8];
+        let mut rest = src;
+        for len in pieces {
+            let (piece, tail) = rest.split_at(len);
+            payload.extend(encode_simple(piece)?);
+            rest = tail;
+        }
+        if best.as_ref().is_none_or(|b| payload.len() < b.len()) {
+            best = Some(payload);
+        }
+    }
+    match best {
+        Some(payload) => out.extend(payload),
+        None => Arrays {
+            src,
+            intervals: &[],
+        }
+        .raise("No split found".into())?,
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use crate::entropy::encode_statistical;
+
+/// Longest copy or run of a single two byte command.
+const MAX_MULTIPLE: usize = 0x701;
+
+/// Builds the command buffer read by `Core::decode_rle`: literals and run bytes
+/// from the front, commands from the back.
+pub(crate) fn commands(src: &[u8]) -> Vec<u8> {
+    let mut writer = Writer::default();
+    let mut literals_start = 0;
+    let mut i = 0;
+    while let Some(&b) = src.get(i) {
+        let run = src
+            .get(i..)
+            .map_or(0, |s| s.iter().take_while(|&&c| c == b).count());
+        // switching the run byte costs a command and a literal
+        if run >= if b == writer.rle_byte { 3 } else { 5 } {
+            writer.segment(src.get(literals_start..i).unwrap_or_default(), b, run);
+            i += run;
+            literals_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    writer.segment(
+        src.get(literals_start..).unwrap_or_default(),
+        writer.rle_byte,
+        0,
+    );
+
+    let mut buf = writer.literals;
+    buf.extend(writer.commands.iter().rev());
+    buf
+}
+
+#[derive(Default)]
+struct Writer {
+    literals: Vec<u8>,
+    /// Command bytes in the order the decoder reads them, back to front.
+    commands: Vec<u8>,
+    rle_byte: u8,
+}
+
+impl Writer {
+    /// Copies |literals| then repeats |rle_byte| |run| times.
+    fn segment(&mut self, literals: &[u8], rle_byte: u8, run: usize) {
+        if rle_byte != self.rle_byte && run != 0 {
+            self.literals.push(rle_byte);
+            self.commands.push(1);
+            self.rle_byte = rle_byte;
+        }
+        self.literals.extend_from_slice(literals);
+
+        let mut copy = literals.len();
+        while copy >= 64 {
+            let k = (copy / 64).min(MAX_MULTIPLE);
+            self.long_command(k + 511);
+            copy -= k * 64;
+        }
+        let (short_run, mut long_run) = (run % 128, run - run % 128);
+        match (copy, short_run) {
+            (0, 0) => {}
+            (0..=15, 3..=15) => self.commands.push(((short_run << 4) | (15 - copy)) as u8),
+            (15, 0) => self.commands.push(0),
+            _ => self.long_command(4096 + (short_run << 6) + copy),
+        }
+        while long_run > 0 {
+            let k = (long_run / 128).min(MAX_MULTIPLE);
+            self.long_command(k + 0x8ff);
+            long_run -= k * 128;
+        }
+    }
+
+    /// Two byte command, stored little endian and read from its high byte.
+    fn long_command(&mut self, v: usize) {
+        self.commands.extend_from_slice(&[(v >> 8) as u8, v as u8]);
+    }
+}
+
+/// Encodes the payload of a type 3 block, without the block header.
+pub(crate) fn encode(src: &[u8], out: &mut Vec<u8>) {
+    if let Some((&first, rest)) = src.split_first() {
+        if rest.iter().all(|&b| b == first) {
+            out.push(first);
+            return;
+        }
+    }
+    let commands = commands(src);
+    // A leading entropy block holds the start of the command buffer,
+    // zero marks a raw buffer.
+    match encode_statistical(&commands) {
+        Some(block) if block.len() <= commands.len() => out.extend(block),
+        _ => {
+            out.push(0);
+            out.extend(commands);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::core::error::{ErrorBuilder, ErrorContext, Res};
+use crate::core::tans::{TansData, TansDecoder};
+use crate::entropy::bit_writer::{BitWriter, LsbWriter};
+use crate::entropy::huffman::{histogram, range_params, symbol_ranges};
+
+/// Table sizes supported by the decoder, as log2 of the number of states.
+const L_BITS: std::ops::RangeInclusive<u32> = 8..=11;
+
+/// Symbol weights normalized to sum to the table size `1 << l_bits`.
+pub(crate) struct TansCode {
+    l_bits: u32,
+    pub weights: [u32; 256],
+}
+
+impl ErrorContext for TansCode {}
+
+// Tables are indexed by symbol, which is bounded by construction.
+#[allow(clippy::indexing_slicing)]
+impl TansCode {
+    /// Scales |histo| to a table of `1 << l_bits` states, keeping every used symbol.
+    /// Returns None if fewer than two symbols are used.
+    pub fn new(histo: &[u32; 256], l_bits: u32) -> Option<Self> {
+        let l = 1u64 << l_bits;
+        let total: u64 = histo.iter().map(|&f| f as u64).sum();
+        if histo.iter().filter(|&&f| f != 0).count() < 2 {
+            return None;
+        }
+        let mut weights = [0; 256];
+        for (w, &f) in weights.iter_mut().zip(histo.iter()) {
+            if f != 0 {
+                *w = ((f as u64 * l + total / 2) / total).max(1) as u32;
+            }
+        }
+
+        // Fix up rounding one state at a time, where it costs the fewest bits
+        let cost = |f: u32, from: u32, to: u32| f as f64 * (from as f64 / to as f64).log2();
+        let mut sum: u64 = weights.iter().map(|&w| w as u64).sum();
+        while sum > l {
+            let (s, _) = (0..256)
+                .filter(|&s| weights[s] > 1)
+                .map(|s| (s, cost(histo[s], weights[s], weights[s] - 1)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))?;
+            weights[s] -= 1;
+            sum -= 1;
+        }
+        while sum < l {
+            let (s, _) = (0..256)
+                .filter(|&s| weights[s] != 0)
+                .map(|s| (s, cost(histo[s], weights[s], weights[s] + 1)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))?;
+            weights[s] += 1;
+            sum += 1;
+        }
+        Some(Self { l_bits, weights })
+    }
+
+    /// Number of bits needed for the symbols counted in |histo|, excluding the table.
+    pub fn cost(&self, histo: &[u32; 256]) -> f64 {
+        let l = (1 << self.l_bits) as f64;
+        histo
+            .iter()
+            .zip(self.weights.iter())
+            .filter(|(&f, _)| f != 0)
+            .map(|(&f, &w)| f as f64 * (l / w as f64).log2())
+            .sum()
+    }
+
+    fn used(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        self.weights
+            .iter()
+            .enumerate()
+            .filter(|(_, &w)| w != 0)
+            .map(|(s, &w)| (s, w))
+    }
+
+    /// Writes the table size and the weights in the format read by `TansDecoder::decode_table`,
+    /// either as a sparse list (|q| is None) or Golomb-Rice coded with |q| fixed low bits.
+    /// Returns false if the format can't represent this table.
+    pub fn write_table(&self, bits: &mut BitWriter, q: Option<u32>) -> bool {
+        bits.write_bit(false);
+        bits.write(self.l_bits - 8, 2);
+        match q {
+            None => self.write_sparse(bits),
+            Some(q) => self.write_golomb_rice(bits, q),
+        }
+    }
+
+    fn write_sparse(&self, bits: &mut BitWriter) -> bool {
+        // All but the heaviest symbol are sent in ascending weight order as deltas,
+        // the heaviest one gets the remaining weight.
+        let mut used: Vec<_> = self.used().map(|(s, w)| (w, s)).collect();
+        used.sort_unstable();
+        let Some(&(_, last)) = used.last() else {
+            return false;
+        };
+        let listed = &used[..used.len() - 1];
+        if listed.is_empty() || listed.len() > 8 {
+            return false;
+        }
+        let mut prev = 0;
+        let deltas: Vec<_> = listed
+            .iter()
+            .map(|&(w, s)| {
+                let delta = w - prev;
+                prev = w;
+                (s, delta)
+            })
+            .collect();
+        let max_delta = deltas.iter().map(|&(_, d)| d).max().unwrap_or(0);
+        let max_delta_bits = (32 - max_delta.leading_zeros()).max(1);
+
+        bits.write_bit(false);
+        bits.write(listed.len() as u32 - 1, 3);
+        bits.write(max_delta_bits, self.l_bits.ilog2() + 1);
+        for (sym, delta) in deltas {
+            bits.write(sym as u32, 8);
+            bits.write(delta, max_delta_bits);
+        }
+        bits.write(last as u32, 8);
+        true
+    }
+
+    fn write_golomb_rice(&self, bits: &mut BitWriter, q: u32) -> bool {
+        let num_symbols = self.used().count();
+        let ranges = symbol_ranges(self.used().map(|(s, _)| s));
+        if num_symbols < 2 {
+            return false;
+        }
+        let params = range_params(&ranges);
+
+        bits.write_bit(true);
+        bits.write(q, 3);
+        bits.write(num_symbols as u32 - 1, 8);
+        if !bits.write_fluff(params.len(), num_symbols) {
+            return false;
+        }
+
+        // Each weight is predicted from a running average, and the residual sent
+        // with a unary coded number of extra bits above |q|.
+        let mut average = 6;
+        let mut values = Vec::with_capacity(num_symbols);
+        for (_, weight) in self.used() {
+            let v = weight - 1;
+            let average_div4 = average >> 2;
+            let coded = if v <= 2 * average_div4 {
+                let delta = v as i32 - average_div4 as i32;
+                ((delta << 1) ^ (delta >> 31)) as u32
+            } else {
+                v
+            };
+            average = average + v.min(2 * average_div4) - average_div4;
+            let nextra = (coded + (1 << q)).ilog2().max(q);
+            values.push((coded + (1 << q) - (1 << nextra), nextra));
+        }
+        for &(_, nextra) in &values {
+            bits.write_unary(nextra - q);
+        }
+        for &(p, _, _) in &params {
+            bits.write_unary(p);
+        }
+        for (_, v, n) in params {
+            bits.write(v, n);
+        }
+        for (v, n) in values {
+            bits.write(v, n);
+        }
+        true
+    }
+
+    /// The symbol tables in the order `TansDecoder::decode_table` produces them.
+    fn tans_data(&self) -> TansData {
+        let mut data = TansData {
+            a_used: 0,
+            b_used: 0,
+            a: [0; 256],
+            b: [0; 256],
+        };
+        for (sym, w) in self.used() {
+            if w == 1 {
+                data.a[data.a_used as usize] = sym as u8;
+                data.a_used += 1;
+            } else {
+                data.b[data.b_used as usize] = ((sym as u32) << 16) + w;
+                data.b_used += 1;
+            }
+        }
+        data
+    }
+
+    /// Encodes all but the last five bytes of |src|, which become the final states of the
+    /// five interleaved decoders. Symbols alternate between the forward and the backward
+    /// stream in groups of five.
+    pub fn write_streams(&self, src: &[u8], out: &mut Vec<u8>) -> Res<()> {
+        let l = 1u32 << self.l_bits;
+        self.assert_le(5, src.len())?;
+        let (symbols, last) = src.split_at(src.len() - 5);
+
+        // State to move to for each (symbol, x >> bits) pair, found from the decoding table
+        let lut = TansDecoder::default().init_lut(&self.tans_data(), self.l_bits as i32);
+        let mut offsets = [0; 256];
+        let mut total = 0;
+        for (offset, &w) in offsets.iter_mut().zip(self.weights.iter()) {
+            *offset = total;
+            total += w as usize;
+        }
+        let mut next_state = vec![0; l as usize];
+        for (state, e) in lut.iter().enumerate() {
+            let sym = e.symbol as usize;
+            let ww = (e.w as u32 + l) >> e.bits_x;
+            next_state[offsets[sym] + (ww - self.weights[sym]) as usize] = state as u32;
+        }
+
+        // Encode backwards, starting from the states the decoder has to end in
+        let mut x = [0; 5];
+        for (x, &b) in x.iter_mut().zip(last) {
+            *x = b as u32 + l;
+        }
+        let mut forward = Vec::new();
+        let mut backward = Vec::new();
+        for (i, &sym) in symbols.iter().enumerate().rev() {
+            let w = self.weights[sym as usize];
+            self.assert_ne(w, 0)?;
+            let x = &mut x[i % 5];
+            let mut n = self.l_bits - w.ilog2();
+            if *x >> n < w {
+                n -= 1;
+            }
+            let stream = if i % 10 < 5 {
+                &mut forward
+            } else {
+                &mut backward
+            };
+            stream.push((*x & ((1 << n) - 1), n));
+            *x = next_state[offsets[sym as usize] + ((*x >> n) - w) as usize] + l;
+        }
+
+        let mut f = LsbWriter::default();
+        let mut b = LsbWriter::default();
+        for (i, x) in x.iter().enumerate() {
+            let writer = if i & 1 == 0 { &mut f } else { &mut b };
+            writer.write(x - l, self.l_bits);
+        }
+        for (v, n) in forward.into_iter().rev() {
+            f.write(v, n);
+        }
+        for (v, n) in backward.into_iter().rev() {
+            b.write(v, n);
+        }
+        out.extend(f.finish());
+        out.extend(b.finish().iter().rev());
+        Ok(())
+    }
+}
+
+/// Picks the table size and format with the smallest estimated output for |src|.
+fn best_code(src: &[u8]) -> Option<(TansCode, BitWriter, usize)> {
+    let histo = histogram(src.get(..src.len().checked_sub(5)?)?);
+    L_BITS
+        .filter_map(|l_bits| TansCode::new(&histo, l_bits))
+        .filter_map(|code| {
+            let table = (0..8)
+                .map(Some)
+                .chain([None])
+                .filter_map(|q| {
+                    let mut bits = BitWriter::default();
+                    code.write_table(&mut bits, q).then_some(bits)
+                })
+                .min_by_key(BitWriter::bit_len)?;
+            let bits = code.cost(&histo).ceil() as usize + 5 * code.l_bits as usize;
+            // both streams are padded to whole bytes
+            let size = table.bit_len().div_ceil(8) + bits.div_ceil(8) + 1;
+            Some((code, table, size))
+        })
+        .min_by_key(|(_, _, size)| *size)
+}
+
+/// Estimated size of the payload [encode] produces for |src|, None if it can't be encoded.
+pub(crate) fn estimate(src: &[u8]) -> Option<usize> {
+    best_code(src).map(|(_, _, size)| size)
+}
+
+/// Encodes the payload of a type 1 block, without the block header.
+pub(crate) fn encode(src: &[u8], out: &mut Vec<u8>) -> Res<()> {
+    let Some((code, table, _)) = best_code(src) else {
+        Err(ErrorBuilder {
+            message: Some("tANS coding needs at least two symbols before the last five".into()),
+            ..Default::default()
+        })?
+    };
+    out.extend(table.finish());
+    code.write_streams(src, out)
+}
@@ -0,0 +1,244 @@
+use crate::dcx::DcxHeader;
+use crate::extractor::{DecoderType, Extractor, QuantumHeader};
+
+/// How a stream is wrapped. The format itself doesn't store the decompressed size,
+/// so tools commonly write it in front of the stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Framing {
+    /// The stream starts at the first byte, and the size is unknown.
+    Raw,
+    /// 8 byte little endian size in front of the stream.
+    SizePrefix8,
+    /// 4 byte little endian size in front of the stream.
+    SizePrefix4,
+    /// A FromSoftware `DCX` container with a `KRAK` payload, after a header of |prefix_len|
+    /// bytes that has the size, see [crate::dcx].
+    Dcx { prefix_len: usize },
+}
+
+impl Framing {
+    /// Number of bytes in front of the stream.
+    pub fn prefix_len(&self) -> usize {
+        match self {
+            Framing::Raw => 0,
+            Framing::SizePrefix8 => 8,
+            Framing::SizePrefix4 => 4,
+            Framing::Dcx { prefix_len } => *prefix_len,
+        }
+    }
+
+    /// The framing of a container that holds a single stream, from its magic.
+    fn container(data: &[u8]) -> Option<Framing> {
+        if !data.starts_with(b"DCX\0") {
+            return None;
+        }
+        let header = DcxHeader::parse(data).ok()?;
+        (header.method == *b"KRAK").then(|| Framing::Dcx {
+            prefix_len: header.payload_offset(),
+        })
+    }
+
+    fn size(&self, data: &[u8]) -> Option<u64> {
+        match self {
+            Framing::Raw => None,
+            Framing::SizePrefix8 => Some(u64::from_le_bytes(*data.first_chunk()?)),
+            Framing::SizePrefix4 => Some(u32::from_le_bytes(*data.first_chunk()?).into()),
+            Framing::Dcx { .. } => Some(DcxHeader::parse(data).ok()?.uncompressed_size.into()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// The block header is valid, but the rest doesn't look like the start of a stream.
+    Low,
+    /// The block header is valid, and there isn't enough data, or structure, to check more.
+    Medium,
+    /// The block header and the first quantum header are valid.
+    High,
+}
+
+/// A guess at the framing and codec of a stream, see [detect].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Detection {
+    pub framing: Framing,
+    pub decoder_type: DecoderType,
+    /// Decompressed size from the prefix.
+    pub size: Option<usize>,
+    pub confidence: Confidence,
+}
+
+impl Detection {
+    /// Extractor for the stream in |data|, which starts where the detected data did.
+    pub fn open<'a>(&self, data: &'a [u8]) -> Extractor<&'a [u8]> {
+        Extractor::new(data.get(self.framing.prefix_len()..).unwrap_or_default())
+    }
+}
+
+/// Guesses how |data|, the start of a file, is framed, by checking for a block header
+/// and a quantum header after each known prefix.
+/// Returns the plausible framings, most confident first.
+///
+/// Of the containers, only `DCX` is a framing, as it holds one stream. Archives of many
+/// streams, like pak files or IoStore TOCs (`-==--==--==--==-`), whose streams are in a
+/// separate `.ucas` file, are out of scope; open them with the modules of their formats.
+pub fn detect(data: &[u8]) -> Vec<Detection> {
+    let framings = [Framing::SizePrefix8, Framing::SizePrefix4, Framing::Raw];
+    let mut detections: Vec<Detection> = Framing::container(data)
+        .into_iter()
+        .chain(framings)
+        .filter_map(|framing| check(data, framing))
+        .collect();
+    // stable, so containers and prefixed framings win ties
+    detections.sort_by_key(|d| std::cmp::Reverse(d.confidence));
+    detections
+}
+
+fn check(data: &[u8], framing: Framing) -> Option<Detection> {
+    let mut input = data.get(framing.prefix_len()..)?;
+    let (header, quantum) = {
+        let mut extractor = Extractor::new(&mut input);
+        extractor.parse_header().ok()?;
+        let quantum = if extractor.header.uncompressed {
+            None
+        } else {
+            Some(extractor.parse_quantum_header())
+        };
+        (std::mem::take(&mut extractor.header), quantum)
+    };
+
+    let mut confidence = match quantum {
+        // stored blocks have no quantum headers to check
+        None => Confidence::Medium,
+        Some(Ok(quantum)) if !quantum.has_known_flags(header.block_size()) => return None,
+        // there's no history to match at the start of a stream
+        Some(Ok(QuantumHeader::WholeMatch { .. })) => Confidence::Low,
+        Some(Ok(_)) => Confidence::High,
+        // cut off in the middle of the quantum header
+        Some(Err(_)) if input.is_empty() => Confidence::Medium,
+        Some(Err(_)) => return None,
+    };
+    // streams start by resetting the decoder
+    if !header.restart_decoder {
+        confidence = Confidence::Low;
+    }
+
+    let size = match framing.size(data) {
+        // sizes above a terabyte are more likely the start of a stream
+        Some(size @ 1..=0xFF_FFFF_FFFF) => usize::try_from(size).ok(),
+        Some(_) => {
+            confidence = Confidence::Low;
+            None
+        }
+        None => None,
+    };
+    log::debug!("{:?} with {:?}: {:?}", framing, header, confidence);
+    Some(Detection {
+        framing,
+        decoder_type: header.decoder_type,
+        size,
+        confidence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::xml_streams;
+
+    /// A `DCX` header for a `KRAK` payload.
+    fn dcx_header(len: usize, compressed_len: usize) -> Vec<u8> {
+        DcxHeader {
+            version: 0x11000,
+            unknown: [0x2C, 0x4C],
+            uncompressed_size: len as u32,
+            compressed_size: compressed_len as u32,
+            method: *b"KRAK",
+            parameters: vec![6, 0, 0, 0, 0, 0, 0, 0],
+        }
+        .to_bytes()
+    }
+
+    #[test_log::test]
+    fn detects_testdata() {
+        for (path, stream, len) in xml_streams() {
+            let dcx = dcx_header(len, stream.len());
+            let stream = &stream[..64];
+            // the same stream with both sizes in front, and in a container
+            for (framing, size) in [
+                (
+                    Framing::Dcx {
+                        prefix_len: dcx.len(),
+                    },
+                    dcx,
+                ),
+                (Framing::SizePrefix4, (len as u32).to_le_bytes().to_vec()),
+                (Framing::SizePrefix8, (len as u64).to_le_bytes().to_vec()),
+            ] {
+                let start = size.len();
+                let data = [size, stream.to_vec()].concat();
+                let best = detect(&data)[0];
+                assert_eq!(best.framing, framing, "{:?}", path);
+                assert_eq!(best.size, Some(len), "{:?}", path);
+                assert_eq!(best.confidence, Confidence::High, "{:?}", path);
+
+                // only the block header
+                let best = detect(&data[..start + 3])[0];
+                assert_eq!(best.framing, framing, "{:?}", path);
+                assert_eq!(best.confidence, Confidence::Medium, "{:?}", path);
+            }
+
+            let best = detect(stream)[0];
+            let decoder_type = match path.extension().unwrap().to_str().unwrap() {
+                "bitknit" => DecoderType::Bitknit,
+                "lzna" => DecoderType::Lzna,
+                "mermaid" | "selkie" => DecoderType::Mermaid,
+                "kraken" => DecoderType::Kraken,
+                _ => DecoderType::Leviathan,
+            };
+            assert_eq!(best.decoder_type, decoder_type, "{:?}", path);
+            assert_eq!(best.framing, Framing::Raw, "{:?}", path);
+            assert_eq!(best.confidence, Confidence::High, "{:?}", path);
+        }
+    }
+
+    #[test_log::test]
+    fn opens_detected() {
+        // 256k Kraken block set to 7
+        let mut data = 0x40000u64.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0x8C, 0x06, 0x07, 0xFF, 0xFF, 0x07]);
+        let detections = detect(&data);
+        assert_eq!(
+            detections,
+            [Detection {
+                framing: Framing::SizePrefix8,
+                decoder_type: DecoderType::Kraken,
+                size: Some(0x40000),
+                confidence: Confidence::High,
+            }]
+        );
+        let mut out = vec![0; 0x40000];
+        assert_eq!(detections[0].open(&data).read(&mut out).unwrap(), out.len());
+        assert!(out.iter().all(|&b| b == 7));
+
+        // only the stream is detected in a container
+        let mut dcx = dcx_header(0x40000, 6);
+        let prefix_len = dcx.len();
+        dcx.extend_from_slice(&data[8..]);
+        let detections = detect(&dcx);
+        assert_eq!(detections[0].framing, Framing::Dcx { prefix_len });
+        assert_eq!(detections[0].size, Some(0x40000));
+        assert_eq!(detections[0].open(&dcx).read(&mut out).unwrap(), out.len());
+        let mut toc = b"-==--==--==--==-".to_vec();
+        toc.resize(64, 0);
+        assert!(detect(&toc).is_empty());
+
+        assert!(detect(&[0; 64]).is_empty());
+        assert!(detect(&[]).is_empty());
+        // a continuation block, or a header found by chance
+        assert_eq!(
+            detect(&[0x0C, 0x06, 0x07, 0xFF, 0xFF, 0x07])[0].confidence,
+            Confidence::Low
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use crate::core::error::{ErrorContext, Res, ResultBuilder};
+use crate::extractor::{Extractor, Output, DEFAULT_WINDOW, LARGE_BLOCK};
+use std::io::Read;
+
+/// Where a block starts in the input.
+#[derive(Debug, Copy, Clone)]
+struct Block {
+    /// Offset of the block header.
+    pos: usize,
+    restart_decoder: bool,
+}
+
+/// The decoder of the last [MappedStream::read_at], which later reads continue.
+struct Decoder {
+    /// The restart block it started at.
+    block: usize,
+    /// Counts its output from that block on, and keeps the last window of it as history.
+    extractor: Extractor<std::io::Empty>,
+}
+
+/// A stream that is in memory as a whole, such as a memory-mapped file. Any `AsRef<[u8]>`
+/// will do, e.g. a `memmap2::Mmap` or a `Vec<u8>`. Quanta are decoded where they are in the
+/// input, instead of being copied into a buffer first as with [Extractor].
+pub struct MappedStream<D: AsRef<[u8]>> {
+    data: D,
+    len: usize,
+    /// The blocks up to the furthest [MappedStream::read_at].
+    blocks: Vec<Block>,
+    /// Offset of the first block header not indexed yet.
+    indexed: usize,
+    decoder: Option<Decoder>,
+}
+
+impl<D: AsRef<[u8]>> MappedStream<D> {
+    /// The stream in |data|, which decompresses to |len| bytes, see [crate::probe_size].
+    pub fn new(data: D, len: usize) -> Self {
+        MappedStream {
+            data,
+            len,
+            blocks: Vec::new(),
+            indexed: 0,
+            decoder: None,
+        }
+    }
+
+    /// Decompressed size.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> D {
+        self.data
+    }
+
+    /// Decodes the stream into |dst|, up to [MappedStream::len] bytes.
+    /// Returns the number of bytes written.
+    pub fn read(&self, dst: &mut [u8]) -> std::io::Result<usize> {
+        let len = dst.len().min(self.len);
+        let dst = dst.get_mut(..len).unwrap_or_default();
+        Ok(Extractor::new(self.data.as_ref()).fill_in_place(&mut Output::new(dst))?)
+    }
+
+    /// Decodes the output from |offset| into |buf|, stopping at the end of the stream.
+    /// Returns the number of bytes written.
+    ///
+    /// Decoding starts at the last block at or before |offset| that restarts the decoder,
+    /// as blocks that start a seek chunk do, since those don't reference the output before
+    /// them. The block and quantum headers are read up to the block of |offset|; after that
+    /// only the input of the quanta decoded is read, so the pages of the rest of a mapped file
+    /// aren't touched.
+    ///
+    /// The decoder keeps the last [DEFAULT_WINDOW] bytes of output, like
+    /// [crate::decompress_to_writer], so a read at or after the end of the previous one
+    /// continues where that stopped. Reads further back than the window, or before the
+    /// restart block, decode again from the restart block.
+    pub fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(self.read_range(offset, buf)?)
+    }
+
+    fn read_range(&mut self, offset: usize, buf: &mut [u8]) -> Res<usize> {
+        let end = offset.saturating_add(buf.len()).min(self.len);
+        if offset >= end {
+            return Ok(0);
+        }
+        self.index_to(offset / LARGE_BLOCK)?;
+        let start = self
+            .blocks
+            .get(..=offset / LARGE_BLOCK)
+            .err()?
+            .iter()
+            .rposition(|block| block.restart_decoder)
+            .unwrap_or(0);
+        let out_start = start * LARGE_BLOCK;
+
+        let mut decoder = match self.decoder.take() {
+            Some(decoder)
+                if decoder.block == start && out_start + decoder.extractor.dropped <= offset =>
+            {
+                decoder
+            }
+            _ => {
+                log::debug!("Decoding {}..{} from block {}", offset, end, start);
+                let block = self.blocks.get(start).msg_of(&start)?;
+                let mut extractor = Extractor::new(std::io::empty());
+                extractor.pos = block.pos;
+                Decoder {
+                    block: start,
+                    extractor,
+                }
+            }
+        };
+        let input = self.data.as_ref().get(decoder.extractor.pos..).err()?;
+        let mut extractor = decoder.extractor.with_input(input);
+        let result = extractor.fill_range(
+            out_start,
+            self.len,
+            offset,
+            buf.get_mut(..end - offset).err()?,
+        );
+        decoder.extractor = extractor.with_input(std::io::empty());
+        // a failed decoder may have stopped in the middle of a quantum
+        if result.is_ok() {
+            self.decoder = Some(decoder);
+        }
+        result
+    }
+
+    /// Reads the block and quantum headers up to |block|, if the stream gets that far.
+    fn index_to(&mut self, block: usize) -> Res<()> {
+        let input = self.data.as_ref().get(self.indexed..).err()?;
+        let mut extractor = Extractor::new(input);
+        extractor.pos = self.indexed;
+        while self.blocks.len() <= block && self.blocks.len() * LARGE_BLOCK < self.len {
+            let len = (self.len - self.blocks.len() * LARGE_BLOCK).min(LARGE_BLOCK);
+            self.blocks.push(extractor.index_block(len)?);
+            self.indexed = extractor.pos;
+        }
+        log::debug!("Indexed {} blocks", self.blocks.len());
+        Ok(())
+    }
+}
+
+impl<D: AsRef<[u8]>> ErrorContext for MappedStream<D> {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "mapped stream of {} bytes to {}",
+            self.data.as_ref().len(),
+            self.len
+        ))
+    }
+}
+
+impl Extractor<&[u8]> {
+    /// Like [Extractor::fill], borrowing the quanta from the input instead of copying them.
+    fn fill_in_place(&mut self, buf: &mut Output) -> Res<usize> {
+        let mut bytes_written = 0;
+        while bytes_written < buf.len() {
+            if ((self.written + bytes_written) & 0x3FFFF) == 0 {
+                self.parse_header()?
+            }
+            let len = (buf.len() - bytes_written).min(self.header.block_size());
+            let quantum = self.read_quantum_with(len, Self::take)?;
+            match self.decode(quantum, buf, bytes_written, len)? {
+                0 => break,
+                count => bytes_written += count,
+            }
+        }
+        self.written += bytes_written;
+        Ok(bytes_written)
+    }
+
+    /// Reads the headers of the next block of |len| bytes of output.
+    fn index_block(&mut self, len: usize) -> Res<Block> {
+        let pos = self.pos;
+        self.parse_header()?;
+        let restart_decoder = self.header.restart_decoder;
+        let mut written = 0;
+        while written < len {
+            let size = (len - written).min(self.header.block_size());
+            self.read_quantum_with(size, Self::take)?;
+            written += size;
+        }
+        Ok(Block {
+            pos,
+            restart_decoder,
+        })
+    }
+
+    /// Decodes up to the end of |buf|, which is the output from |offset|, keeping a window of
+    /// history. |start| is where in the output of |len| bytes this extractor started, at a
+    /// block boundary.
+    fn fill_range(
+        &mut self,
+        start: usize,
+        len: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Res<usize> {
+        let end = offset + buf.len();
+        let mut ring = std::mem::take(&mut self.history);
+        let mut window = DEFAULT_WINDOW;
+        // the output decoded before
+        let ring_start = start + self.dropped;
+        copy_range(&ring, ring_start, offset, buf)?;
+
+        while start + self.written < end {
+            if (self.written & 0x3FFFF) == 0 {
+                self.parse_header()?;
+                window = window.min(self.header.decoder_type.max_distance());
+            }
+            // quanta only decode whole
+            let size = (len - start - self.written).min(self.header.block_size());
+            self.make_room(&mut ring, window, size);
+            let at = ring.len();
+            let mut out = Output::extend(&mut ring, size);
+            let quantum = self.read_quantum_with(size, Self::take)?;
+            let count = self.decode(quantum, &mut out, at, size)?;
+            out.init_to(at + count)?;
+            // SAFETY: init_to initialized the output up to there
+            unsafe { ring.set_len(at + count) };
+            copy_range(ring.get(at..).err()?, start + self.written, offset, buf)?;
+            self.written += count;
+            if count == 0 {
+                break;
+            }
+        }
+        self.history = ring;
+        Ok(buf.len())
+    }
+}
+
+impl<In: Read> Extractor<In> {
+    /// This extractor, reading the rest of the stream from |input|.
+    fn with_input<I: Read>(self, input: I) -> Extractor<I> {
+        Extractor {
+            input,
+            pos: self.pos,
+            written: self.written,
+            resumed_at: self.resumed_at,
+            dropped: self.dropped,
+            header: self.header,
+            quantum: self.quantum,
+            bitknit_state: self.bitknit_state,
+            lzna_state: self.lzna_state,
+            pipelined: self.pipelined,
+            history: self.history,
+        }
+    }
+}
+
+/// Copies the part of |decoded|, the output from |pos|, that |buf| from |offset| covers.
+fn copy_range(decoded: &[u8], pos: usize, offset: usize, buf: &mut [u8]) -> Res<()> {
+    let from = pos.max(offset);
+    let to = (pos + decoded.len()).min(offset + buf.len());
+    if from < to {
+        buf.get_mut(from - offset..to - offset)
+            .err()?
+            .copy_from_slice(decoded.get(from - pos..to - pos).err()?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::xml_streams;
+    use crate::tests::{kraken_stream, sample};
+
+    #[test_log::test]
+    fn reads_ranges() {
+        let data = sample(5 * LARGE_BLOCK + 1000, 0);
+        // one seek chunk per block, and a stream without them
+        let seekable: Vec<u8> = data.chunks(LARGE_BLOCK).flat_map(kraken_stream).collect();
+        let whole = kraken_stream(&data);
+
+        for stream in [&seekable, &whole] {
+            let mut stream = MappedStream::new(stream.as_slice(), data.len());
+            let mut out = vec![0; data.len()];
+            assert_eq!(stream.read(&mut out).unwrap(), data.len());
+            assert!(out == data);
+
+            for (offset, len) in [
+                (0, 100),
+                (1000, 5000),
+                (LARGE_BLOCK - 5, 10),
+                (3 * LARGE_BLOCK + 7, LARGE_BLOCK),
+                (data.len() - 10, 100),
+            ] {
+                let mut buf = vec![0; len];
+                let read = stream.read_at(offset, &mut buf).unwrap();
+                assert_eq!(read, len.min(data.len() - offset));
+                assert!(buf[..read] == data[offset..offset + read], "{}", offset);
+            }
+            assert_eq!(stream.read_at(data.len(), &mut [0; 10]).unwrap(), 0);
+        }
+
+        // only the headers up to the block read are indexed
+        let mut corrupt = seekable.clone();
+        let mut stream = MappedStream::new(&seekable, data.len());
+        stream.read_at(0, &mut [0; 1]).unwrap();
+        assert_eq!(stream.blocks.len(), 1);
+        stream.read_at(data.len() - 1, &mut [0; 1]).unwrap();
+        let blocks = stream.blocks;
+        assert_eq!(blocks.len(), 6);
+        assert!(blocks.iter().all(|block| block.restart_decoder));
+
+        // only the seek chunk of the range is decoded
+        corrupt[blocks[1].pos + 10..blocks[2].pos].fill(0x55);
+        let mut stream = MappedStream::new(corrupt, data.len());
+        let mut buf = vec![0; 1000];
+        stream.read_at(4 * LARGE_BLOCK, &mut buf).unwrap();
+        assert!(buf == data[4 * LARGE_BLOCK..4 * LARGE_BLOCK + 1000]);
+        let mut out = vec![0; data.len()];
+        assert!(stream.read(&mut out).is_err() || out != data);
+    }
+
+    #[test_log::test]
+    fn reads_testdata() {
+        for (path, data, len) in xml_streams() {
+            let expected = crate::decompress_to_vec(&data, len).unwrap();
+            let mut stream = MappedStream::new(data.clone(), len);
+
+            // reads in order continue the decoder, without reading the input it and the index are past
+            let mut offset = 0;
+            while offset < len {
+                let mut buf = vec![0; 100_000];
+                let read = stream.read_at(offset, &mut buf).unwrap();
+                assert!(buf[..read] == expected[offset..offset + read], "{:?}", path);
+                offset += read;
+                let decoder = &stream.decoder.as_ref().unwrap().extractor;
+                assert!(decoder.written < offset + LARGE_BLOCK, "{:?}", path);
+                let pos = decoder.pos.min(stream.indexed);
+                stream.data[..pos].fill(0x55);
+            }
+
+            // and reads before them start over
+            let mut stream = MappedStream::new(data.as_slice(), len);
+            for offset in [len / 2, len / 3, 10] {
+                let mut buf = vec![0; 1000];
+                let read = stream.read_at(offset, &mut buf).unwrap();
+                assert!(buf[..read] == expected[offset..offset + read], "{:?}", path);
+            }
+        }
+    }
+}
@@ -2,12 +2,30 @@ use crate::algorithm::Leviathan;
 use crate::algorithm::Mermaid;
 use crate::algorithm::{Bitknit, BitknitState, Kraken};
 use crate::algorithm::{Lzna, LznaState};
-use crate::core::error::End::{Idx, Len};
+use crate::core::error::End::Idx;
 use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::state::{persist_fields, take, Persist};
 use crate::core::Core;
+use output::Output;
 use std::io::Read;
+use std::mem::MaybeUninit;
+use std::ops::Range;
 
-#[derive(Debug, Default)]
+mod detect;
+mod mapped;
+mod output;
+mod pipeline;
+mod probe;
+mod raw;
+mod stream;
+
+pub use detect::{detect, Confidence, Detection, Framing};
+pub use mapped::MappedStream;
+pub use probe::{probe_size, SizeProbe};
+pub use raw::{decode_raw, decode_raw_with_state, CodecState};
+pub use stream::{decompress_to_writer, DEFAULT_WINDOW};
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub enum DecoderType {
     #[default]
     Lzna = 0x5,
@@ -29,25 +47,80 @@ pub struct BlockHeader {
     /// Whether this block is uncompressed
     pub uncompressed: bool,
 
-    /// Whether this block uses checksums.
+    /// Whether this block uses checksums. They are read but not verified,
+    /// see [Extractor::quantum_checksum].
     pub use_checksums: bool,
 }
 
+impl Persist for DecoderType {
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self as u8).save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Res<()> {
+        let [value] = take(input)?;
+        *self = match value {
+            0x5 => DecoderType::Lzna,
+            0x6 => DecoderType::Kraken,
+            0xA => DecoderType::Mermaid,
+            0xB => DecoderType::Bitknit,
+            0xC => DecoderType::Leviathan,
+            _ => None.message(|_| format!("Unknown decoder type {:X}", value))?,
+        };
+        Ok(())
+    }
+}
+
+persist_fields!(BlockHeader {
+    decoder_type,
+    restart_decoder,
+    uncompressed,
+    use_checksums,
+});
+
+/// Start of a state saved by [Extractor::save_state], followed by a format version.
+const STATE_MAGIC: &[u8; 4] = b"OOZS";
+const STATE_VERSION: u8 = 2;
+
 const SMALL_BLOCK: usize = 0x4000;
 const LARGE_BLOCK: usize = 0x40000;
+/// Bitknit, LZNA and Leviathan pick contexts by the low bits of the output position, so
+/// history is dropped in multiples of this to keep those bits.
+const HISTORY_ALIGN: usize = 16;
 
-impl BlockHeader {
+/// How many bytes to drop from the front of |len| bytes of history to keep at least |keep|.
+fn history_to_drop(len: usize, keep: usize) -> usize {
+    len.saturating_sub(keep) & !(HISTORY_ALIGN - 1)
+}
+
+impl DecoderType {
+    /// Decompressed size of a quantum.
     fn block_size(&self) -> usize {
-        match self.decoder_type {
+        match self {
             DecoderType::Lzna => SMALL_BLOCK,
             DecoderType::Bitknit => SMALL_BLOCK,
             _ => LARGE_BLOCK,
         }
     }
+
+    /// How far back matches can reach, where the format limits it. Bitknit distances are
+    /// coded in at most 26 bits; the other codecs leave the limit to the compressor.
+    fn max_distance(&self) -> usize {
+        match self {
+            DecoderType::Bitknit => 1 << 26,
+            _ => usize::MAX,
+        }
+    }
+}
+
+impl BlockHeader {
+    fn block_size(&self) -> usize {
+        self.decoder_type.block_size()
+    }
 }
 
 /// Additional header in front of each large or small block ("quantum").
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum QuantumHeader {
     Compressed {
         /// The compressed size of this quantum. If this value is 0 it means
@@ -55,8 +128,10 @@ pub enum QuantumHeader {
         compressed_size: usize,
         // If checksums are enabled, holds the checksum.
         checksum: u32,
-        // Two flags
+        /// Set on the first quantum of LZNA and Bitknit streams, which starts without history.
+        /// Known streams don't set it on large quanta.
         flag1: bool,
+        /// Not set in any known stream.
         flag2: bool,
     },
     WholeMatch {
@@ -69,12 +144,81 @@ pub enum QuantumHeader {
     Uncompressed,
 }
 
+impl QuantumHeader {
+    /// Whether the flags are a combination seen in known streams. What the flags mean isn't
+    /// known, so other combinations are rejected rather than decoded some way.
+    fn has_known_flags(&self, block_size: usize) -> bool {
+        match *self {
+            QuantumHeader::Compressed { flag1, flag2, .. } => matches!(
+                (block_size, flag1, flag2),
+                (_, false, false) | (SMALL_BLOCK, true, false)
+            ),
+            _ => true,
+        }
+    }
+}
+
+/// A quantum read from the input, which can be decoded once the output before it is written.
+/// The bytes are copied out of the input, or borrowed from it, see [MappedStream].
+enum Quantum<I = Vec<u8>> {
+    /// Stored bytes
+    Raw(I),
+    Memset(u8),
+    WholeMatch(usize),
+    Compressed {
+        decoder_type: DecoderType,
+        restart_decoder: bool,
+        input: I,
+    },
+}
+
 pub struct Extractor<In: Read> {
     input: In,
     pos: usize,
+    /// Decompressed bytes produced, which locates the next block header.
+    written: usize,
+    /// Decompressed bytes produced before the state this extractor resumed from.
+    resumed_at: usize,
+    /// Output bytes no longer kept as history, see [Extractor::read_to_writer].
+    dropped: usize,
     header: BlockHeader,
+    /// The last quantum header, for error messages.
+    quantum: Option<QuantumHeader>,
     bitknit_state: Option<BitknitState>,
     lzna_state: Option<LznaState>,
+    pipelined: bool,
+    /// The dictionary and the output of the reads after it, which later matches can
+    /// reference, see [Extractor::set_dictionary]. Empty when reads decode straight into
+    /// their buffer.
+    history: Vec<u8>,
+}
+
+/// Decompresses |src| into |dst|, which should be the size of the decompressed data.
+/// Returns the number of bytes written.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> std::io::Result<usize> {
+    Extractor::new(src).read(dst)
+}
+
+/// Decompresses |src| into a new vector of |len| bytes, see [Extractor::read_uninit].
+/// Compressed quanta are still zero filled before they are decoded, so this only saves the
+/// fill of raw, memset and whole-match quanta.
+pub fn decompress_to_vec(src: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+    let mut dst = Vec::with_capacity(len);
+    let written = Extractor::new(src).read_uninit(dst.spare_capacity_mut())?;
+    // SAFETY: read_uninit initialized the first |written| bytes
+    unsafe { dst.set_len(written) };
+    Ok(dst)
+}
+
+/// Like [decompress], for data compressed against |dictionary|.
+pub fn decompress_with_dictionary(
+    src: &[u8],
+    dictionary: &[u8],
+    dst: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut extractor = Extractor::new(src);
+    extractor.set_dictionary(dictionary);
+    extractor.read(dst)
 }
 
 impl<In: Read> Extractor<In> {
@@ -82,25 +226,100 @@ impl<In: Read> Extractor<In> {
     /// You could also try reading blocks of 0x40000 bytes at a time,
     /// but decompressors for some formats may fail if the output would be smaller
     /// than the input buffer, as decompressed size doesn't appear to be encoded
-    /// in the compression format.
+    /// in the compression format. [probe_size] works it out from the stream structure.
     pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        log::debug!("reading to buf with size {}", buf.len());
-        let mut bytes_written = 0;
-        while bytes_written < buf.len() {
-            if (bytes_written & 0x3FFFF) == 0 {
-                self.parse_header()?
-            }
-            log::debug!("Parsed header {:?}", self.header);
-            match self.extract(buf, bytes_written)? {
-                0 => break,
-                count => {
-                    bytes_written += count;
+        Ok(self.read_output(Output::new(buf))?)
+    }
+
+    /// Like [Extractor::read], for a |buf| that isn't initialized.
+    /// When this returns `Ok(n)`, the first n bytes of |buf| are initialized.
+    ///
+    /// Only raw, memset and whole-match quanta are written without a zero fill. The decoders
+    /// of compressed quanta work on initialized bytes, so each of those is still zero filled
+    /// right before it is decoded. For a stream of compressed quanta every byte is zeroed
+    /// once all the same, only not in a separate pass up front.
+    pub fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> std::io::Result<usize> {
+        Ok(self.read_output(Output::uninit(buf))?)
+    }
+
+    /// Like [Extractor::read], but decodes into `buf[history..]`, with the bytes before it
+    /// as history that matches can reference, e.g. a dictionary or earlier output.
+    /// Returns the number of bytes written after the history.
+    pub fn read_with_history(&mut self, buf: &mut [u8], history: usize) -> std::io::Result<usize> {
+        Ok(self.fill(&mut Output::new(buf), history)?)
+    }
+
+    fn read_output(&mut self, mut buf: Output) -> Res<usize> {
+        if self.history.is_empty() {
+            self.fill(&mut buf, 0)
+        } else {
+            self.fill_after_history(buf)
+        }
+    }
+
+    fn fill(&mut self, buf: &mut Output, history: usize) -> Res<usize> {
+        log::debug!(
+            "reading to buf with size {} after {} bytes of history",
+            buf.len(),
+            history
+        );
+        self.assert_le(history, buf.len())?;
+        let bytes_written = if self.pipelined {
+            self.read_pipelined(buf, history)?
+        } else {
+            let mut bytes_written = 0;
+            while history + bytes_written < buf.len() {
+                if ((self.written + bytes_written) & 0x3FFFF) == 0 {
+                    self.parse_header()?
+                }
+                log::debug!("Parsed header {:?}", self.header);
+                match self.extract(buf, history + bytes_written)? {
+                    0 => break,
+                    count => {
+                        bytes_written += count;
+                    }
                 }
             }
-        }
+            bytes_written
+        };
+        self.written += bytes_written;
         log::debug!("Output filled. Wrote {} bytes", bytes_written);
         Ok(bytes_written)
     }
+
+    /// Decodes after the history, then copies the output to |buf|. The output stays in the
+    /// history for the matches of later reads.
+    fn fill_after_history(&mut self, mut buf: Output) -> Res<usize> {
+        let mut window = std::mem::take(&mut self.history);
+        let decoded = self.extend_history(&mut window, buf.len());
+        self.history = window;
+        let decoded = decoded?;
+        let bytes_written = decoded.len();
+        buf.write(0, self.history.get(decoded).err()?)?;
+        self.trim_history(DEFAULT_WINDOW);
+        Ok(bytes_written)
+    }
+
+    /// Decodes up to |len| bytes onto the end of |window|, returning where they are.
+    fn extend_history(&mut self, window: &mut Vec<u8>, len: usize) -> Res<Range<usize>> {
+        let history = window.len();
+        let mut out = Output::extend(window, len);
+        let bytes_written = self.fill(&mut out, history)?;
+        out.init_to(history + bytes_written)?;
+        // SAFETY: init_to initialized the output up to there
+        unsafe { window.set_len(history + bytes_written) };
+        Ok(history..history + bytes_written)
+    }
+
+    /// Keeps the last |window| bytes of history once it grows to twice that, so each byte
+    /// kept is moved about once. The positions stay aligned, whatever the dictionary length.
+    fn trim_history(&mut self, window: usize) {
+        if self.history.len() >= 2 * window {
+            let dropped = history_to_drop(self.history.len(), window);
+            self.history.drain(..dropped);
+            self.dropped += dropped;
+        }
+    }
 }
 
 impl<In: Read> Extractor<In> {
@@ -108,12 +327,130 @@ impl<In: Read> Extractor<In> {
         Extractor {
             input,
             pos: 0,
+            written: 0,
+            resumed_at: 0,
+            dropped: 0,
             header: Default::default(),
+            quantum: None,
             bitknit_state: None,
             lzna_state: None,
+            pipelined: false,
+            history: Vec::new(),
+        }
+    }
+
+    /// Pipelined mode entropy decodes Kraken, Mermaid and Selkie chunks on a second thread,
+    /// while the calling thread copies the matches of the chunks before them.
+    /// Other codecs decode on the calling thread as usual. The two threads only overlap on a
+    /// machine with more than one CPU; on a single one, benches/pipelined.rs measures it
+    /// a few percent slower than sequential reads.
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.pipelined = pipelined;
+    }
+
+    /// Places |dictionary| before the output of [Extractor::read], so that the matches of
+    /// data compressed against the same dictionary resolve into it. Reads then keep their
+    /// output after it, up to a window of [DEFAULT_WINDOW] bytes.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        self.history = dictionary.to_vec();
+    }
+
+    /// Number of input bytes read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The checksum stored with the last compressed quantum read, in blocks that use
+    /// checksums. It isn't verified, as the function that computes it is unknown; checksummed
+    /// streams decode like any other, relying on the checks of the decoders.
+    pub fn quantum_checksum(&self) -> Option<u32> {
+        match self.quantum {
+            Some(QuantumHeader::Compressed { checksum, .. }) if self.header.use_checksums => {
+                Some(checksum)
+            }
+            _ => None,
         }
     }
 
+    /// Snapshot of the decoding progress, which [Extractor::resume] continues from, e.g. after
+    /// a restart. |output| is everything the reads of this extractor returned so far, or since
+    /// it resumed; at least the last [DEFAULT_WINDOW] bytes of it, after the dictionary, are
+    /// saved as the history for later matches.
+    ///
+    /// Reads should end on a quantum boundary, which they do when the buffer sizes are
+    /// multiples of 0x40000 bytes, or of 0x4000 bytes for Bitknit and LZNA.
+    pub fn save_state(&self, output: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(self.state(output)?)
+    }
+
+    fn state(&self, output: &[u8]) -> Res<Vec<u8>> {
+        self.assert_eq(output.len(), self.written - self.resumed_at)?;
+        // reads after a dictionary already keep their output in the history
+        let (dropped, history) = if self.history.is_empty() {
+            (0, output)
+        } else {
+            (self.dropped, self.history.as_slice())
+        };
+        // matches can't reach further back than the format allows, and the start stays
+        // aligned like a dropped history
+        let window = DEFAULT_WINDOW.min(self.header.decoder_type.max_distance());
+        let (before, history) = history.split_at(history_to_drop(history.len(), window));
+
+        let mut state = STATE_MAGIC.to_vec();
+        STATE_VERSION.save(&mut state);
+        self.pos.save(&mut state);
+        self.written.save(&mut state);
+        (dropped + before.len()).save(&mut state);
+        self.header.save(&mut state);
+        self.bitknit_state.save(&mut state);
+        self.lzna_state.save(&mut state);
+        // the format of a saved Vec<u8>
+        history.len().save(&mut state);
+        state.extend_from_slice(history);
+        Ok(state)
+    }
+
+    /// Continues decoding from a |state| returned by [Extractor::save_state].
+    /// |input| must start at the [Extractor::position] of the saved extractor.
+    /// Reads then return the output after the last byte saved.
+    pub fn resume(input: In, state: &[u8]) -> std::io::Result<Self> {
+        let mut extractor = Extractor::new(input);
+        extractor.load_state(state)?;
+        Ok(extractor)
+    }
+
+    fn load_state(&mut self, mut state: &[u8]) -> Res<()> {
+        let input = &mut state;
+        if take::<4>(input)? != *STATE_MAGIC {
+            self.raise("Not a saved decoder state".into())?
+        }
+        let mut version = 0u8;
+        version.load(input)?;
+        self.assert_eq(version, STATE_VERSION)?;
+        self.pos.load(input)?;
+        self.written.load(input)?;
+        self.resumed_at = self.written;
+        self.dropped.load(input)?;
+        self.header.load(input)?;
+        self.bitknit_state.load(input)?;
+        self.lzna_state.load(input)?;
+        self.history.load(input)?;
+        self.assert_eq(input.len(), 0)?;
+        Ok(())
+    }
+
+    fn core<'a>(
+        &self,
+        input: &'a [u8],
+        output: &'a mut [u8],
+        offset: usize,
+        len: usize,
+    ) -> Core<'a> {
+        let mut core = Core::new(input, output, offset, len);
+        core.dropped = self.dropped;
+        core
+    }
+
     fn read_exact(&mut self, buf: &mut [u8]) -> Res<()> {
         self.input
             .read_exact(buf)
@@ -123,41 +460,95 @@ impl<In: Read> Extractor<In> {
         Ok(())
     }
 
-    fn extract(&mut self, output: &mut [u8], offset: usize) -> Res<usize> {
-        let tmp = &mut [0; LARGE_BLOCK];
+    fn extract(&mut self, output: &mut Output, offset: usize) -> Res<usize> {
         let dst_bytes_left = std::cmp::min(output.len() - offset, self.header.block_size());
+        let quantum = self.read_quantum(dst_bytes_left)?;
+        self.decode(quantum, output, offset, dst_bytes_left)
+    }
+
+    /// Reads the next quantum of |dst_bytes_left| decompressed bytes from the input.
+    fn read_quantum(&mut self, dst_bytes_left: usize) -> Res<Quantum> {
+        self.read_quantum_with(dst_bytes_left, |extractor, len| {
+            let mut out = vec![0; len];
+            extractor.read_exact(&mut out).at(extractor)?;
+            Ok(out)
+        })
+    }
 
+    /// Like [Extractor::read_quantum], with |read| getting the stored bytes of the quantum.
+    fn read_quantum_with<I>(
+        &mut self,
+        dst_bytes_left: usize,
+        mut read: impl FnMut(&mut Self, usize) -> Res<I>,
+    ) -> Res<Quantum<I>> {
         if self.header.uncompressed {
-            let out = self.slice_mut(output, offset, Idx(dst_bytes_left))?;
-            self.read_exact(out).at(self)?;
-            return Ok(out.len());
+            return Ok(Quantum::Raw(read(self, dst_bytes_left)?));
         }
 
         let quantum = self.parse_quantum_header()?;
         log::debug!("Parsed quantum {:?}", quantum);
+        self.quantum = Some(quantum);
         match quantum {
             QuantumHeader::Compressed {
-                compressed_size, ..
+                compressed_size,
+                flag1,
+                flag2,
+                ..
             } => {
-                let input = self.slice_mut(tmp, 0, Idx(compressed_size))?;
-                self.read_exact(input).at(self)?;
+                // reject layouts that weren't seen in known streams, rather than misread them
+                if !quantum.has_known_flags(self.header.block_size()) {
+                    self.raise(format!(
+                        "Unsupported quantum flags {} {} for {:?}",
+                        flag1, flag2, self.header.decoder_type
+                    ))?
+                }
+                let input = read(self, compressed_size)?;
                 if self.header.use_checksums {
                     // If you can find a file with checksums enabled maybe you can figure out which algorithm to use here
                 }
-                let bytes_read = match self.header.decoder_type {
-                    DecoderType::Kraken => {
-                        Core::new(input, output, offset, dst_bytes_left).decode_quantum(Kraken)
-                    }
-                    DecoderType::Mermaid => {
-                        Core::new(input, output, offset, dst_bytes_left).decode_quantum(Mermaid)
-                    }
-                    DecoderType::Leviathan => {
-                        Core::new(input, output, offset, dst_bytes_left).decode_quantum(Leviathan)
-                    }
+                Ok(Quantum::Compressed {
+                    decoder_type: self.header.decoder_type,
+                    restart_decoder: std::mem::take(&mut self.header.restart_decoder),
+                    input,
+                })
+            }
+            QuantumHeader::WholeMatch {
+                whole_match_distance,
+            } => Ok(Quantum::WholeMatch(whole_match_distance)),
+            QuantumHeader::Memset { value } => Ok(Quantum::Memset(value)),
+            QuantumHeader::Uncompressed => Ok(Quantum::Raw(read(self, dst_bytes_left)?)),
+        }
+    }
+
+    /// Decodes |quantum| to |offset| in |output|, after everything before it was written.
+    fn decode(
+        &mut self,
+        quantum: Quantum<impl AsRef<[u8]>>,
+        output: &mut Output,
+        offset: usize,
+        dst_bytes_left: usize,
+    ) -> Res<usize> {
+        match quantum {
+            Quantum::Compressed {
+                decoder_type,
+                restart_decoder,
+                input,
+            } => {
+                let input = input.as_ref();
+                let output = output.init_to(offset + dst_bytes_left)?;
+                let bytes_read = match decoder_type {
+                    DecoderType::Kraken => self
+                        .core(input, output, offset, dst_bytes_left)
+                        .decode_quantum(Kraken),
+                    DecoderType::Mermaid => self
+                        .core(input, output, offset, dst_bytes_left)
+                        .decode_quantum(Mermaid),
+                    DecoderType::Leviathan => self
+                        .core(input, output, offset, dst_bytes_left)
+                        .decode_quantum(Leviathan),
                     DecoderType::Bitknit => {
-                        if self.header.restart_decoder {
+                        if restart_decoder {
                             self.bitknit_state = Some(BitknitState::new());
-                            self.header.restart_decoder = false;
                         }
                         let out = self.slice_mut(output, 0, Idx(offset + dst_bytes_left))?;
                         let state = self
@@ -168,9 +559,8 @@ impl<In: Read> Extractor<In> {
                         bitknit.decode()
                     }
                     DecoderType::Lzna => {
-                        if self.header.restart_decoder {
+                        if restart_decoder {
                             self.lzna_state = Some(LznaState::new());
-                            self.header.restart_decoder = false;
                         }
                         let out = self.slice_mut(output, 0, Idx(offset + dst_bytes_left))?;
                         let state = self.lzna_state.as_mut().msg_of(&"Lzna uninitialized")?;
@@ -178,41 +568,28 @@ impl<In: Read> Extractor<In> {
                     }
                 }
                 .at(self)?;
-                self.assert_eq(bytes_read, compressed_size)?;
-                log::debug!(
-                    "Extracted {} bytes from {}",
-                    dst_bytes_left,
-                    compressed_size
-                );
+                self.assert_eq(bytes_read, input.len())?;
+                log::debug!("Extracted {} bytes from {}", dst_bytes_left, input.len());
                 Ok(dst_bytes_left)
             }
-            QuantumHeader::WholeMatch {
-                whole_match_distance,
-            } => {
-                // no test coverage
+            Quantum::WholeMatch(whole_match_distance) => {
                 if whole_match_distance > offset {
                     self.raise(format!(
                         "Distance {} invalid - only {} bytes buffered",
                         whole_match_distance, offset
                     ))?
                 }
-                let from = offset - whole_match_distance;
-                let to = from + dst_bytes_left;
-                output.copy_within(from..to, offset);
+                output.repeat(offset, whole_match_distance, dst_bytes_left)?;
                 Ok(dst_bytes_left)
             }
-            QuantumHeader::Memset { value } => {
-                // no test coverage
-                self.slice_mut(output, offset, Len(dst_bytes_left))?
-                    .fill(value);
+            Quantum::Memset(value) => {
+                output.fill(offset, dst_bytes_left, value)?;
                 log::debug!("Set block to {}", value);
                 Ok(dst_bytes_left)
             }
-            QuantumHeader::Uncompressed => {
-                // no test coverage
-                let out = self.slice_mut(output, offset, Len(dst_bytes_left))?;
-                self.read_exact(out).at(self)?;
-                Ok(dst_bytes_left)
+            Quantum::Raw(bytes) => {
+                output.write(offset, bytes.as_ref())?;
+                Ok(bytes.as_ref().len())
             }
         }
     }
@@ -247,12 +624,15 @@ impl<In: Read> Extractor<In> {
                         0
                     },
                 })
-            } else if (v >> 18) == 1 {
-                Ok(QuantumHeader::Memset {
-                    value: self.read_bytes::<1>(1)?[0],
-                })
             } else {
-                self.raise(format!("Invalid header data {}", v))?
+                // Only the memset is seen in known streams. The whole match and stored quanta
+                // of small blocks may have 256k counterparts, but they aren't confirmed.
+                match v >> 18 {
+                    1 => Ok(QuantumHeader::Memset {
+                        value: self.read_bytes::<1>(1)?[0],
+                    }),
+                    _ => self.raise(format!("Invalid header data {}", v))?,
+                }
             }
         } else {
             let v = u16::from_be_bytes(self.read_bytes(2)?);
@@ -325,8 +705,254 @@ impl<In: Read> Extractor<In> {
 impl<In: Read> ErrorContext for Extractor<In> {
     fn describe(&self) -> Option<String> {
         Some(format!(
-            "header: {:?}, input bytes read: {}",
-            self.header, self.pos
+            "header: {:?}, quantum: {:?}, input bytes read: {}",
+            self.header, self.quantum, self.pos
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hand built stream of special quanta, with the output it decodes to.
+    /// 256k quanta only have the memset, see [Extractor::parse_quantum_header].
+    struct Fixture {
+        decoder_type: DecoderType,
+        data: Vec<u8>,
+        expected: Vec<u8>,
+    }
+
+    impl Fixture {
+        fn new(decoder_type: DecoderType) -> Self {
+            Fixture {
+                decoder_type,
+                data: Vec::new(),
+                expected: Vec::new(),
+            }
+        }
+
+        fn block_size(&self) -> usize {
+            BlockHeader {
+                decoder_type: self.decoder_type,
+                ..Default::default()
+            }
+            .block_size()
+        }
+
+        fn block_header(&mut self, uncompressed: bool) {
+            if self.expected.len() % LARGE_BLOCK == 0 {
+                self.data.push(0x8C | (u8::from(uncompressed) << 6));
+                self.data.push(self.decoder_type as u8);
+            }
+        }
+
+        fn quantum_header(&mut self, kind: u32) {
+            self.block_header(false);
+            if self.block_size() == LARGE_BLOCK {
+                let v = (kind << 18) | 0x3FFFF;
+                self.data.extend_from_slice(&v.to_be_bytes()[1..]);
+            } else {
+                let v = ((kind << 14) | 0x3FFF) as u16;
+                self.data.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+
+        fn whole_match_header(&mut self, distance: usize) {
+            self.quantum_header(0);
+            if distance <= 0x8000 {
+                let v = (0x8000 + distance - 1) as u16;
+                self.data.extend_from_slice(&v.to_be_bytes());
+            } else {
+                let rest = distance - 1 - 0x8000;
+                assert!(rest >> 15 < 0x80);
+                self.data
+                    .extend_from_slice(&((rest & 0x7FFF) as u16).to_be_bytes());
+                self.data.push(0x80 | (rest >> 15) as u8);
+            }
+        }
+
+        fn whole_match(&mut self, distance: usize) -> &mut Self {
+            self.whole_match_header(distance);
+            for _ in 0..self.block_size() {
+                self.expected
+                    .push(self.expected[self.expected.len() - distance]);
+            }
+            self
+        }
+
+        fn memset(&mut self, value: u8) -> &mut Self {
+            self.quantum_header(1);
+            self.data.push(value);
+            self.expected
+                .resize(self.expected.len() + self.block_size(), value);
+            self
+        }
+
+        fn raw(&mut self, seed: u8) -> &mut Self {
+            self.quantum_header(2);
+            let bytes: Vec<u8> = (0..self.block_size())
+                .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+                .collect();
+            self.data.extend_from_slice(&bytes);
+            self.expected.extend_from_slice(&bytes);
+            self
+        }
+
+        /// A whole 256k block stored without quantum headers.
+        fn uncompressed_block(&mut self, seed: u8) -> &mut Self {
+            self.block_header(true);
+            let bytes: Vec<u8> = (0..LARGE_BLOCK)
+                .map(|i| (i as u8).wrapping_mul(7) ^ seed)
+                .collect();
+            self.data.extend_from_slice(&bytes);
+            self.expected.extend_from_slice(&bytes);
+            self
+        }
+
+        fn check(&self) {
+            let len = self.expected.len();
+            let mut actual = vec![0; len];
+            assert_eq!(decompress(&self.data, &mut actual).unwrap(), len);
+            assert!(actual == self.expected, "{:?}", self.decoder_type);
+            let actual = decompress_to_vec(&self.data, len).unwrap();
+            assert!(actual == self.expected, "{:?}", self.decoder_type);
+        }
+    }
+
+    const DECODER_TYPES: [DecoderType; 5] = [
+        DecoderType::Kraken,
+        DecoderType::Mermaid,
+        DecoderType::Leviathan,
+        DecoderType::Lzna,
+        DecoderType::Bitknit,
+    ];
+
+    #[test_log::test]
+    fn special_quanta() {
+        for decoder_type in DECODER_TYPES {
+            let mut fixture = Fixture::new(decoder_type);
+            let block = fixture.block_size();
+            let small = block == SMALL_BLOCK;
+            fixture.memset(0xAB);
+            if small {
+                fixture
+                    .raw(1)
+                    .whole_match(block)
+                    .whole_match(3)
+                    .whole_match(3 * block)
+                    .raw(2);
+            }
+            fixture.memset(0);
+            // pad to a whole block, so the next one starts with a header
+            while fixture.expected.len() % LARGE_BLOCK != 0 {
+                fixture.memset(fixture.expected.len() as u8);
+            }
+            fixture.uncompressed_block(3);
+            if small {
+                fixture.whole_match(LARGE_BLOCK + 5);
+            } else {
+                fixture.memset(5);
+            }
+            fixture.check();
+            let len = fixture.expected.len();
+            assert_eq!(probe_size(&fixture.data).unwrap(), SizeProbe::AtMost(len));
+            if small {
+                fixture.raw(4);
+            } else {
+                fixture.uncompressed_block(4);
+            }
+            assert_eq!(
+                probe_size(&fixture.data).unwrap(),
+                SizeProbe::Exact(len + fixture.block_size())
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn special_quanta_errors() {
+        for decoder_type in DECODER_TYPES {
+            // whole match reaching one byte before the start of the output
+            let mut fixture = Fixture::new(decoder_type);
+            let block = fixture.block_size();
+            fixture.memset(1).whole_match_header(block + 1);
+            let mut out = vec![0; 2 * block];
+            assert!(decompress(&fixture.data, &mut out).is_err());
+
+            // the kinds 256k quanta don't have
+            let kinds: &[u32] = if block == LARGE_BLOCK {
+                &[0, 2, 3]
+            } else {
+                &[3]
+            };
+            for &kind in kinds {
+                let mut fixture = Fixture::new(decoder_type);
+                fixture.quantum_header(kind);
+                fixture.data.resize(fixture.data.len() + block + 3, 0);
+                let mut out = vec![0; block];
+                assert!(decompress(&fixture.data, &mut out).is_err(), "{}", kind);
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn trims_odd_dictionary() {
+        let dictionary = crate::tests::sample(2 * SMALL_BLOCK + 5, 0);
+        let mut extractor = Extractor::new([].as_slice());
+        extractor.set_dictionary(&dictionary);
+        extractor.trim_history(SMALL_BLOCK);
+        // the 5 odd bytes stay, so the history keeps its positions modulo 16
+        assert_eq!(extractor.dropped, SMALL_BLOCK);
+        assert!(extractor.history == dictionary[SMALL_BLOCK..]);
+    }
+
+    #[test_log::test]
+    fn saves_aligned_history() {
+        let dictionary = crate::tests::sample(DEFAULT_WINDOW + 21, 0);
+        let mut extractor = Extractor::new([].as_slice());
+        extractor.set_dictionary(&dictionary);
+        let state = extractor.save_state(&[]).unwrap();
+        let resumed = Extractor::resume([].as_slice(), &state).unwrap();
+        // the 5 odd bytes are saved along with the window
+        assert_eq!(resumed.dropped, 16);
+        assert!(resumed.history == dictionary[16..]);
+    }
+
+    #[test_log::test]
+    fn checksums_unverified() {
+        let data = crate::tests::sample(1000, 0);
+        let mut stream = crate::tests::kraken_stream(&data);
+        stream[1] |= 0x80;
+        // after the quantum header
+        stream.splice(5..5, [0x12, 0x34, 0x56]);
+        let mut extractor = Extractor::new(stream.as_slice());
+        assert_eq!(extractor.quantum_checksum(), None);
+        let mut out = vec![0; data.len()];
+        assert_eq!(extractor.read(&mut out).unwrap(), data.len());
+        assert!(out == data);
+        assert_eq!(extractor.quantum_checksum(), Some(0x123456));
+    }
+
+    #[test_log::test]
+    fn quantum_flags() {
+        for decoder_type in DECODER_TYPES {
+            let mut fixture = Fixture::new(decoder_type);
+            fixture.block_header(false);
+            let large = fixture.block_size() == LARGE_BLOCK;
+            // flag2, or flag1 on a large quantum, with a 16 byte payload
+            if large {
+                fixture.data.extend_from_slice(&[0x04, 0x00, 0x0F]);
+            } else {
+                fixture.data.extend_from_slice(&[0x80, 0x0F]);
+            }
+            fixture.data.extend_from_slice(&[0; 16]);
+            let err = decompress(&fixture.data, &mut vec![0; fixture.block_size()]).unwrap_err();
+            assert!(
+                err.to_string().contains("Unsupported quantum flags"),
+                "{}",
+                err
+            );
+            assert!(err.to_string().contains("flag2: "), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::core::error::{ErrorBuilder, ResultBuilder};
+use std::mem::MaybeUninit;
+
+/// Output buffer that may start out uninitialized. Quanta are written in order, and
+/// everything before |init| was written, so no uninitialized byte is ever read or returned.
+pub(super) struct Output<'a> {
+    buf: &'a mut [MaybeUninit<u8>],
+    init: usize,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        let init = buf.len();
+        // SAFETY: same layout, and only initialized bytes are ever written through it
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        Output { buf, init }
+    }
+
+    pub fn uninit(buf: &'a mut [MaybeUninit<u8>]) -> Self {
+        Output { buf, init: 0 }
+    }
+
+    /// The output after the bytes of |vec|, in |additional| bytes of its spare capacity.
+    /// The caller sets the length of |vec| once the output is initialized.
+    pub fn extend(vec: &'a mut Vec<u8>, additional: usize) -> Self {
+        vec.reserve(additional);
+        let init = vec.len();
+        // SAFETY: within the capacity, and only the first |init| bytes are read before written
+        let buf =
+            unsafe { std::slice::from_raw_parts_mut(vec.as_mut_ptr().cast(), init + additional) };
+        Output { buf, init }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The output up to |end|, for decoders that read back what they wrote.
+    /// Zero fills the bytes not written yet, as the decoders of compressed quanta only work on
+    /// initialized bytes; the fill is in cache right before they decode over it.
+    pub fn init_to(&mut self, end: usize) -> Result<&mut [u8], ErrorBuilder> {
+        let len = self.buf.len();
+        let buf = self
+            .buf
+            .get_mut(..end)
+            .message(|_| format!("{} past the end of the output {}", end, len))?;
+        if let Some(rest) = buf.get_mut(self.init..) {
+            rest.fill(MaybeUninit::new(0));
+        }
+        self.init = self.init.max(end);
+        // SAFETY: everything before |init| is initialized
+        Ok(unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) })
+    }
+
+    /// The bytes at |offset|, which must not leave a gap after the written output.
+    fn get_mut(
+        &mut self,
+        offset: usize,
+        len: usize,
+    ) -> Result<&mut [MaybeUninit<u8>], ErrorBuilder> {
+        if offset > self.init {
+            None.message(|_| format!("Gap between {} and {}", self.init, offset))?
+        }
+        let out_len = self.buf.len();
+        let out = self.buf.get_mut(offset..offset + len).message(|_| {
+            format!(
+                "{}..{} past the end of the output {}",
+                offset,
+                offset + len,
+                out_len
+            )
+        })?;
+        self.init = self.init.max(offset + len);
+        Ok(out)
+    }
+
+    pub fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), ErrorBuilder> {
+        for (out, &b) in self.get_mut(offset, bytes.len())?.iter_mut().zip(bytes) {
+            out.write(b);
+        }
+        Ok(())
+    }
+
+    pub fn fill(&mut self, offset: usize, len: usize, value: u8) -> Result<(), ErrorBuilder> {
+        self.get_mut(offset, len)?.fill(MaybeUninit::new(value));
+        Ok(())
+    }
+
+    /// Copies |len| bytes from |distance| back, repeating the source when it's shorter.
+    pub fn repeat(
+        &mut self,
+        offset: usize,
+        distance: usize,
+        len: usize,
+    ) -> Result<(), ErrorBuilder> {
+        if distance == 0 || distance > offset {
+            None.message(|_| format!("Distance {} invalid at {}", distance, offset))?
+        }
+        // checks the bounds and the gap before anything is written
+        self.get_mut(offset, len)?;
+        let mut pos = offset;
+        while pos < offset + len {
+            let (src, dst) = self.buf.split_at_mut(pos);
+            let n = distance.min(offset + len - pos);
+            let src = src.get(pos - distance..pos - distance + n).err()?;
+            dst.get_mut(..n).err()?.copy_from_slice(src);
+            pos += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_log::test]
+    fn writes_before_reads() {
+        let mut buf = [MaybeUninit::uninit(); 20];
+        let mut output = Output::uninit(&mut buf);
+        assert!(output.write(0, b"ab").is_ok());
+        assert!(output.repeat(2, 2, 5).is_ok());
+        assert!(output.fill(7, 3, b'x').is_ok());
+        assert!(output.write(10, b"yz").is_ok());
+        assert!(output.fill(13, 1, 0).is_err());
+        assert!(output.repeat(12, 13, 1).is_err());
+        assert_eq!(output.init_to(12).ok().unwrap(), b"abababaxxxyz");
+        assert_eq!(output.init_to(14).ok().unwrap(), b"abababaxxxyz\0\0");
+        assert!(output.init_to(21).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::algorithm::{Kraken, KrakenLzTable, Mermaid, MermaidLzTable};
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::{Core, PhasedChunk};
+use crate::extractor::{DecoderType, Extractor, Output, Quantum};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+
+/// Quanta read ahead of the one being copied to the output.
+const READ_AHEAD: usize = 2;
+
+/// A quantum for the entropy decoding thread.
+struct Job {
+    decoder_type: DecoderType,
+    input: Arc<Vec<u8>>,
+    offset: usize,
+    len: usize,
+    dropped: usize,
+}
+
+enum Phased {
+    Kraken(PhasedChunk<KrakenLzTable>),
+    Mermaid(PhasedChunk<MermaidLzTable>),
+}
+
+enum Message {
+    Chunk(Box<Phased>),
+    /// All chunks of the quantum were sent, with the number of input bytes used.
+    Done(usize),
+}
+
+/// A quantum waiting to be written to the output.
+enum Pending {
+    /// Tables come from the entropy decoding thread
+    Phased {
+        input: Arc<Vec<u8>>,
+        offset: usize,
+        len: usize,
+    },
+    /// Decoded on the calling thread
+    Direct {
+        quantum: Quantum,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl<In: Read> Extractor<In> {
+    /// Like [Extractor::read_with_history], but with Kraken and Mermaid chunks entropy decoded on a
+    /// second thread while the calling thread copies the matches of the chunks before them.
+    pub(super) fn read_pipelined(&mut self, buf: &mut Output, history: usize) -> Res<usize> {
+        std::thread::scope(|scope| {
+            let (jobs, job_receiver) = sync_channel(READ_AHEAD);
+            let (chunk_sender, chunks) = sync_channel(READ_AHEAD);
+            scope.spawn(move || read_tables(job_receiver, chunk_sender));
+
+            let mut pending = VecDeque::new();
+            let mut bytes_read = 0;
+            let mut bytes_written = 0;
+            loop {
+                if history + bytes_read < buf.len() && pending.len() < READ_AHEAD {
+                    if ((self.written + bytes_read) & 0x3FFFF) == 0 {
+                        self.parse_header()?
+                    }
+                    let offset = history + bytes_read;
+                    let len = std::cmp::min(buf.len() - offset, self.header.block_size());
+                    pending.push_back(match self.read_quantum(len)? {
+                        Quantum::Compressed {
+                            decoder_type:
+                                decoder_type @ (DecoderType::Kraken | DecoderType::Mermaid),
+                            input,
+                            ..
+                        } => {
+                            let input = Arc::new(input);
+                            jobs.send(Job {
+                                decoder_type,
+                                input: input.clone(),
+                                offset,
+                                len,
+                                dropped: self.dropped,
+                            })
+                            .ok()
+                            .msg_of(&"Entropy decoding thread stopped")?;
+                            Pending::Phased { input, offset, len }
+                        }
+                        quantum => Pending::Direct {
+                            quantum,
+                            offset,
+                            len,
+                        },
+                    });
+                    bytes_read += len;
+                } else if let Some(quantum) = pending.pop_front() {
+                    bytes_written += match quantum {
+                        Pending::Direct {
+                            quantum,
+                            offset,
+                            len,
+                        } => self.decode(quantum, buf, offset, len)?,
+                        Pending::Phased { input, offset, len } => {
+                            let output = buf.init_to(offset + len)?;
+                            let mut core = self.core(&input, output, offset, len);
+                            loop {
+                                match chunks
+                                    .recv()
+                                    .ok()
+                                    .msg_of(&"Entropy decoding thread stopped")??
+                                {
+                                    Message::Chunk(phased) => match *phased {
+                                        Phased::Kraken(chunk) => {
+                                            core.copy_chunk(&Kraken, chunk).at(self)?
+                                        }
+                                        Phased::Mermaid(chunk) => {
+                                            core.copy_chunk(&Mermaid, chunk).at(self)?
+                                        }
+                                    },
+                                    Message::Done(used) => {
+                                        self.assert_eq(used, input.len())?;
+                                        break;
+                                    }
+                                }
+                            }
+                            len
+                        }
+                    };
+                } else {
+                    break;
+                }
+            }
+            Ok(bytes_written)
+        })
+    }
+}
+
+/// Runs the first phase of each job, sending the chunks and then the number of input bytes
+/// used. Stops at the first error, or when the receiving side hangs up.
+fn read_tables(jobs: Receiver<Job>, chunks: SyncSender<Res<Message>>) {
+    for job in jobs {
+        let result = read_job(&job, &chunks).map(Message::Done);
+        let failed = result.is_err();
+        if chunks.send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn read_job(job: &Job, chunks: &SyncSender<Res<Message>>) -> Res<usize> {
+    let mut core = Core::new(&job.input, &mut [], job.offset, job.len);
+    core.dropped = job.dropped;
+    let send = |phased| {
+        chunks
+            .send(Ok(Message::Chunk(Box::new(phased))))
+            .ok()
+            .msg_of(&"Copying thread stopped")
+    };
+    Ok(match job.decoder_type {
+        DecoderType::Kraken => core.read_chunks(&Kraken, |chunk| Ok(send(Phased::Kraken(chunk))?)),
+        DecoderType::Mermaid => {
+            core.read_chunks(&Mermaid, |chunk| Ok(send(Phased::Mermaid(chunk))?))
+        }
+        _ => core.raise(format!("{:?} has a single phase", job.decoder_type))?,
+    }
+    .at(&core)?)
+}
@@ -0,0 +1,112 @@
+use crate::algorithm::{Kraken, Leviathan, Mermaid};
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::extractor::{DecoderType, Extractor, QuantumHeader};
+
+/// Decompressed size found by [probe_size].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SizeProbe {
+    Exact(usize),
+    /// The last quantum is a memset, a whole match, or Bitknit or LZNA, which would have
+    /// to be decoded to tell its size. It's at least one byte.
+    AtMost(usize),
+}
+
+/// Works out how many bytes |src| decompresses to, which the format doesn't store.
+/// Only the block and quantum headers are read, and the chunk tables of the last quantum,
+/// since every quantum before it is whole.
+pub fn probe_size(src: &[u8]) -> std::io::Result<SizeProbe> {
+    Ok(Extractor::new(src).probe()?)
+}
+
+impl<'a> Extractor<&'a [u8]> {
+    fn probe(&mut self) -> Res<SizeProbe> {
+        let mut len = 0;
+        while !self.input.is_empty() {
+            if len & 0x3FFFF == 0 {
+                self.parse_header()?;
+            }
+            let block_size = self.header.block_size();
+            if self.header.uncompressed {
+                len += self.skip(block_size)?.len();
+                continue;
+            }
+
+            let quantum = self.parse_quantum_header()?;
+            self.quantum = Some(quantum);
+            len += match quantum {
+                QuantumHeader::Compressed {
+                    compressed_size, ..
+                } => {
+                    if !quantum.has_known_flags(block_size) {
+                        self.raise("Unsupported quantum flags".into())?
+                    }
+                    let input = self.take(compressed_size)?;
+                    if !self.input.is_empty() {
+                        block_size
+                    } else {
+                        let mut output = [0; 8];
+                        let mut core = self.core(input, &mut output, 0, 0);
+                        match self.header.decoder_type {
+                            DecoderType::Kraken => core.quantum_len(&Kraken, len, block_size),
+                            DecoderType::Mermaid => core.quantum_len(&Mermaid, len, block_size),
+                            DecoderType::Leviathan => core.quantum_len(&Leviathan, len, block_size),
+                            DecoderType::Bitknit | DecoderType::Lzna => {
+                                return Ok(SizeProbe::AtMost(len + block_size))
+                            }
+                        }
+                        .at(self)?
+                    }
+                }
+                QuantumHeader::Uncompressed => self.skip(block_size)?.len(),
+                QuantumHeader::Memset { .. } | QuantumHeader::WholeMatch { .. } => {
+                    if self.input.is_empty() {
+                        return Ok(SizeProbe::AtMost(len + block_size));
+                    }
+                    block_size
+                }
+            };
+        }
+        log::debug!("Probed {} bytes of output", len);
+        Ok(SizeProbe::Exact(len))
+    }
+
+    /// Splits the next |len| bytes off the input.
+    pub(super) fn take(&mut self, len: usize) -> Res<&'a [u8]> {
+        let (bytes, rest) = self
+            .input
+            .split_at_checked(len)
+            .message(|_| format!("Failed to read {} bytes", len))?;
+        self.input = rest;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Splits up to |len| bytes off the input, which can end early in the last quantum.
+    fn skip(&mut self, len: usize) -> Res<&'a [u8]> {
+        self.take(len.min(self.input.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::xml_streams;
+
+    #[test_log::test]
+    fn probes_testdata() {
+        for (path, data, len) in xml_streams() {
+            let data = data.as_slice();
+            let detection = crate::detect(data)[0];
+            let probe = probe_size(data).unwrap();
+            match detection.decoder_type {
+                DecoderType::Bitknit | DecoderType::Lzna => {
+                    let SizeProbe::AtMost(max) = probe else {
+                        panic!("{:?} {:?}", path, probe)
+                    };
+                    assert!(len <= max && max - len < 0x4000, "{:?}", path);
+                }
+                _ => assert_eq!(probe, SizeProbe::Exact(len), "{:?}", path),
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::algorithm::{Bitknit, BitknitState, Kraken, Leviathan, Lzna, LznaState, Mermaid};
+use crate::core::error::{ErrorContext, Res, WithContext};
+use crate::core::Core;
+use crate::extractor::DecoderType;
+
+/// State that Bitknit and LZNA carry from one quantum to the next, which the block
+/// headers would otherwise reset. A new state starts a stream.
+#[derive(Default)]
+pub struct CodecState {
+    bitknit: Option<BitknitState>,
+    lzna: Option<LznaState>,
+}
+
+impl CodecState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts over, as for a block header with the restart flag.
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+/// Decodes |src|, the payload of a single quantum stored without the block header and the
+/// quantum header, into |dst| after the first |history| bytes, which hold the preceding
+/// output. The rest of |dst| is the decompressed size of the quantum, at most 256k, or 16k
+/// for Bitknit and LZNA. Returns the number of bytes read from |src|.
+///
+/// Bitknit and LZNA quanta are decoded as the start of a stream,
+/// see [decode_raw_with_state] to continue one.
+pub fn decode_raw(
+    codec: DecoderType,
+    src: &[u8],
+    dst: &mut [u8],
+    history: usize,
+) -> std::io::Result<usize> {
+    decode_raw_with_state(codec, src, dst, history, &mut CodecState::new())
+}
+
+/// Like [decode_raw], for a quantum of a Bitknit or LZNA stream that continues from |state|.
+pub fn decode_raw_with_state(
+    codec: DecoderType,
+    src: &[u8],
+    dst: &mut [u8],
+    history: usize,
+    state: &mut CodecState,
+) -> std::io::Result<usize> {
+    let raw = Raw {
+        codec,
+        src_len: src.len(),
+        dst_len: dst.len(),
+        history,
+    };
+    Ok(raw.decode(src, dst, state)?)
+}
+
+struct Raw {
+    codec: DecoderType,
+    src_len: usize,
+    dst_len: usize,
+    history: usize,
+}
+
+impl Raw {
+    fn decode(&self, src: &[u8], dst: &mut [u8], state: &mut CodecState) -> Res<usize> {
+        self.assert_le(self.history, self.dst_len)?;
+        let len = self.dst_len - self.history;
+        self.assert_le(len, self.codec.block_size())?;
+        let offset = self.history;
+        Ok(match self.codec {
+            DecoderType::Kraken => Core::new(src, dst, offset, len).decode_quantum(Kraken),
+            DecoderType::Mermaid => Core::new(src, dst, offset, len).decode_quantum(Mermaid),
+            DecoderType::Leviathan => Core::new(src, dst, offset, len).decode_quantum(Leviathan),
+            DecoderType::Bitknit => {
+                let state = state.bitknit.get_or_insert_with(BitknitState::new);
+                Bitknit::new(src, dst, state, offset).decode()
+            }
+            DecoderType::Lzna => {
+                let state = state.lzna.get_or_insert_with(LznaState::new);
+                Lzna::new(src, dst, offset).decode_quantum(state)
+            }
+        }
+        .at(self)?)
+    }
+}
+
+impl ErrorContext for Raw {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "raw {:?} quantum of {} bytes to {} after {}",
+            self.codec,
+            self.src_len,
+            self.dst_len - self.history.min(self.dst_len),
+            self.history
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractor::{Extractor, Quantum};
+    use crate::tests::xml_streams;
+
+    /// Decodes the quanta of the xml streams one at a time, with the headers parsed separately.
+    #[test_log::test]
+    fn decodes_headerless_quanta() {
+        for (path, data, len) in xml_streams() {
+            let expected = crate::decompress_to_vec(data.as_slice(), len).unwrap();
+
+            let mut extractor = Extractor::new(data.as_slice());
+            let mut out = vec![0; len];
+            let mut state = CodecState::new();
+            let mut offset = 0;
+            let mut first = true;
+            while offset < len {
+                if offset & 0x3FFFF == 0 {
+                    extractor.parse_header().unwrap();
+                }
+                let size = (len - offset).min(extractor.header.block_size());
+                let dst = &mut out[..offset + size];
+                match extractor.read_quantum(size).unwrap() {
+                    Quantum::Compressed {
+                        decoder_type,
+                        restart_decoder,
+                        input,
+                    } => {
+                        if restart_decoder {
+                            state.reset();
+                        }
+                        if first {
+                            let mut fresh = dst.to_vec();
+                            let read = decode_raw(decoder_type, &input, &mut fresh, offset);
+                            assert_eq!(read.unwrap(), input.len(), "{:?}", path);
+                            assert_eq!(fresh[offset..], expected[offset..offset + size]);
+                            first = false;
+                        }
+                        let read =
+                            decode_raw_with_state(decoder_type, &input, dst, offset, &mut state);
+                        assert_eq!(read.unwrap(), input.len(), "{:?} at {}", path, offset);
+                    }
+                    // not in raw payloads
+                    _ => dst[offset..].copy_from_slice(&expected[offset..offset + size]),
+                }
+                offset += size;
+            }
+            assert!(out == expected, "{:?}", path);
+        }
+    }
+
+    #[test_log::test]
+    fn rejects_sizes() {
+        let mut dst = vec![0; 0x40001];
+        assert!(decode_raw(DecoderType::Kraken, &[0; 8], &mut dst, 0).is_err());
+        assert!(decode_raw(DecoderType::Kraken, &[0; 8], &mut dst[..10], 11).is_err());
+        assert!(decode_raw(DecoderType::Bitknit, &[0; 8], &mut dst[..0x4001], 0).is_err());
+        assert!(decode_raw(DecoderType::Lzna, &[0; 8], &mut dst[..0x4010], 8).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::extractor::{history_to_drop, Extractor, Output, LARGE_BLOCK};
+use std::io::{Read, Write};
+
+/// History kept by [decompress_to_writer]. The format has no limit on match distances,
+/// but compressors usually limit them to a dictionary size well below this.
+pub const DEFAULT_WINDOW: usize = 0x4000000;
+
+/// Decompresses |len| bytes from |reader| to |writer|, keeping only the last
+/// [DEFAULT_WINDOW] bytes of output for matches to reference, or fewer for codecs
+/// that can't reach back that far.
+/// The length is needed because the format doesn't store it.
+/// Returns the number of bytes written.
+pub fn decompress_to_writer(
+    reader: impl Read,
+    writer: impl Write,
+    len: usize,
+) -> std::io::Result<usize> {
+    Extractor::new(reader).read_to_writer(writer, len, DEFAULT_WINDOW)
+}
+
+impl<In: Read> Extractor<In> {
+    /// Decodes |len| bytes to |writer|, keeping |window| bytes of output (and dictionary)
+    /// as history. Each quantum is written as soon as it is decoded. The history grows with
+    /// the output up to a quarter more than the window, then the last |window| bytes are
+    /// moved to the front, so memory use stays around 1.25 times the window.
+    /// Matches reaching further back than the window fail with an error, and so does a window
+    /// smaller than the quanta of the stream.
+    ///
+    /// Quanta are decoded on the calling thread, also in pipelined mode. Afterwards the
+    /// history is kept for the matches of later reads.
+    pub fn read_to_writer(
+        &mut self,
+        writer: impl Write,
+        len: usize,
+        window: usize,
+    ) -> std::io::Result<usize> {
+        Ok(self.fill_writer(writer, len, window)?)
+    }
+
+    fn fill_writer(&mut self, mut writer: impl Write, len: usize, window: usize) -> Res<usize> {
+        let requested = window;
+        let mut window = window.min(self.history.len() + len);
+        let mut ring = std::mem::take(&mut self.history);
+        self.drop_history(&mut ring, window);
+
+        let mut bytes_written = 0;
+        while bytes_written < len {
+            if (self.written & 0x3FFFF) == 0 {
+                self.parse_header()?;
+                // the decoders would see the start of the stream after each quantum
+                if requested < self.header.block_size() {
+                    self.raise(format!(
+                        "Window {} is smaller than a quantum of {:?}",
+                        requested, self.header.decoder_type
+                    ))?
+                }
+                window = window.min(self.header.decoder_type.max_distance());
+            }
+            let size = (len - bytes_written).min(self.header.block_size());
+            self.make_room(&mut ring, window, size);
+            let offset = ring.len();
+            let mut out = Output::extend(&mut ring, size);
+            let count = self.extract(&mut out, offset)?;
+            out.init_to(offset + count)?;
+            // SAFETY: init_to initialized the output up to there
+            unsafe { ring.set_len(offset + count) };
+            writer.write_all(ring.get(offset..).err()?).at(self)?;
+            self.written += count;
+            bytes_written += count;
+            if count == 0 {
+                break;
+            }
+        }
+        writer.flush().at(self)?;
+        log::debug!("Wrote {} bytes with a window of {}", bytes_written, window);
+        self.history = ring;
+        Ok(bytes_written)
+    }
+
+    /// Makes room for |size| more bytes after |ring|, which keeps at least |window| bytes.
+    pub(super) fn make_room(&mut self, ring: &mut Vec<u8>, window: usize, size: usize) {
+        let end = ring.len() + size;
+        if end <= ring.capacity() {
+            return;
+        }
+        let limit = window + (window / 4).max(LARGE_BLOCK);
+        if end > limit {
+            self.drop_history(ring, window);
+        }
+        // grows like a Vec up to the limit, so short streams don't allocate a whole window
+        let capacity = (2 * ring.capacity()).min(limit).max(ring.len() + size);
+        ring.reserve_exact(capacity - ring.len());
+    }
+
+    /// Keeps the last |window| bytes of |ring|, and up to 15 more to keep the positions aligned.
+    fn drop_history(&mut self, ring: &mut Vec<u8>, window: usize) {
+        let dropped = history_to_drop(ring.len(), window);
+        ring.drain(..dropped);
+        self.dropped += dropped;
+    }
+}
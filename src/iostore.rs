@@ -0,0 +1,619 @@
+//! Reader for Unreal Engine 5 IoStore containers, a `.utoc` table of contents with the
+//! data in a `.ucas` file.
+//!
+//! Chunks are laid out one after another in an uncompressed address space, which is cut
+//! in compression blocks of the same size. The table of contents maps each chunk to a
+//! range of that space, and each block to where it's stored in the `.ucas` file.
+
+use crate::archive::{Archive, ArchiveEntry, Compression};
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::reader::Reader;
+use std::io::{Read, Seek, SeekFrom};
+
+const MAGIC: &[u8; 16] = b"-==--==--==--==-";
+
+// Versions that changed the layout, named as in the engine source.
+const VERSION_DIRECTORY_INDEX: u8 = 2;
+const VERSION_PARTITION_SIZE: u8 = 3;
+const VERSION_PERFECT_HASH: u8 = 4;
+const VERSION_PERFECT_HASH_WITH_OVERFLOW: u8 = 5;
+const VERSION_LATEST: u8 = 8;
+
+const FLAG_ENCRYPTED: u8 = 2;
+const FLAG_SIGNED: u8 = 4;
+const FLAG_INDEXED: u8 = 8;
+
+/// Marks the end of the lists in the directory index.
+const NONE: u32 = u32::MAX;
+
+/// Identifies a chunk: 8 bytes of id, 2 of index, then the chunk type in the last byte.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkId(pub [u8; 12]);
+
+impl ChunkId {
+    pub fn chunk_type(&self) -> u8 {
+        self.0[11]
+    }
+}
+
+/// A chunk in an [IoStore].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TocEntry {
+    pub id: ChunkId,
+    /// Position in the uncompressed address space.
+    pub offset: u64,
+    /// Decompressed size.
+    pub len: u64,
+    /// Path below the mount point, for chunks listed in the directory index.
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct CompressionBlock {
+    /// Position in the `.ucas` file.
+    offset: u64,
+    compressed_size: u32,
+    uncompressed_size: u32,
+    /// Index in the method names, which start at 1. 0 means stored.
+    method: u8,
+}
+
+/// An open IoStore container.
+pub struct IoStore<R: Read + Seek> {
+    cas: R,
+    version: u8,
+    flags: u8,
+    block_size: u64,
+    methods: Vec<String>,
+    blocks: Vec<CompressionBlock>,
+    mount_point: String,
+    entries: Vec<TocEntry>,
+}
+
+impl<R: Read + Seek> IoStore<R> {
+    /// Reads the table of contents from |toc|, for the data in |cas|.
+    pub fn open(mut toc: impl Read, cas: R) -> std::io::Result<Self> {
+        let mut data = Vec::new();
+        toc.read_to_end(&mut data)?;
+        let mut store = IoStore {
+            cas,
+            version: 0,
+            flags: 0,
+            block_size: 0,
+            methods: Vec::new(),
+            blocks: Vec::new(),
+            mount_point: String::new(),
+            entries: Vec::new(),
+        };
+        store.read_toc(&data)?;
+        Ok(store)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Path the entry paths are relative to, empty if the container has no directory index.
+    pub fn mount_point(&self) -> &str {
+        &self.mount_point
+    }
+
+    pub fn entries(&self) -> &[TocEntry] {
+        &self.entries
+    }
+
+    pub fn find(&self, path: &str) -> Option<&TocEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.path.as_deref() == Some(path))
+    }
+
+    pub fn find_id(&self, id: ChunkId) -> Option<&TocEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Reads and decompresses the data of |entry|.
+    pub fn read(&mut self, entry: &TocEntry) -> std::io::Result<Vec<u8>> {
+        Ok(self.read_entry(entry)?)
+    }
+
+    fn read_toc(&mut self, data: &[u8]) -> Res<()> {
+        let mut toc = Reader::new(data);
+        if toc.bytes(MAGIC.len())? != MAGIC {
+            self.raise("Not an IoStore table of contents".into())?
+        }
+        self.version = toc.u8()?;
+        if !(1..=VERSION_LATEST).contains(&self.version) {
+            self.raise(format!("Unsupported IoStore version {}", self.version))?
+        }
+        toc.bytes(3)?;
+        let header_size = toc.u32()?;
+        let entry_count = toc.u32()?;
+        let block_count = toc.u32()?;
+        let block_entry_size = toc.u32()?;
+        self.assert_eq(block_entry_size, 12)?;
+        let method_count = toc.u32()?;
+        let method_name_len = toc.u32()?;
+        self.block_size = toc.u32()?.into();
+        self.assert_ne(self.block_size, 0)?;
+        let directory_index_size = toc.u32()?;
+        let partition_count = toc.u32()?;
+        // container id and encryption key guid
+        toc.bytes(8 + 16)?;
+        self.flags = toc.u8()?;
+        toc.bytes(3)?;
+        let seed_count = toc.u32()?;
+        toc.u64()?;
+        let without_perfect_hash_count = toc.u32()?;
+        if self.version >= VERSION_PARTITION_SIZE && partition_count > 1 {
+            self.raise(format!("{} partitions aren't supported", partition_count))?
+        }
+
+        let mut toc = Reader::new(data.get(header_size as usize..).err()?);
+        let mut ids = Vec::new();
+        for _ in 0..entry_count {
+            ids.push(ChunkId(toc.array()?));
+        }
+        for id in ids {
+            // 40 bit big endian offset and length
+            let mut offset_length = [0; 16];
+            offset_length[3..8].copy_from_slice(toc.bytes(5)?);
+            offset_length[11..].copy_from_slice(toc.bytes(5)?);
+            let (offset, len) = offset_length.split_at(8);
+            self.entries.push(TocEntry {
+                id,
+                offset: u64::from_be_bytes(offset.try_into().at(self)?),
+                len: u64::from_be_bytes(len.try_into().at(self)?),
+                path: None,
+            });
+        }
+        if self.version >= VERSION_PERFECT_HASH {
+            toc.bytes(seed_count as usize * 4)?;
+        }
+        if self.version >= VERSION_PERFECT_HASH_WITH_OVERFLOW {
+            toc.bytes(without_perfect_hash_count as usize * 4)?;
+        }
+        for _ in 0..block_count {
+            let mut offset = [0; 8];
+            offset[..5].copy_from_slice(toc.bytes(5)?);
+            let mut size = [0; 4];
+            size[..3].copy_from_slice(toc.bytes(3)?);
+            let mut uncompressed_size = [0; 4];
+            uncompressed_size[..3].copy_from_slice(toc.bytes(3)?);
+            self.blocks.push(CompressionBlock {
+                offset: u64::from_le_bytes(offset),
+                compressed_size: u32::from_le_bytes(size),
+                uncompressed_size: u32::from_le_bytes(uncompressed_size),
+                method: toc.u8()?,
+            });
+        }
+        for _ in 0..method_count {
+            let name = toc.bytes(method_name_len as usize)?;
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            self.methods
+                .push(String::from_utf8_lossy(name.get(..len).err()?).into_owned());
+        }
+        if self.flags & FLAG_SIGNED != 0 {
+            let hash_size = toc.u32()? as usize;
+            // signatures of the table of contents and of the block hashes, then the hashes
+            toc.bytes(2 * hash_size + 20 * block_count as usize)?;
+        }
+        log::debug!(
+            "IoStore version {} with {} chunks, {} blocks of {} and methods {:?}",
+            self.version,
+            self.entries.len(),
+            self.blocks.len(),
+            self.block_size,
+            self.methods
+        );
+
+        if self.version >= VERSION_DIRECTORY_INDEX
+            && self.flags & FLAG_INDEXED != 0
+            && directory_index_size > 0
+        {
+            if self.flags & FLAG_ENCRYPTED != 0 {
+                self.raise("The directory index is encrypted".into())?
+            }
+            let index = toc.bytes(directory_index_size as usize)?;
+            self.read_directory_index(&mut Reader::new(index))?;
+        }
+        Ok(())
+    }
+
+    /// Names the entries from the directory index, a tree of directories, each with a
+    /// list of files holding the index of their entry.
+    fn read_directory_index(&mut self, index: &mut Reader) -> Res<()> {
+        self.mount_point = index.string()?;
+        let mut directories = Vec::new();
+        for _ in 0..index.u32()? {
+            // name, first child, next sibling, first file
+            directories.push([index.u32()?, index.u32()?, index.u32()?, index.u32()?]);
+        }
+        let mut files = Vec::new();
+        for _ in 0..index.u32()? {
+            // name, next file, entry
+            files.push([index.u32()?, index.u32()?, index.u32()?]);
+        }
+        let mut strings = Vec::new();
+        for _ in 0..index.u32()? {
+            strings.push(index.string()?);
+        }
+        let name = |i: u32| strings.get(i as usize).msg_of(&i);
+
+        let mut pending = vec![(0, String::new())];
+        // bounds the walk if the lists loop
+        let mut steps = directories.len() + files.len();
+        while let Some((directory, path)) = pending.pop() {
+            if directories.is_empty() {
+                break;
+            }
+            let [_, first_child, _, first_file] = *directories.get(directory).msg_of(&directory)?;
+            let mut file = first_file;
+            while file != NONE {
+                let [file_name, next, entry] = *files.get(file as usize).msg_of(&file)?;
+                let entry = self.entries.get_mut(entry as usize).msg_of(&entry)?;
+                entry.path = Some(format!("{}{}", path, name(file_name)?));
+                file = next;
+                steps = steps
+                    .checked_sub(1)
+                    .msg_of(&"Loop in the directory index")?;
+            }
+            let mut child = first_child;
+            while child != NONE {
+                let [child_name, _, next, _] = *directories.get(child as usize).msg_of(&child)?;
+                pending.push((child as usize, format!("{}{}/", path, name(child_name)?)));
+                child = next;
+                steps = steps
+                    .checked_sub(1)
+                    .msg_of(&"Loop in the directory index")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_entry(&mut self, entry: &TocEntry) -> Res<Vec<u8>> {
+        let stored = self.read_stored_entry(entry)?;
+        self.decode_entry(entry, &stored)
+    }
+
+    /// The compression blocks holding |entry|.
+    fn entry_blocks(&self, entry: &TocEntry) -> Res<&[CompressionBlock]> {
+        if entry.len == 0 {
+            return Ok(&[]);
+        }
+        let first = usize::try_from(entry.offset / self.block_size).at(self)?;
+        let last = usize::try_from((entry.offset + entry.len - 1) / self.block_size).at(self)?;
+        Ok(self.blocks.get(first..=last).msg_of(&(first, last))?)
+    }
+
+    /// Reads the blocks of |entry|, one after the other.
+    fn read_stored_entry(&mut self, entry: &TocEntry) -> Res<Vec<u8>> {
+        if self.flags & FLAG_ENCRYPTED != 0 {
+            self.raise("The container is encrypted".into())?
+        }
+        let blocks = self.entry_blocks(entry)?.to_vec();
+        let mut stored = Vec::new();
+        for block in blocks {
+            stored.extend(self.read_at(block.offset, block.compressed_size)?);
+        }
+        Ok(stored)
+    }
+
+    fn decode_entry(&self, entry: &TocEntry, stored: &[u8]) -> Res<Vec<u8>> {
+        let len = usize::try_from(entry.len).at(self)?;
+        let blocks = self.entry_blocks(entry)?;
+        let mut stored = Reader::new(stored);
+        let mut out = Vec::with_capacity(blocks.len() * self.block_size as usize);
+        for block in blocks {
+            let data = stored.bytes(block.compressed_size as usize)?;
+            let size = block.uncompressed_size as usize;
+            if block.method == 0 {
+                self.assert_eq(data.len(), size)?;
+                out.extend_from_slice(data);
+                continue;
+            }
+            if let Compression::Other(name) = self.compression(block.method)? {
+                self.raise(format!("Unsupported compression {}", name))?
+            }
+            let start = out.len();
+            out.resize(start + size, 0);
+            let written = crate::decompress(data, out.get_mut(start..).err()?).at(self)?;
+            self.assert_eq(written, size)?;
+        }
+        let start = (entry.offset % self.block_size) as usize;
+        out.drain(..start.min(out.len()));
+        self.assert_le(len, out.len())?;
+        out.truncate(len);
+        Ok(out)
+    }
+
+    fn compression(&self, method: u8) -> Res<Compression> {
+        if method == 0 {
+            return Ok(Compression::None);
+        }
+        let name = self.methods.get(method as usize - 1).msg_of(&method)?;
+        Ok(if name.eq_ignore_ascii_case("oodle") {
+            Compression::Oodle
+        } else {
+            Compression::Other(name.clone())
+        })
+    }
+
+    fn entry(&self, index: usize) -> Res<&TocEntry> {
+        Ok(self.entries.get(index).msg_of(&index)?)
+    }
+
+    /// Listing of |entry|, with the method of its compressed blocks.
+    fn archive_entry(&self, entry: &TocEntry) -> ArchiveEntry {
+        let blocks = self.entry_blocks(entry).unwrap_or_default();
+        let mut compression = Compression::None;
+        for block in blocks.iter().filter(|block| block.method != 0) {
+            compression = self
+                .compression(block.method)
+                .unwrap_or_else(|_| Compression::Other(format!("method {}", block.method)));
+        }
+        ArchiveEntry {
+            path: match &entry.path {
+                Some(path) => path.clone(),
+                // chunks without a path, by their id
+                None => entry.id.0.iter().map(|b| format!("{:02x}", b)).collect(),
+            },
+            compressed_size: blocks.iter().map(|b| u64::from(b.compressed_size)).sum(),
+            size: entry.len,
+            compression,
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, len: u32) -> Res<Vec<u8>> {
+        let mut data = vec![0; len as usize];
+        self.cas.seek(SeekFrom::Start(offset)).at(self)?;
+        self.cas
+            .read_exact(&mut data)
+            .at(self)
+            .message(|_| format!("Failed to read {} bytes at {}", len, offset))?;
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> Archive for IoStore<R> {
+    fn list(&self) -> Vec<ArchiveEntry> {
+        self.entries
+            .iter()
+            .map(|entry| self.archive_entry(entry))
+            .collect()
+    }
+
+    fn read_stored(&mut self, index: usize) -> std::io::Result<Vec<u8>> {
+        let entry = self.entry(index)?.clone();
+        Ok(self.read_stored_entry(&entry)?)
+    }
+
+    fn decode(&self, index: usize, stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(self.decode_entry(self.entry(index)?, stored)?)
+    }
+}
+
+impl<R: Read + Seek> ErrorContext for IoStore<R> {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "IoStore version {}, {} chunks",
+            self.version,
+            self.entries.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::reader::tests::string;
+    use crate::tests::{kraken_stream, sample};
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: usize = 0x10000;
+
+    struct Chunk<'a> {
+        path: Option<&'a str>,
+        chunk_type: u8,
+        data: Vec<u8>,
+        compressed: bool,
+    }
+
+    /// Directory index with the files in the root, or in a single level of directories.
+    fn directory_index(chunks: &[Chunk]) -> Vec<u8> {
+        let mut strings: Vec<&str> = Vec::new();
+        let mut directories = vec![[NONE, NONE, NONE, NONE]];
+        let mut files: Vec<[u32; 3]> = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let Some(path) = chunk.path else {
+                continue;
+            };
+            let (directory, name) = match path.split_once('/') {
+                Some((directory, name)) => (Some(directory), name),
+                None => (None, path),
+            };
+            let directory = match directory {
+                None => 0,
+                Some(directory) => match strings.iter().position(|&s| s == directory) {
+                    Some(name) => directories
+                        .iter()
+                        .position(|d| d[0] == name as u32)
+                        .unwrap(),
+                    None => {
+                        strings.push(directory);
+                        // prepend to the children of the root
+                        directories.push([strings.len() as u32 - 1, NONE, directories[0][1], NONE]);
+                        directories[0][1] = directories.len() as u32 - 1;
+                        directories.len() - 1
+                    }
+                },
+            };
+            strings.push(name);
+            files.push([
+                strings.len() as u32 - 1,
+                directories[directory][3],
+                i as u32,
+            ]);
+            directories[directory][3] = files.len() as u32 - 1;
+        }
+
+        let mut out = Vec::new();
+        string(&mut out, "../../../");
+        out.extend_from_slice(&(directories.len() as u32).to_le_bytes());
+        directories
+            .iter()
+            .flatten()
+            .for_each(|v| out.extend_from_slice(&v.to_le_bytes()));
+        out.extend_from_slice(&(files.len() as u32).to_le_bytes());
+        files
+            .iter()
+            .flatten()
+            .for_each(|v| out.extend_from_slice(&v.to_le_bytes()));
+        out.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        strings.iter().for_each(|s| string(&mut out, s));
+        out
+    }
+
+    /// Table of contents and data of a version 8 container holding |chunks|. Each chunk
+    /// starts at a block boundary, and blocks are compressed with method 1, Oodle.
+    fn write_container(chunks: &[Chunk], flags: u8) -> (Vec<u8>, Vec<u8>) {
+        let mut cas = Vec::new();
+        let mut ids = Vec::new();
+        let mut offset_lengths = Vec::new();
+        let mut blocks = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut id = [0; 12];
+            id[..8].copy_from_slice(&(i as u64 + 0x1000).to_le_bytes());
+            id[11] = chunk.chunk_type;
+            ids.extend_from_slice(&id);
+            let offset = (blocks.len() / 12 * BLOCK_SIZE) as u64;
+            offset_lengths.extend_from_slice(&offset.to_be_bytes()[3..]);
+            offset_lengths.extend_from_slice(&(chunk.data.len() as u64).to_be_bytes()[3..]);
+            for block in chunk.data.chunks(BLOCK_SIZE) {
+                let stored = if chunk.compressed {
+                    kraken_stream(block)
+                } else {
+                    block.to_vec()
+                };
+                blocks.extend_from_slice(&(cas.len() as u64).to_le_bytes()[..5]);
+                blocks.extend_from_slice(&(stored.len() as u32).to_le_bytes()[..3]);
+                blocks.extend_from_slice(&(block.len() as u32).to_le_bytes()[..3]);
+                blocks.push(chunk.compressed as u8);
+                cas.extend(stored);
+            }
+        }
+        let directory_index = directory_index(chunks);
+
+        let mut toc = MAGIC.to_vec();
+        toc.extend_from_slice(&[VERSION_LATEST, 0, 0, 0]);
+        for value in [
+            144,
+            chunks.len() as u32,
+            blocks.len() as u32 / 12,
+            12,
+            1,
+            32,
+            BLOCK_SIZE as u32,
+            directory_index.len() as u32,
+            1,
+        ] {
+            toc.extend_from_slice(&value.to_le_bytes());
+        }
+        toc.extend_from_slice(&[0; 8 + 16]);
+        toc.extend_from_slice(&[flags, 0, 0, 0]);
+        // no perfect hash seeds, a single partition
+        toc.extend_from_slice(&0u32.to_le_bytes());
+        toc.extend_from_slice(&u64::MAX.to_le_bytes());
+        toc.extend_from_slice(&0u32.to_le_bytes());
+        toc.resize(144, 0);
+        toc.extend(ids);
+        toc.extend(offset_lengths);
+        toc.extend(blocks);
+        let mut name = [0; 32];
+        name[..5].copy_from_slice(b"Oodle");
+        toc.extend_from_slice(&name);
+        toc.extend(directory_index);
+        // chunk metas, which the reader doesn't use
+        toc.extend(std::iter::repeat_n(0, chunks.len() * 24));
+        (toc, cas)
+    }
+
+    fn chunks() -> Vec<Chunk<'static>> {
+        vec![
+            Chunk {
+                path: None,
+                chunk_type: 10,
+                data: vec![1, 2, 3, 4],
+                compressed: false,
+            },
+            Chunk {
+                path: Some("Game/Maps/Level.umap"),
+                chunk_type: 2,
+                data: sample(150_000, 0),
+                compressed: true,
+            },
+            Chunk {
+                path: Some("Game/Hero.uasset"),
+                chunk_type: 2,
+                data: b"Compressed in a single block".repeat(30),
+                compressed: true,
+            },
+            Chunk {
+                path: Some("Config.ini"),
+                chunk_type: 2,
+                data: b"[Stored]".to_vec(),
+                compressed: false,
+            },
+        ]
+    }
+
+    #[test_log::test]
+    fn reads_container() {
+        let chunks = chunks();
+        let (toc, cas) = write_container(&chunks, FLAG_INDEXED);
+        let mut store = IoStore::open(toc.as_slice(), Cursor::new(cas)).unwrap();
+        assert_eq!(store.version(), VERSION_LATEST);
+        assert_eq!(store.mount_point(), "../../../");
+        assert_eq!(store.entries().len(), chunks.len());
+        for (entry, chunk) in store.entries().to_vec().iter().zip(&chunks) {
+            assert_eq!(entry.path.as_deref(), chunk.path);
+            assert_eq!(entry.id.chunk_type(), chunk.chunk_type);
+            assert_eq!(store.read(entry).unwrap(), chunk.data, "{:?}", chunk.path);
+        }
+        let entry = store.find("Game/Hero.uasset").unwrap().clone();
+        assert_eq!(store.find_id(entry.id), Some(&entry));
+        assert!(store.find("Hero.uasset").is_none());
+
+        let listed = store.list();
+        assert_eq!(listed[0].path, "00100000000000000000000a");
+        assert_eq!(listed[0].compression, Compression::None);
+        assert_eq!(listed[1].path, "Game/Maps/Level.umap");
+        assert_eq!(listed[1].compression, Compression::Oodle);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(listed[i].size, chunk.data.len() as u64);
+            assert_eq!(store.extract(i).unwrap(), chunk.data);
+        }
+    }
+
+    #[test_log::test]
+    fn rejects_unsupported() {
+        let chunks = chunks();
+        let (toc, cas) = write_container(&chunks, FLAG_ENCRYPTED);
+        let mut store = IoStore::open(toc.as_slice(), Cursor::new(cas)).unwrap();
+        let entry = store.entries()[1].clone();
+        assert!(entry.path.is_none());
+        assert!(store.read(&entry).is_err());
+
+        let (mut toc, cas) = write_container(&chunks, FLAG_INDEXED);
+        // rename the method
+        let pos = toc.windows(5).position(|w| w == b"Oodle").unwrap();
+        toc[pos..pos + 5].copy_from_slice(b"Zlib\0");
+        let mut store = IoStore::open(toc.as_slice(), Cursor::new(cas)).unwrap();
+        let entry = store.find("Config.ini").unwrap().clone();
+        assert_eq!(store.read(&entry).unwrap(), b"[Stored]");
+        let entry = store.find("Game/Hero.uasset").unwrap().clone();
+        assert!(store.read(&entry).is_err());
+
+        assert!(IoStore::open(&[0u8; 200][..], Cursor::new(Vec::new())).is_err());
+    }
+}
@@ -7,18 +7,34 @@
     clippy::missing_asserts_for_indexing
 )]
 mod algorithm;
+pub mod archive;
 mod core;
+pub mod dcx;
+pub mod entropy;
 mod extractor;
+pub mod iostore;
+pub mod pak;
+pub mod pkg;
+pub mod warframe;
 
-pub use crate::extractor::Extractor;
+#[cfg(test)]
+mod testdata;
+
+pub use crate::extractor::{
+    decode_raw, decode_raw_with_state, decompress, decompress_to_vec, decompress_to_writer,
+    decompress_with_dictionary, detect, probe_size, CodecState, Confidence, DecoderType, Detection,
+    Extractor, Framing, MappedStream, SizeProbe, DEFAULT_WINDOW,
+};
 
 // used by benches/huffman.rs:
 //pub use crate::core::huffman::{reverse_naive, reverse_simd, reverse_sse};
 
 #[cfg(test)]
 mod tests {
+    pub(crate) use crate::testdata::xml_streams;
+
     use crate::extractor::Extractor;
-    use std::io::Read;
+    use std::io::{self, Read};
     use std::{
         fs,
         io::{Seek, SeekFrom},
@@ -26,6 +42,47 @@ mod tests {
         time,
     };
 
+    /// Kraken stream of |data|, for container fixtures. Chunks are entropy coded with the
+    /// encoders in [crate::entropy], or stored when that doesn't make them smaller.
+    /// Quanta that don't fit a compressed quantum are stored as uncompressed blocks.
+    pub(crate) fn kraken_stream(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, quantum) in data.chunks(0x40000).enumerate() {
+            let restart = if i == 0 { 0x80 } else { 0 };
+            let mut chunks = Vec::new();
+            for chunk in quantum.chunks(0x20000) {
+                let mut encoded = Vec::new();
+                crate::entropy::encode(chunk, &mut encoded).unwrap();
+                // short entropy headers would read as LZ chunk headers
+                if encoded[0] < 0x80 && encoded.len() < chunk.len() {
+                    chunks.extend(encoded);
+                } else {
+                    let header = 0x800000 | chunk.len() as u32;
+                    chunks.extend_from_slice(&header.to_be_bytes()[1..]);
+                    chunks.extend_from_slice(chunk);
+                }
+            }
+            if chunks.len() < 0x3FFFF {
+                out.extend_from_slice(&[restart | 0x0C, 0x06]);
+                let size = chunks.len() as u32 - 1;
+                out.extend_from_slice(&size.to_be_bytes()[1..]);
+                out.extend(chunks);
+            } else {
+                out.extend_from_slice(&[restart | 0x4C, 0x06]);
+                out.extend_from_slice(quantum);
+            }
+        }
+        out
+    }
+
+    /// Deterministic data of |len| bytes, which the entropy coders compress a little.
+    /// Different |seed|s give different data.
+    pub(crate) fn sample(len: usize, seed: u32) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| (i.wrapping_mul(i + seed) >> 7) as u8 % 53)
+            .collect()
+    }
+
     #[test_log::test]
     fn it_works() {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -74,4 +131,195 @@ mod tests {
         }
         log::debug!("done");
     }
+
+    /// Decodes the small testdata file of each codec with the default settings and
+    /// after |configure|, and compares the results.
+    fn compare_modes(configure: impl Fn(&mut Extractor<&[u8]>)) {
+        for (path, data, len) in xml_streams() {
+            let mut expected = vec![0; len];
+            Extractor::new(data.as_slice()).read(&mut expected).unwrap();
+            let mut actual = vec![0; len];
+            let mut extractor = Extractor::new(data.as_slice());
+            configure(&mut extractor);
+            extractor.read(&mut actual).unwrap();
+            assert!(expected == actual, "{:?}", path);
+        }
+    }
+
+    #[test_log::test]
+    fn pipelined_matches_sequential() {
+        compare_modes(|extractor| extractor.set_pipelined(true));
+    }
+
+    #[test_log::test]
+    fn dictionary_resumes_stream() {
+        for (path, data, len) in xml_streams() {
+            if len <= 0x40000 {
+                continue;
+            }
+
+            let mut expected = vec![0; len];
+            crate::decompress(data.as_slice(), &mut expected).unwrap();
+            // the same extractor keeps the Bitknit and LZNA models of the first block
+            let mut window = vec![0; len];
+            let mut extractor = Extractor::new(data.as_slice());
+            extractor.set_pipelined(true);
+            extractor.read(&mut window[..0x40000]).unwrap();
+            extractor.read_with_history(&mut window, 0x40000).unwrap();
+            assert!(expected == window, "{:?}", path);
+
+            // decode the first block again to find where the second one starts
+            let mut input = data.as_slice();
+            Extractor::new(&mut input)
+                .read(&mut window[..0x40000])
+                .unwrap();
+
+            // a new extractor only needs the dictionary with LZ codecs
+            if matches!(path.extension().unwrap().to_str(), Some("bitknit" | "lzna")) {
+                continue;
+            }
+            let (dictionary, rest) = expected.split_at(0x40000);
+            let mut actual = vec![0; rest.len()];
+            crate::decompress_with_dictionary(input, dictionary, &mut actual).unwrap();
+            assert!(rest == actual, "{:?}", path);
+
+            // later reads reference the output of earlier ones in the history
+            let mut actual = vec![0; rest.len()];
+            let mut extractor = Extractor::new(input);
+            extractor.set_dictionary(dictionary);
+            for block in actual.chunks_mut(0x40000) {
+                assert_eq!(extractor.read(block).unwrap(), block.len());
+            }
+            assert!(rest == actual, "{:?}", path);
+        }
+    }
+
+    #[test_log::test]
+    fn resume_saved_state() {
+        for (path, data, len) in xml_streams() {
+            let data = data.as_slice();
+
+            let mut expected = vec![0; len];
+            crate::decompress(data, &mut expected).unwrap();
+            // save and restore after every block, as if restarted each time
+            let mut state = Extractor::new(data).save_state(&[]).unwrap();
+            let mut pos = 0;
+            let mut actual = Vec::new();
+            while actual.len() < len {
+                let mut extractor = Extractor::resume(&data[pos..], &state).unwrap();
+                assert_eq!(extractor.position(), pos);
+                let mut block = vec![0; std::cmp::min(0x40000, len - actual.len())];
+                assert_eq!(extractor.read(&mut block).unwrap(), block.len());
+                actual.extend_from_slice(&block);
+                assert!(extractor.save_state(&block[1..]).is_err());
+                state = extractor.save_state(&block).unwrap();
+                pos = extractor.position();
+            }
+            assert!(expected == actual, "{:?}", path);
+            assert!(Extractor::resume(data, &state[..state.len() - 1]).is_err());
+        }
+    }
+
+    #[test_log::test]
+    fn decode_to_uninit() {
+        for (path, data, len) in xml_streams() {
+            let data = data.as_slice();
+
+            let mut expected = vec![0; len];
+            crate::decompress(data, &mut expected).unwrap();
+            assert!(
+                crate::decompress_to_vec(data, len).unwrap() == expected,
+                "{:?}",
+                path
+            );
+
+            let mut actual = Vec::with_capacity(len);
+            let mut extractor = Extractor::new(data);
+            extractor.set_pipelined(true);
+            let written = extractor.read_uninit(actual.spare_capacity_mut()).unwrap();
+            assert_eq!(written, len);
+            unsafe { actual.set_len(written) };
+            assert!(actual == expected, "{:?}", path);
+        }
+    }
+
+    #[test_log::test]
+    fn decode_to_writer() {
+        for (path, data, len) in xml_streams() {
+            let data = data.as_slice();
+
+            let expected = crate::decompress_to_vec(data, len).unwrap();
+            let mut actual = Vec::new();
+            assert_eq!(
+                crate::decompress_to_writer(data, &mut actual, len).unwrap(),
+                len
+            );
+            assert!(actual == expected, "{:?}", path);
+
+            // matches reach back to near the start, further than a 256k window
+            let mut actual = Vec::new();
+            let mut extractor = Extractor::new(data);
+            extractor.set_pipelined(true);
+            extractor
+                .read_to_writer(&mut actual, len, 0x500000)
+                .unwrap();
+            assert!(actual == expected, "{:?}", path);
+            assert!(Extractor::new(data)
+                .read_to_writer(io::sink(), len, 0x40000)
+                .is_err());
+        }
+
+        // without matches, the history is dropped as the output goes past the window
+        let data = sample(7 * 0x40000 + 100, 0);
+        let stream = kraken_stream(&data);
+        let mut actual = Vec::new();
+        let mut extractor = Extractor::new(stream.as_slice());
+        let written = extractor
+            .read_to_writer(&mut actual, data.len(), 0x40000)
+            .unwrap();
+        assert_eq!(written, data.len());
+        assert!(actual == data);
+    }
+
+    /// The codecs with contexts on the low bits of the output position, with windows that
+    /// drop history at positions that aren't a multiple of 16.
+    #[test_log::test]
+    fn unaligned_windows() {
+        for name in ["reymont.bitknit", "reymont.lzna", "reymont.leviathan"] {
+            let (data, len) = crate::testdata::stream(name);
+            let expected = crate::decompress_to_vec(&data, len).unwrap();
+            for window in [0x508001, 0x508003] {
+                let mut actual = Vec::new();
+                Extractor::new(data.as_slice())
+                    .read_to_writer(&mut actual, len, window)
+                    .unwrap();
+                assert!(actual == expected, "{} {:X}", name, window);
+            }
+            // each quantum would start a stream without history
+            for window in [0, 0x3FFF] {
+                assert!(Extractor::new(data.as_slice())
+                    .read_to_writer(io::sink(), len, window)
+                    .is_err());
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn kraken_stream_stores_incompressible() {
+        let mut x = 1u32;
+        let data: Vec<u8> = (0..0x40000 + 100)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let stream = kraken_stream(&data);
+        // an uncompressed block, then a stored chunk in a compressed quantum
+        assert_eq!(stream[..2], [0xCC, 0x06]);
+        assert_eq!(stream[0x40002..0x40004], [0x0C, 0x06]);
+        assert_eq!(stream[0x40007..0x4000A], [0x80, 0x00, 100]);
+        assert!(crate::decompress_to_vec(&stream, data.len()).unwrap() == data);
+    }
 }
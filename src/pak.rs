@@ -0,0 +1,817 @@
+//! Reader for Unreal Engine 4 `.pak` archives, whose entries are split in blocks that are
+//! each compressed on their own, without a size prefix.
+//!
+//! The footer at the end of the file locates the index, which lists the entries. From
+//! version 10 the index stores packed entries, and their names in a separate directory index.
+
+pub use crate::archive::Compression;
+use crate::archive::{Archive, ArchiveEntry};
+use crate::core::error::{ErrorBuilder, ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::reader::Reader;
+use crate::extractor::SizeProbe;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+const MAGIC: u32 = 0x5A6F12E1;
+
+// Versions that changed the layout, named as in the engine source.
+const VERSION_NO_TIMESTAMPS: u32 = 2;
+const VERSION_COMPRESSION_ENCRYPTION: u32 = 3;
+const VERSION_INDEX_ENCRYPTION: u32 = 4;
+const VERSION_RELATIVE_CHUNK_OFFSETS: u32 = 5;
+const VERSION_DELETE_RECORDS: u32 = 6;
+const VERSION_FNAME_BASED_COMPRESSION_METHOD: u32 = 8;
+const VERSION_FROZEN_INDEX: u32 = 9;
+const VERSION_PATH_HASH_INDEX: u32 = 10;
+const VERSION_LATEST: u32 = 11;
+
+/// Size of the footer fields from the magic to the index hash.
+const FOOTER_LEN: u64 = 44;
+/// Compression method names are stored in the footer in fields of this size.
+const METHOD_NAME_LEN: usize = 32;
+
+/// A file in a [Pak].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PakEntry {
+    /// Path below the mount point, with `/` separators.
+    pub path: String,
+    /// Position of the copy of the entry record in front of the data.
+    pub offset: u64,
+    /// Stored size, after compression.
+    pub size: u64,
+    pub uncompressed_size: u64,
+    pub compression: Compression,
+    /// Position of each compressed block in the file.
+    pub blocks: Vec<Range<u64>>,
+    /// Decompressed size of each block, except the last.
+    pub block_size: u32,
+    /// Encrypted entries need the AES key of the game.
+    pub encrypted: bool,
+}
+
+/// An open `.pak` archive.
+pub struct Pak<R: Read + Seek> {
+    reader: R,
+    version: u32,
+    /// Size of the file, which bounds every read.
+    file_len: u64,
+    mount_point: String,
+    entries: Vec<PakEntry>,
+}
+
+impl<R: Read + Seek> Pak<R> {
+    /// Reads the footer and the index of the archive in |reader|.
+    pub fn open(reader: R) -> std::io::Result<Self> {
+        let mut pak = Pak {
+            reader,
+            version: 0,
+            file_len: 0,
+            mount_point: String::new(),
+            entries: Vec::new(),
+        };
+        pak.read_index()?;
+        Ok(pak)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Path the entry paths are relative to, usually starting with `../../../`.
+    pub fn mount_point(&self) -> &str {
+        &self.mount_point
+    }
+
+    pub fn entries(&self) -> &[PakEntry] {
+        &self.entries
+    }
+
+    pub fn find(&self, path: &str) -> Option<&PakEntry> {
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Reads and decompresses the data of |entry|.
+    pub fn read(&mut self, entry: &PakEntry) -> std::io::Result<Vec<u8>> {
+        Ok(self.read_entry(entry)?)
+    }
+
+    fn read_index(&mut self) -> Res<()> {
+        let footer = self.read_footer()?;
+        if footer.encrypted_index {
+            self.raise("The index is encrypted".into())?
+        }
+        let index = self.read_at(footer.index_offset, footer.index_size)?;
+        let mut index = Reader::new(&index);
+        self.mount_point = index.string()?;
+        let count = index.u32()?;
+        if self.version < VERSION_PATH_HASH_INDEX {
+            for _ in 0..count {
+                let path = index.string()?;
+                if let Some(entry) = self.entry_record(&mut index, &footer.methods, path)? {
+                    self.entries.push(entry);
+                }
+            }
+        } else {
+            self.read_directory_index(&mut index, &footer.methods)?;
+            self.assert_eq(self.entries.len(), count as usize)?;
+        }
+        log::debug!("Read {} pak entries", self.entries.len());
+        Ok(())
+    }
+
+    fn read_footer(&mut self) -> Res<Footer> {
+        let file_len = self.reader.seek(SeekFrom::End(0)).at(self)?;
+        self.file_len = file_len;
+        // the fields after the index hash depend on the version, which is in front of them
+        for trailing in [
+            0,
+            4 * METHOD_NAME_LEN,
+            5 * METHOD_NAME_LEN,
+            5 * METHOD_NAME_LEN + 1,
+        ] {
+            let Some(start) = file_len.checked_sub(FOOTER_LEN + trailing as u64 + 1) else {
+                continue;
+            };
+            let data = self.read_at(start, FOOTER_LEN + trailing as u64 + 1)?;
+            let mut footer = Reader::new(&data);
+            let encrypted_index = footer.u8()?;
+            if footer.u32()? != MAGIC {
+                continue;
+            }
+            let version = footer.u32()?;
+            let expected = match version {
+                ..VERSION_FNAME_BASED_COMPRESSION_METHOD => 0,
+                VERSION_FNAME_BASED_COMPRESSION_METHOD if trailing == 4 * METHOD_NAME_LEN => {
+                    trailing
+                }
+                VERSION_FROZEN_INDEX => 5 * METHOD_NAME_LEN + 1,
+                VERSION_FNAME_BASED_COMPRESSION_METHOD
+                | VERSION_PATH_HASH_INDEX..=VERSION_LATEST => 5 * METHOD_NAME_LEN,
+                _ => self.raise(format!("Unsupported pak version {}", version))?,
+            };
+            if trailing != expected || version == 0 {
+                continue;
+            }
+            self.version = version;
+            let index_offset = footer.u64()?;
+            let index_size = footer.u64()?;
+            footer.bytes(20)?;
+            if version == VERSION_FROZEN_INDEX && footer.u8()? != 0 {
+                self.raise("Frozen indexes aren't supported".into())?
+            }
+            let mut methods = Vec::new();
+            while footer.remaining() > 0 {
+                let name = footer.bytes(METHOD_NAME_LEN)?;
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                methods.push(String::from_utf8_lossy(name.get(..len).err()?).into_owned());
+            }
+            log::debug!("Pak version {} with methods {:?}", version, methods);
+            return Ok(Footer {
+                encrypted_index: version >= VERSION_INDEX_ENCRYPTION && encrypted_index != 0,
+                index_offset,
+                index_size,
+                methods,
+            });
+        }
+        self.raise("No pak footer found".into())?
+    }
+
+    /// Reads an entry record as stored in the index of older versions, and in front of the data.
+    /// Returns None for records of deleted files.
+    fn entry_record(
+        &self,
+        index: &mut Reader,
+        methods: &[String],
+        path: String,
+    ) -> Res<Option<PakEntry>> {
+        let offset = index.u64()?;
+        let size = index.u64()?;
+        let uncompressed_size = index.u64()?;
+        let compression = if self.version < VERSION_FNAME_BASED_COMPRESSION_METHOD {
+            match index.u32()? {
+                0 => Compression::None,
+                0x1 => Compression::Other("Zlib".into()),
+                0x2 => Compression::Other("Gzip".into()),
+                // the custom method of these versions is the Oodle plugin
+                0x4 => Compression::Oodle,
+                flags => Compression::Other(format!("flags {:X}", flags)),
+            }
+        } else {
+            self.method(methods, index.u32()?)?
+        };
+        if self.version < VERSION_NO_TIMESTAMPS {
+            index.u64()?;
+        }
+        index.bytes(20)?;
+        let mut blocks = Vec::new();
+        let mut flags = 0;
+        let mut block_size = 0;
+        if self.version >= VERSION_COMPRESSION_ENCRYPTION {
+            if compression != Compression::None {
+                let base = if self.version >= VERSION_RELATIVE_CHUNK_OFFSETS {
+                    offset
+                } else {
+                    0
+                };
+                for _ in 0..index.u32()? {
+                    let start = index.u64()?;
+                    let end = index.u64()?;
+                    blocks.push(add(base, start)?..add(base, end)?);
+                }
+            }
+            flags = index.u8()?;
+            block_size = index.u32()?;
+        }
+        if self.version >= VERSION_DELETE_RECORDS && flags & 2 != 0 {
+            return Ok(None);
+        }
+        Ok(Some(PakEntry {
+            path,
+            offset,
+            size,
+            uncompressed_size,
+            compression,
+            blocks,
+            block_size,
+            encrypted: flags & 1 != 0,
+        }))
+    }
+
+    /// Reads the entries of version 10 and later, which are packed in the index, and
+    /// named by the full directory index.
+    fn read_directory_index(&mut self, index: &mut Reader, methods: &[String]) -> Res<()> {
+        // seed of the path hash index, which only maps hashes to entries
+        index.u64()?;
+        if index.u32()? != 0 {
+            index.bytes(8 + 8 + 20)?;
+        }
+        if index.u32()? == 0 {
+            self.raise("The pak has no full directory index".into())?
+        }
+        let directory_offset = index.u64()?;
+        let directory_size = index.u64()?;
+        index.bytes(20)?;
+        let len = index.u32()?;
+        let encoded = index.bytes(len as usize)?;
+        let mut records = Vec::new();
+        for _ in 0..index.u32()? {
+            records.push(self.entry_record(index, methods, String::new())?);
+        }
+
+        let directories = self.read_at(directory_offset, directory_size)?;
+        let mut directories = Reader::new(&directories);
+        for _ in 0..directories.u32()? {
+            let directory = directories.string()?;
+            for _ in 0..directories.u32()? {
+                let name = directories.string()?;
+                let location = directories.i32()?;
+                let path = format!("{}{}", directory, name)
+                    .trim_start_matches('/')
+                    .to_string();
+                let entry = if let Ok(start) = usize::try_from(location) {
+                    let mut reader = Reader::new(encoded.get(start..).err()?);
+                    Some(self.encoded_entry(&mut reader, methods, path)?)
+                } else {
+                    let i = usize::try_from(-(location + 1)).at(self)?;
+                    records
+                        .get(i)
+                        .msg_of(&location)?
+                        .clone()
+                        .map(|entry| PakEntry { path, ..entry })
+                };
+                self.entries.extend(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpacks an entry of version 10 and later. The bit field in front holds
+    /// whether offset and sizes fit in 32 bits, the method, the block count and size.
+    fn encoded_entry(&self, index: &mut Reader, methods: &[String], path: String) -> Res<PakEntry> {
+        let value = index.u32()?;
+        let block_size = if value & 0x3F == 0x3F {
+            index.u32()?
+        } else {
+            (value & 0x3F) << 11
+        };
+        let method = (value >> 23) & 0x3F;
+        let mut read_size = |small: bool| {
+            if small {
+                index.u32().map(u64::from)
+            } else {
+                index.u64()
+            }
+        };
+        let offset = read_size(value & (1 << 31) != 0)?;
+        let uncompressed_size = read_size(value & (1 << 30) != 0)?;
+        let size = if method != 0 {
+            read_size(value & (1 << 29) != 0)?
+        } else {
+            uncompressed_size
+        };
+        let encrypted = value & (1 << 22) != 0;
+        let block_count = (value >> 6) & 0xFFFF;
+
+        let mut blocks = Vec::new();
+        let mut start = add(offset, header_len(self.version, method != 0, block_count))?;
+        if block_count == 1 && !encrypted {
+            blocks.push(start..add(start, size)?);
+        } else {
+            for _ in 0..block_count {
+                let len = u64::from(index.u32()?);
+                blocks.push(start..add(start, len)?);
+                // encrypted blocks are padded to the AES block size
+                start = add(
+                    start,
+                    if encrypted {
+                        len.next_multiple_of(16)
+                    } else {
+                        len
+                    },
+                )?;
+            }
+        }
+        Ok(PakEntry {
+            path,
+            offset,
+            size,
+            uncompressed_size,
+            compression: self.method(methods, method)?,
+            blocks,
+            block_size: if block_count == 1 {
+                u32::try_from(uncompressed_size).at(self)?
+            } else {
+                block_size
+            },
+            encrypted,
+        })
+    }
+
+    /// Compression method by its index in the footer, which starts at 1.
+    fn method(&self, methods: &[String], index: u32) -> Res<Compression> {
+        if index == 0 {
+            return Ok(Compression::None);
+        }
+        let name = methods.get(index as usize - 1).msg_of(&index)?;
+        Ok(if name.eq_ignore_ascii_case("oodle") {
+            Compression::Oodle
+        } else {
+            Compression::Other(name.clone())
+        })
+    }
+
+    fn entry(&self, index: usize) -> Res<&PakEntry> {
+        Ok(self.entries.get(index).msg_of(&index)?)
+    }
+
+    fn read_entry(&mut self, entry: &PakEntry) -> Res<Vec<u8>> {
+        let stored = self.read_stored_entry(entry)?;
+        self.decode_entry(entry, &stored)
+    }
+
+    /// Reads the data of |entry|, from the start of the first block to the end of the last.
+    fn read_stored_entry(&mut self, entry: &PakEntry) -> Res<Vec<u8>> {
+        if entry.encrypted {
+            self.raise(format!("{} is encrypted", entry.path))?
+        }
+        match (entry.blocks.first(), entry.blocks.last()) {
+            (Some(first), Some(last)) => {
+                let len = last.end.checked_sub(first.start).msg_of(&entry.blocks)?;
+                self.read_at(first.start, len)
+            }
+            _ => {
+                let start = add(entry.offset, header_len(self.version, false, 0))?;
+                self.read_at(start, entry.size)
+            }
+        }
+    }
+
+    fn decode_entry(&self, entry: &PakEntry, stored: &[u8]) -> Res<Vec<u8>> {
+        let len = usize::try_from(entry.uncompressed_size).at(self)?;
+        match &entry.compression {
+            Compression::None => Ok(stored.to_vec()),
+            Compression::Oodle => {
+                let base = entry.blocks.first().map_or(0, |block| block.start);
+                let mut blocks = Vec::new();
+                for block in &entry.blocks {
+                    let start = block.start.checked_sub(base).msg_of(block)? as usize;
+                    let end = block.end.checked_sub(base).msg_of(block)? as usize;
+                    blocks.push(stored.get(start..end).msg_of(block)?);
+                }
+                let block_size = match (blocks.as_slice(), entry.block_size) {
+                    // a single block holds everything, whatever the size says, and the stream
+                    // bounds how much that can be
+                    ([data], _) => {
+                        let (SizeProbe::Exact(most) | SizeProbe::AtMost(most)) =
+                            crate::probe_size(data).at(self)?;
+                        self.assert_le(len, most)
+                            .message(|_| format!("{} is {} bytes", entry.path, len))?;
+                        len.max(1)
+                    }
+                    (_, 0) => self.raise(format!("{} has blocks of 0 bytes", entry.path))?,
+                    (_, block_size) => block_size as usize,
+                };
+                // checked before the output is allocated
+                self.assert_eq(blocks.len(), len.div_ceil(block_size))?;
+                let mut out = vec![0; len];
+                for (data, out) in blocks.into_iter().zip(out.chunks_mut(block_size)) {
+                    let written = crate::decompress(data, out).at(self)?;
+                    self.assert_eq(written, out.len())?;
+                }
+                Ok(out)
+            }
+            Compression::Other(name) => self.raise(format!("Unsupported compression {}", name))?,
+        }
+    }
+
+    /// Reads |len| bytes at |offset|, which come from the file and are checked against its
+    /// size before anything is allocated.
+    fn read_at(&mut self, offset: u64, len: u64) -> Res<Vec<u8>> {
+        self.assert_le(add(offset, len)?, self.file_len)
+            .message(|_| format!("{} bytes at {} are past the end of the file", len, offset))?;
+        let mut data = vec![0; usize::try_from(len).at(self)?];
+        self.reader.seek(SeekFrom::Start(offset)).at(self)?;
+        self.reader
+            .read_exact(&mut data)
+            .at(self)
+            .message(|_| format!("Failed to read {} bytes at {}", len, offset))?;
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> Archive for Pak<R> {
+    fn list(&self) -> Vec<ArchiveEntry> {
+        self.entries
+            .iter()
+            .map(|entry| ArchiveEntry {
+                path: entry.path.clone(),
+                compressed_size: entry.size,
+                size: entry.uncompressed_size,
+                compression: entry.compression.clone(),
+            })
+            .collect()
+    }
+
+    fn read_stored(&mut self, index: usize) -> std::io::Result<Vec<u8>> {
+        let entry = self.entry(index)?.clone();
+        Ok(self.read_stored_entry(&entry)?)
+    }
+
+    fn decode(&self, index: usize, stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        let entry = self.entry(index)?;
+        Ok(self.decode_entry(entry, stored)?)
+    }
+}
+
+impl<R: Read + Seek> ErrorContext for Pak<R> {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "pak version {}, {} entries",
+            self.version,
+            self.entries.len()
+        ))
+    }
+}
+
+struct Footer {
+    encrypted_index: bool,
+    index_offset: u64,
+    index_size: u64,
+    /// Names of the compression methods from version 8.
+    methods: Vec<String>,
+}
+
+/// Sum of offsets and sizes from the index, which can be anything.
+fn add(a: u64, b: u64) -> Result<u64, ErrorBuilder> {
+    a.checked_add(b)
+        .message(|_| format!("{} + {} overflows", a, b))
+}
+
+/// Size of the entry record in front of the data.
+fn header_len(version: u32, compressed: bool, block_count: u32) -> u64 {
+    // offset, sizes, method and hash
+    let mut len = 8 + 8 + 8 + 4 + 20;
+    if version >= VERSION_COMPRESSION_ENCRYPTION {
+        // flags and block size
+        len += 1 + 4;
+        if compressed {
+            len += 4 + 16 * u64::from(block_count);
+        }
+    }
+    if version < VERSION_NO_TIMESTAMPS {
+        len += 8;
+    }
+    len
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::core::reader::tests::string;
+    use crate::tests::{kraken_stream, sample};
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: usize = 0x10000;
+
+    pub(crate) struct File<'a> {
+        pub path: &'a str,
+        pub data: Vec<u8>,
+        pub method: u32,
+        pub flags: u8,
+    }
+
+    /// Entry record with the block ranges as stored, relative from version 5.
+    fn record(version: u32, offset: u64, file: &File, blocks: &[Range<u64>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let size = blocks.iter().map(|b| b.end - b.start).sum::<u64>();
+        out.extend_from_slice(&offset.to_le_bytes());
+        let size = if file.method == 0 {
+            file.data.len() as u64
+        } else {
+            size
+        };
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&(file.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&file.method.to_le_bytes());
+        out.extend_from_slice(&[0; 20]);
+        if file.method != 0 {
+            out.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+            let base = if version >= VERSION_RELATIVE_CHUNK_OFFSETS {
+                offset
+            } else {
+                0
+            };
+            for block in blocks {
+                out.extend_from_slice(&(block.start - base).to_le_bytes());
+                out.extend_from_slice(&(block.end - base).to_le_bytes());
+            }
+        }
+        out.push(file.flags);
+        out.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        out
+    }
+
+    /// Encoded entry of version 10 and later.
+    fn encoded(offset: u64, file: &File, blocks: &[Range<u64>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let count = if file.method == 0 {
+            0
+        } else {
+            blocks.len() as u32
+        };
+        let value = 7 << 29 | file.method << 23 | count << 6 | (BLOCK_SIZE as u32 >> 11);
+        out.extend_from_slice(&value.to_le_bytes());
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        out.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+        if file.method != 0 {
+            let size = blocks.iter().map(|b| b.end - b.start).sum::<u64>();
+            out.extend_from_slice(&(size as u32).to_le_bytes());
+            if count > 1 {
+                for block in blocks {
+                    out.extend_from_slice(&((block.end - block.start) as u32).to_le_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Pak of |version| holding |files|. From version 8 method 1 is named Oodle.
+    /// From version 10, entries are encoded in the index like UnrealPak does, stored ones
+    /// with method 0. Entries with flags get a full record instead, like the ones UnrealPak
+    /// can't encode, so both kinds are read.
+    pub(crate) fn write_pak(version: u32, files: &[File]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut index = Vec::new();
+        let mut encoded_entries = Vec::new();
+        let mut records = Vec::new();
+        let mut stored = 0;
+        let mut directories: Vec<(String, Vec<(String, i32)>)> = Vec::new();
+        for file in files {
+            let offset = out.len() as u64;
+            let streams: Vec<Vec<u8>> = if file.method == 0 {
+                vec![file.data.clone()]
+            } else {
+                file.data.chunks(BLOCK_SIZE).map(kraken_stream).collect()
+            };
+            let mut start = offset + header_len(version, file.method != 0, streams.len() as u32);
+            let mut blocks = Vec::new();
+            for stream in &streams {
+                blocks.push(start..start + stream.len() as u64);
+                start += stream.len() as u64;
+            }
+            let record = record(version, offset, file, &blocks);
+            out.extend_from_slice(&record);
+            streams
+                .iter()
+                .for_each(|stream| out.extend_from_slice(stream));
+
+            if version < VERSION_PATH_HASH_INDEX {
+                string(&mut index, file.path);
+                index.extend(record);
+                continue;
+            }
+            let location = if file.flags != 0 {
+                records.extend(record);
+                stored += 1;
+                -stored
+            } else {
+                let location = encoded_entries.len() as i32;
+                encoded_entries.extend(encoded(offset, file, &blocks));
+                location
+            };
+            let (directory, name) = match file.path.rsplit_once('/') {
+                Some((directory, name)) => (format!("/{}/", directory), name),
+                None => ("/".to_string(), file.path),
+            };
+            match directories.iter_mut().find(|(d, _)| *d == directory) {
+                Some((_, names)) => names.push((name.to_string(), location)),
+                None => directories.push((directory, vec![(name.to_string(), location)])),
+            }
+        }
+
+        let index_offset = out.len() as u64;
+        let mut header = Vec::new();
+        string(&mut header, "../../../Game/");
+        header.extend_from_slice(&(files.len() as u32).to_le_bytes());
+        if version >= VERSION_PATH_HASH_INDEX {
+            let mut directory_index = Vec::new();
+            directory_index.extend_from_slice(&(directories.len() as u32).to_le_bytes());
+            for (directory, names) in &directories {
+                string(&mut directory_index, directory);
+                directory_index.extend_from_slice(&(names.len() as u32).to_le_bytes());
+                for (name, location) in names {
+                    string(&mut directory_index, name);
+                    directory_index.extend_from_slice(&location.to_le_bytes());
+                }
+            }
+            index.extend_from_slice(&0u64.to_le_bytes());
+            // no path hash index
+            index.extend_from_slice(&0u32.to_le_bytes());
+            index.extend_from_slice(&1u32.to_le_bytes());
+            // the directory index follows the primary index
+            let len = header.len() + index.len() + 8 + 8 + 20 + 4 + encoded_entries.len() + 4;
+            let len = len + records.len();
+            index.extend_from_slice(&(index_offset + len as u64).to_le_bytes());
+            index.extend_from_slice(&(directory_index.len() as u64).to_le_bytes());
+            index.extend_from_slice(&[0; 20]);
+            index.extend_from_slice(&(encoded_entries.len() as u32).to_le_bytes());
+            index.extend(encoded_entries);
+            index.extend_from_slice(&(stored as u32).to_le_bytes());
+            index.extend(records);
+            header.extend(index);
+            let index_size = header.len() as u64;
+            out.extend(header);
+            out.extend(directory_index);
+            footer(&mut out, version, index_offset, index_size);
+        } else {
+            header.extend(index);
+            let index_size = header.len() as u64;
+            out.extend(header);
+            footer(&mut out, version, index_offset, index_size);
+        }
+        out
+    }
+
+    fn footer(out: &mut Vec<u8>, version: u32, index_offset: u64, index_size: u64) {
+        if version >= 7 {
+            // encryption key GUID
+            out.extend_from_slice(&[0; 16]);
+        }
+        if version >= VERSION_INDEX_ENCRYPTION {
+            out.push(0);
+        }
+        out.extend_from_slice(&MAGIC.to_le_bytes());
+        out.extend_from_slice(&version.to_le_bytes());
+        out.extend_from_slice(&index_offset.to_le_bytes());
+        out.extend_from_slice(&index_size.to_le_bytes());
+        out.extend_from_slice(&[0; 20]);
+        if version >= VERSION_FNAME_BASED_COMPRESSION_METHOD {
+            let count = if version == VERSION_FNAME_BASED_COMPRESSION_METHOD {
+                4
+            } else {
+                5
+            };
+            for i in 0..count {
+                let mut name = [0; METHOD_NAME_LEN];
+                if i == 0 {
+                    name[..5].copy_from_slice(b"Oodle");
+                }
+                out.extend_from_slice(&name);
+            }
+        }
+    }
+
+    fn files(oodle: u32) -> Vec<File<'static>> {
+        vec![
+            File {
+                path: "readme.txt",
+                data: b"Stored in a single block".repeat(20),
+                method: oodle,
+                flags: 0,
+            },
+            File {
+                path: "Content/Maps/level.umap",
+                data: sample(150_000, 0),
+                method: oodle,
+                flags: 0,
+            },
+            File {
+                path: "Content/raw.bin",
+                data: (0..1000u32).map(|i| i as u8).collect(),
+                method: 0,
+                flags: 0,
+            },
+        ]
+    }
+
+    #[test_log::test]
+    fn reads_versions() {
+        for version in [3, 8, 11] {
+            let oodle = if version < VERSION_FNAME_BASED_COMPRESSION_METHOD {
+                4
+            } else {
+                1
+            };
+            let files = files(oodle);
+            let mut pak = Pak::open(Cursor::new(write_pak(version, &files))).unwrap();
+            assert_eq!(pak.version(), version);
+            assert_eq!(pak.mount_point(), "../../../Game/");
+            let paths: Vec<&str> = pak.entries().iter().map(|e| e.path.as_str()).collect();
+            assert_eq!(paths, files.iter().map(|f| f.path).collect::<Vec<_>>());
+            for file in &files {
+                let entry = pak.find(file.path).unwrap().clone();
+                let expected = if file.method == 0 {
+                    Compression::None
+                } else {
+                    Compression::Oodle
+                };
+                assert_eq!(entry.compression, expected, "{}", file.path);
+                assert_eq!(pak.read(&entry).unwrap(), file.data, "{}", file.path);
+            }
+            assert_eq!(pak.find("Content/Maps/level.umap").unwrap().blocks.len(), 3);
+        }
+    }
+
+    #[test_log::test]
+    fn reads_records() {
+        // a full record in the index from version 10
+        let mut encrypted = files(1);
+        encrypted[1].flags = 1;
+        let pak = Pak::open(Cursor::new(write_pak(11, &encrypted))).unwrap();
+        let paths: Vec<&str> = pak.entries().iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, encrypted.iter().map(|f| f.path).collect::<Vec<_>>());
+        assert!(pak.find("Content/Maps/level.umap").unwrap().encrypted);
+        assert!(!pak.find("Content/raw.bin").unwrap().encrypted);
+
+        // single blocks of old versions may not have a block size
+        let mut data = write_pak(3, &files(4));
+        let record_len = 8 + 8 + 8 + 4 + 20 + 4 + 16 + 1 + 4;
+        let record = data[..record_len].to_vec();
+        let index = record_len
+            + data[record_len..]
+                .windows(record_len)
+                .position(|w| w == record)
+                .unwrap();
+        data[index + record_len - 4..index + record_len].fill(0);
+        let mut pak = Pak::open(Cursor::new(data)).unwrap();
+        let entry = pak.entries()[0].clone();
+        assert_eq!(entry.block_size, 0);
+        assert_eq!(pak.read(&entry).unwrap(), files(4)[0].data);
+    }
+
+    #[test_log::test]
+    fn rejects_unsupported() {
+        let mut files = files(4);
+        files[0].method = 1;
+        files[1].flags = 1;
+        let mut pak = Pak::open(Cursor::new(write_pak(3, &files))).unwrap();
+        let entry = pak.entries()[0].clone();
+        assert_eq!(entry.compression, Compression::Other("Zlib".into()));
+        assert!(pak.read(&entry).is_err());
+        let entry = pak.entries()[1].clone();
+        assert!(entry.encrypted);
+        assert!(pak.read(&entry).is_err());
+
+        assert!(Pak::open(Cursor::new(vec![0; 300])).is_err());
+        assert!(Pak::open(Cursor::new(Vec::new())).is_err());
+    }
+
+    #[test_log::test]
+    fn rejects_corrupt_sizes() {
+        // an index that would be larger than the file
+        let mut data = write_pak(3, &files(4));
+        let len = data.len();
+        data[len - 28..len - 20].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert!(Pak::open(Cursor::new(data)).is_err());
+
+        let mut pak = Pak::open(Cursor::new(write_pak(3, &files(4)))).unwrap();
+        for path in ["readme.txt", "Content/Maps/level.umap"] {
+            let mut entry = pak.find(path).unwrap().clone();
+            entry.uncompressed_size = 1 << 40;
+            assert!(pak.read(&entry).is_err(), "{}", path);
+        }
+        let mut entry = pak.find("Content/raw.bin").unwrap().clone();
+        entry.size = 1 << 40;
+        assert!(pak.read(&entry).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! Reader for Tiger engine `.pkg` packages, as used by Destiny 2.
+//!
+//! A package has a table of entries, the files, and a table of blocks, each decompressing
+//! to at most 256k. An entry starts at an offset in a block and continues in the next
+//! blocks. Blocks can be stored in the package of a later patch, and can be encrypted
+//! with AES-GCM, whose keys aren't part of this crate: see [BlockDecryptor].
+
+use crate::archive::{Archive, ArchiveEntry, Compression};
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::reader::Reader;
+use crate::SizeProbe;
+
+/// Decompressed size of all blocks but the last.
+pub const BLOCK_SIZE: usize = 0x40000;
+
+// header fields of Beyond Light and later packages
+const PKG_ID: usize = 0x10;
+const PATCH_ID: usize = 0x30;
+const ENTRY_TABLE_OFFSET: usize = 0x44;
+const ENTRY_COUNT: usize = 0x60;
+const BLOCK_COUNT: usize = 0x68;
+const BLOCK_TABLE_OFFSET: usize = 0x6C;
+const HEADER_LEN: usize = 0x70;
+
+const ENTRY_LEN: usize = 16;
+const BLOCK_LEN: usize = 0x30;
+
+const FLAG_COMPRESSED: u16 = 1;
+const FLAG_ENCRYPTED: u16 = 2;
+const FLAG_ALTERNATE_KEY: u16 = 4;
+
+/// A file in a [Package].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PkgEntry {
+    /// Tag of the type of the file.
+    pub reference: u32,
+    pub type_info: u32,
+    pub starting_block: u32,
+    /// Where the file starts in the output of the starting block.
+    pub starting_block_offset: u32,
+    pub size: u32,
+}
+
+impl PkgEntry {
+    pub fn file_type(&self) -> u8 {
+        (self.type_info >> 9) as u8 & 0x7F
+    }
+
+    pub fn file_subtype(&self) -> u8 {
+        (self.type_info >> 6) as u8 & 0x7
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PkgBlock {
+    /// Position in the package of |patch_id|.
+    pub offset: u32,
+    pub size: u32,
+    pub patch_id: u16,
+    pub flags: u16,
+    /// SHA-1 of the stored block.
+    pub hash: [u8; 20],
+    /// AES-GCM tag of encrypted blocks.
+    pub tag: [u8; 16],
+}
+
+impl PkgBlock {
+    pub fn compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// Encrypted with the second of the two keys.
+    pub fn alternate_key(&self) -> bool {
+        self.flags & FLAG_ALTERNATE_KEY != 0
+    }
+}
+
+/// Decrypts encrypted blocks, before they're decompressed.
+pub trait BlockDecryptor {
+    /// Decrypts |data|, the stored bytes of |block| in the package of |pkg_id|, in place.
+    fn decrypt(&mut self, pkg_id: u16, block: &PkgBlock, data: &mut [u8]) -> std::io::Result<()>;
+}
+
+/// A package, with the files of its patches.
+pub struct Package<'a> {
+    pkg_id: u16,
+    patch_id: u16,
+    entries: Vec<PkgEntry>,
+    blocks: Vec<PkgBlock>,
+    patches: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Package<'a> {
+    /// Reads the tables of the package in |data|, the file with the highest patch id.
+    pub fn parse(data: &'a [u8]) -> std::io::Result<Self> {
+        let mut package = Package {
+            pkg_id: 0,
+            patch_id: 0,
+            entries: Vec::new(),
+            blocks: Vec::new(),
+            patches: Vec::new(),
+        };
+        package.read_tables(data)?;
+        package.patches.push((package.patch_id, data));
+        Ok(package)
+    }
+
+    pub fn pkg_id(&self) -> u16 {
+        self.pkg_id
+    }
+
+    pub fn patch_id(&self) -> u16 {
+        self.patch_id
+    }
+
+    pub fn entries(&self) -> &[PkgEntry] {
+        &self.entries
+    }
+
+    pub fn blocks(&self) -> &[PkgBlock] {
+        &self.blocks
+    }
+
+    /// Adds |data|, the file of an earlier patch, for the blocks stored there.
+    pub fn add_patch(&mut self, patch_id: u16, data: &'a [u8]) {
+        self.patches.retain(|(id, _)| *id != patch_id);
+        self.patches.push((patch_id, data));
+    }
+
+    /// Decrypts, if needed, and decompresses the block at |index|.
+    pub fn read_block(
+        &self,
+        index: usize,
+        decryptor: Option<&mut dyn BlockDecryptor>,
+    ) -> std::io::Result<Vec<u8>> {
+        Ok(self.block(index, decryptor)?)
+    }
+
+    /// Reads the file of |entry|, from its blocks.
+    pub fn read(
+        &self,
+        entry: &PkgEntry,
+        decryptor: Option<&mut dyn BlockDecryptor>,
+    ) -> std::io::Result<Vec<u8>> {
+        Ok(self.read_entry(entry, decryptor)?)
+    }
+
+    fn entry(&self, index: usize) -> Res<&PkgEntry> {
+        Ok(self.entries.get(index).msg_of(&index)?)
+    }
+
+    fn read_entry(
+        &self,
+        entry: &PkgEntry,
+        mut decryptor: Option<&mut dyn BlockDecryptor>,
+    ) -> Res<Vec<u8>> {
+        let size = entry.size as usize;
+        let mut out = Vec::with_capacity(size);
+        let mut start = entry.starting_block_offset as usize;
+        let mut index = entry.starting_block as usize;
+        while out.len() < size {
+            let block = self.block(index, decryptor.as_deref_mut())?;
+            let end = block.len().min(start + size - out.len());
+            out.extend_from_slice(block.get(start..end).msg_of(&(start, end))?);
+            start = 0;
+            index += 1;
+        }
+        Ok(out)
+    }
+
+    fn read_tables(&mut self, data: &[u8]) -> Res<()> {
+        let header = data.get(..HEADER_LEN).msg_of(&"Package header cut off")?;
+        let field = |offset: usize| Reader::new(header.get(offset..).unwrap_or_default()).u32();
+        self.pkg_id = field(PKG_ID)? as u16;
+        self.patch_id = field(PATCH_ID)? as u16;
+        let table = |offset: usize, count: u32, len: usize| {
+            let end = offset + count as usize * len;
+            data.get(offset..end)
+                .message(|_| format!("Table at {} of {} records cut off", offset, count))
+        };
+
+        let entries = table(
+            field(ENTRY_TABLE_OFFSET)? as usize,
+            field(ENTRY_COUNT)?,
+            ENTRY_LEN,
+        )?;
+        let mut entries = Reader::new(entries);
+        while entries.remaining() > 0 {
+            let reference = entries.u32()?;
+            let type_info = entries.u32()?;
+            let info = entries.u64()?;
+            self.entries.push(PkgEntry {
+                reference,
+                type_info,
+                starting_block: (info & 0x3FFF) as u32,
+                starting_block_offset: ((info >> 14) & 0x3FFF) as u32 * 16,
+                size: (info >> 28) as u32 & 0x3FFF_FFFF,
+            });
+        }
+
+        let blocks = table(
+            field(BLOCK_TABLE_OFFSET)? as usize,
+            field(BLOCK_COUNT)?,
+            BLOCK_LEN,
+        )?;
+        let mut blocks = Reader::new(blocks);
+        while blocks.remaining() > 0 {
+            let offset = blocks.u32()?;
+            let size = blocks.u32()?;
+            let patch_id = u16::from_le_bytes(blocks.array()?);
+            let flags = u16::from_le_bytes(blocks.array()?);
+            self.blocks.push(PkgBlock {
+                offset,
+                size,
+                patch_id,
+                flags,
+                hash: blocks.array()?,
+                tag: blocks.array()?,
+            });
+        }
+        log::debug!(
+            "Package {:04x} patch {} with {} entries in {} blocks",
+            self.pkg_id,
+            self.patch_id,
+            self.entries.len(),
+            self.blocks.len()
+        );
+        Ok(())
+    }
+
+    fn block<'d>(
+        &self,
+        index: usize,
+        decryptor: Option<&mut (dyn BlockDecryptor + 'd)>,
+    ) -> Res<Vec<u8>> {
+        let block = self.blocks.get(index).msg_of(&index)?;
+        let (_, data) = self
+            .patches
+            .iter()
+            .find(|(id, _)| *id == block.patch_id)
+            .message(|_| format!("Block {} is in patch {}", index, block.patch_id))?;
+        let start = block.offset as usize;
+        let data = data
+            .get(start..start + block.size as usize)
+            .message(|_| format!("Block {} cut off", index))?;
+
+        let mut decrypted;
+        let data = if block.encrypted() {
+            let Some(decryptor) = decryptor else {
+                self.raise(format!("Block {} is encrypted", index))?
+            };
+            decrypted = data.to_vec();
+            decryptor
+                .decrypt(self.pkg_id, block, &mut decrypted)
+                .at(self)?;
+            &decrypted
+        } else {
+            data
+        };
+        if !block.compressed() {
+            return Ok(data.to_vec());
+        }
+        // the last block of a package can be shorter, and the stream doesn't store its size
+        let len = match crate::probe_size(data).at(self)? {
+            SizeProbe::Exact(len) => len.min(BLOCK_SIZE),
+            SizeProbe::AtMost(_) => BLOCK_SIZE,
+        };
+        let mut out = vec![0; len];
+        let written = crate::decompress(data, &mut out).at(self)?;
+        self.assert_eq(written, len)?;
+        Ok(out)
+    }
+}
+
+impl Archive for Package<'_> {
+    /// Entries are named by package and index, as the package doesn't hold names.
+    fn list(&self) -> Vec<ArchiveEntry> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let block = self.blocks.get(entry.starting_block as usize);
+                ArchiveEntry {
+                    path: format!("{:04x}-{:04x}", self.pkg_id, i),
+                    compressed_size: entry.size.into(),
+                    size: entry.size.into(),
+                    compression: match block {
+                        Some(block) if block.compressed() => Compression::Oodle,
+                        _ => Compression::None,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// The package is in memory, see [Package::read].
+    fn read_stored(&mut self, _index: usize) -> std::io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Reads the entry without a decryptor, failing on encrypted blocks.
+    fn decode(&self, index: usize, _stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(self.read_entry(self.entry(index)?, None)?)
+    }
+}
+
+impl ErrorContext for Package<'_> {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "package {:04x} patch {}",
+            self.pkg_id, self.patch_id
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{kraken_stream, sample};
+
+    /// Stands in for AES-GCM.
+    struct Xor(u8);
+
+    impl BlockDecryptor for Xor {
+        fn decrypt(
+            &mut self,
+            pkg_id: u16,
+            block: &PkgBlock,
+            data: &mut [u8],
+        ) -> std::io::Result<()> {
+            assert_eq!(pkg_id, 0x0123);
+            assert!(block.alternate_key());
+            data.iter_mut().for_each(|b| *b ^= self.0);
+            Ok(())
+        }
+    }
+
+    fn entry(table: &mut Vec<u8>, block: u64, offset: u64, size: u64) {
+        table.extend_from_slice(&0x8080_1234u32.to_le_bytes());
+        table.extend_from_slice(&(26u32 << 9 | 7 << 6).to_le_bytes());
+        table.extend_from_slice(&(block | (offset / 16) << 14 | size << 28).to_le_bytes());
+    }
+
+    fn block(table: &mut Vec<u8>, offset: usize, size: usize, patch_id: u16, flags: u16) {
+        table.extend_from_slice(&(offset as u32).to_le_bytes());
+        table.extend_from_slice(&(size as u32).to_le_bytes());
+        table.extend_from_slice(&patch_id.to_le_bytes());
+        table.extend_from_slice(&flags.to_le_bytes());
+        table.extend_from_slice(&[0; 36]);
+    }
+
+    #[test_log::test]
+    fn reads_package() {
+        let first = sample(BLOCK_SIZE, 0);
+        let second: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let third = b"Encrypted and compressed".repeat(40);
+        let first_block = kraken_stream(&first);
+        let third_block: Vec<u8> = kraken_stream(&third).iter().map(|b| b ^ 0x5A).collect();
+
+        let mut data = vec![0; HEADER_LEN];
+        let mut field = |offset: usize, value: u32| {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        field(PKG_ID, 0x0123);
+        field(PATCH_ID, 1);
+        field(ENTRY_TABLE_OFFSET, HEADER_LEN as u32);
+        field(ENTRY_COUNT, 2);
+        field(BLOCK_TABLE_OFFSET, (HEADER_LEN + 2 * ENTRY_LEN) as u32);
+        field(BLOCK_COUNT, 3);
+        let spanning = BLOCK_SIZE - 0x100 + 500;
+        entry(&mut data, 0, 0x100, spanning as u64);
+        entry(&mut data, 2, 0, third.len() as u64);
+        let start = HEADER_LEN + 2 * ENTRY_LEN + 3 * BLOCK_LEN;
+        block(&mut data, start, first_block.len(), 1, FLAG_COMPRESSED);
+        // stored in the file of the first patch
+        block(&mut data, 16, second.len(), 0, 0);
+        let flags = FLAG_COMPRESSED | FLAG_ENCRYPTED | FLAG_ALTERNATE_KEY;
+        block(
+            &mut data,
+            start + first_block.len(),
+            third_block.len(),
+            1,
+            flags,
+        );
+        data.extend(first_block);
+        data.extend(third_block);
+        let mut patch = vec![0xFF; 16];
+        patch.extend_from_slice(&second);
+
+        let mut package = Package::parse(&data).unwrap();
+        assert_eq!(package.pkg_id(), 0x0123);
+        assert_eq!(package.patch_id(), 1);
+        assert_eq!(package.blocks().len(), 3);
+        let entries = package.entries().to_vec();
+        assert_eq!(entries[0].file_type(), 26);
+        assert_eq!(entries[0].file_subtype(), 7);
+        assert_eq!(entries[0].starting_block_offset, 0x100);
+        assert_eq!(package.read_block(0, None).unwrap(), first);
+
+        assert!(package.read(&entries[0], None).is_err());
+        package.add_patch(0, &patch);
+        let mut expected = first[0x100..].to_vec();
+        expected.extend_from_slice(&second[..500]);
+        assert_eq!(package.read(&entries[0], None).unwrap(), expected);
+
+        let error = package.read(&entries[1], None).unwrap_err().to_string();
+        assert!(error.contains("encrypted"), "{}", error);
+        let out = package.read(&entries[1], Some(&mut Xor(0x5A))).unwrap();
+        assert_eq!(out, third);
+
+        let listed = package.list();
+        assert_eq!(listed[1].path, "0123-0001");
+        assert_eq!(listed[1].compression, Compression::Oodle);
+        assert_eq!(package.extract(0).unwrap(), expected);
+        assert!(package.extract(1).is_err());
+
+        assert!(Package::parse(&data[..HEADER_LEN]).is_err());
+    }
+}
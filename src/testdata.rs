@@ -0,0 +1,40 @@
+//! The streams in testdata/, shared by the tests and benches/pipelined.rs.
+use std::{fs, path::Path, path::PathBuf};
+
+/// The stream of testdata/|name|, after its size prefix, and the decompressed size.
+#[allow(dead_code)]
+pub fn stream(name: &str) -> (Vec<u8>, usize) {
+    read(
+        &Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name),
+    )
+}
+
+fn read(path: &Path) -> (Vec<u8>, usize) {
+    let mut data = fs::read(path).unwrap();
+    // 4 byte sizes are followed by the first block header
+    let start = if data[4] == 0x8C { 4 } else { 8 };
+    let mut len = [0; 8];
+    len[..start].copy_from_slice(&data[..start]);
+    data.drain(..start);
+    (data, usize::from_le_bytes(len))
+}
+
+/// The xml stream of each codec, one small file per codec to keep debug builds quick.
+/// Each is the path, the stream after its size prefix, and the decompressed size.
+pub fn xml_streams() -> Vec<(PathBuf, Vec<u8>, usize)> {
+    let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    d.push("testdata");
+    let mut streams = Vec::new();
+    for path in fs::read_dir(d).unwrap() {
+        let path = path.unwrap().path();
+        if path.file_stem().unwrap() != "xml" {
+            continue;
+        }
+        let (data, len) = read(&path);
+        streams.push((path, data, len));
+    }
+    streams.sort();
+    streams
+}
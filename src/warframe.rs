@@ -0,0 +1,340 @@
+//! Reader for Warframe `.cache` archives, indexed by a `.toc` file.
+//!
+//! The `.toc` is a list of fixed size records for files and directories. A compressed file
+//! is a series of blocks, each behind an 8 byte big endian header with its compressed and
+//! decompressed length. Blocks are Oodle streams without a size prefix, or the game's own
+//! LZ format, which this crate can't decompress.
+
+use crate::archive::{Archive, ArchiveEntry, Compression};
+use crate::core::error::{ErrorContext, Res, ResultBuilder, WithContext};
+use crate::core::reader::Reader;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+const MAGIC: u32 = 0x1867C64E;
+const VERSION: u32 = 20;
+const NAME_LEN: usize = 64;
+/// Offset of directory records.
+const DIRECTORY: i64 = -1;
+/// Block headers start with this bit set, files without it are a single LZ block.
+const BLOCK_MARKER: u32 = 0x8000_0000;
+const BLOCK_OODLE: u32 = 1;
+
+/// A file in a [Cache].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    /// Full path, starting with `/`.
+    pub path: String,
+    pub offset: u64,
+    /// Windows FILETIME of the last change.
+    pub timestamp: u64,
+    pub compressed_size: u32,
+    pub size: u32,
+}
+
+/// An open `.cache` archive.
+pub struct Cache<R: Read + Seek> {
+    /// Locked only by [Archive::list], the reads have it to themselves.
+    reader: Mutex<R>,
+    entries: Vec<CacheEntry>,
+    /// The compression of each entry, from the header of its first block. Only read when
+    /// the entries are listed, since it takes a seek per entry.
+    compression: OnceLock<Vec<Compression>>,
+}
+
+impl<R: Read + Seek> Cache<R> {
+    /// Reads the records from |toc|, for the data in |cache|.
+    pub fn open(mut toc: impl Read, cache: R) -> std::io::Result<Self> {
+        let mut data = Vec::new();
+        toc.read_to_end(&mut data)?;
+        let mut cache = Cache {
+            reader: Mutex::new(cache),
+            entries: Vec::new(),
+            compression: OnceLock::new(),
+        };
+        cache.read_toc(&data)?;
+        Ok(cache)
+    }
+
+    pub fn entries(&self) -> &[CacheEntry] {
+        &self.entries
+    }
+
+    pub fn find(&self, path: &str) -> Option<&CacheEntry> {
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Reads and decompresses the data of |entry|.
+    pub fn read(&mut self, entry: &CacheEntry) -> std::io::Result<Vec<u8>> {
+        Ok(self.read_entry(entry)?)
+    }
+
+    fn read_toc(&mut self, data: &[u8]) -> Res<()> {
+        let mut toc = Reader::new(data);
+        let magic = toc.u32()?;
+        self.assert_eq(magic, MAGIC)?;
+        let version = toc.u32()?;
+        self.assert_eq(version, VERSION)?;
+        // the root has no record
+        let mut directories = vec![String::new()];
+        while toc.remaining() > 0 {
+            let offset = toc.u64()? as i64;
+            let timestamp = toc.u64()?;
+            let compressed_size = toc.u32()?;
+            let size = toc.u32()?;
+            toc.u32()?;
+            let parent = toc.u32()?;
+            let name = toc.bytes(NAME_LEN)?;
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let name = String::from_utf8_lossy(name.get(..len).err()?);
+            let parent = directories.get(parent as usize).msg_of(&parent)?;
+            let path = format!("{}/{}", parent, name);
+            if offset == DIRECTORY {
+                directories.push(path);
+            } else {
+                self.entries.push(CacheEntry {
+                    path,
+                    offset: offset as u64,
+                    timestamp,
+                    compressed_size,
+                    size,
+                });
+            }
+        }
+        log::debug!(
+            "Read {} cache entries in {} directories",
+            self.entries.len(),
+            directories.len()
+        );
+        Ok(())
+    }
+
+    /// Peeks at the first block header of each compressed entry. Later blocks may still use
+    /// another format, which [Cache::read] reports, as it does for entries whose header
+    /// can't be read.
+    fn read_compression(&self) -> Vec<Compression> {
+        let mut reader = self.reader.lock().unwrap_or_else(PoisonError::into_inner);
+        self.entries
+            .iter()
+            .map(|entry| {
+                if entry.compressed_size == entry.size {
+                    return Compression::None;
+                }
+                self.peek_compression(&mut *reader, entry)
+                    .unwrap_or_else(|_| Compression::Other("unreadable".into()))
+            })
+            .collect()
+    }
+
+    fn peek_compression(&self, reader: &mut R, entry: &CacheEntry) -> Res<Compression> {
+        let mut header = [0; 8];
+        reader.seek(SeekFrom::Start(entry.offset)).at(self)?;
+        reader.read_exact(&mut header).at(self)?;
+        let mut header = Reader::new(&header);
+        let first = header.u32_be()?;
+        let block_type = header.u32_be()? & 0xF;
+        Ok(if first & BLOCK_MARKER == 0 {
+            Compression::Other("LZ".into())
+        } else if block_type != BLOCK_OODLE {
+            Compression::Other(format!("block type {}", block_type))
+        } else {
+            Compression::Oodle
+        })
+    }
+
+    fn entry(&self, index: usize) -> Res<&CacheEntry> {
+        Ok(self.entries.get(index).msg_of(&index)?)
+    }
+
+    fn read_entry(&mut self, entry: &CacheEntry) -> Res<Vec<u8>> {
+        let stored = self.read_stored_entry(entry)?;
+        self.decode_entry(entry, &stored)
+    }
+
+    fn read_stored_entry(&mut self, entry: &CacheEntry) -> Res<Vec<u8>> {
+        let mut data = vec![0; entry.compressed_size as usize];
+        let reader = self
+            .reader
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let read = reader
+            .seek(SeekFrom::Start(entry.offset))
+            .and_then(|_| reader.read_exact(&mut data));
+        read.at(self)
+            .message(|_| format!("Failed to read {}", entry.path))?;
+        Ok(data)
+    }
+
+    fn decode_entry(&self, entry: &CacheEntry, stored: &[u8]) -> Res<Vec<u8>> {
+        if entry.compressed_size == entry.size {
+            return Ok(stored.to_vec());
+        }
+        let mut out = Vec::with_capacity(entry.size as usize);
+        let mut blocks = Reader::new(stored);
+        while blocks.remaining() > 0 {
+            let first = blocks.u32_be()?;
+            let second = blocks.u32_be()?;
+            if first & BLOCK_MARKER == 0 {
+                self.raise(format!(
+                    "Unsupported block: {} is LZ compressed",
+                    entry.path
+                ))?
+            }
+            let compressed_len = (first >> 2) & 0xFF_FFFF;
+            let len = (second >> 5) & 0xFF_FFFF;
+            let block_type = second & 0xF;
+            if block_type != BLOCK_OODLE {
+                self.raise(format!(
+                    "Unsupported block: type {} at {} of {}",
+                    block_type,
+                    out.len(),
+                    entry.path
+                ))?
+            }
+            let block = blocks.bytes(compressed_len as usize)?;
+            let start = out.len();
+            out.resize(start + len as usize, 0);
+            let written = crate::decompress(block, out.get_mut(start..).err()?).at(self)?;
+            self.assert_eq(written, len as usize)?;
+        }
+        self.assert_eq(out.len(), entry.size as usize)?;
+        Ok(out)
+    }
+}
+
+impl<R: Read + Seek> Archive for Cache<R> {
+    fn list(&self) -> Vec<ArchiveEntry> {
+        self.entries
+            .iter()
+            .zip(self.compression.get_or_init(|| self.read_compression()))
+            .map(|(entry, compression)| ArchiveEntry {
+                path: entry.path.clone(),
+                compressed_size: entry.compressed_size.into(),
+                size: entry.size.into(),
+                compression: compression.clone(),
+            })
+            .collect()
+    }
+
+    fn read_stored(&mut self, index: usize) -> std::io::Result<Vec<u8>> {
+        let entry = self.entry(index)?.clone();
+        Ok(self.read_stored_entry(&entry)?)
+    }
+
+    fn decode(&self, index: usize, stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(self.decode_entry(self.entry(index)?, stored)?)
+    }
+}
+
+impl<R: Read + Seek> ErrorContext for Cache<R> {
+    fn describe(&self) -> Option<String> {
+        Some(format!("cache with {} entries", self.entries.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{kraken_stream, sample};
+    use std::io::Cursor;
+
+    fn record(toc: &mut Vec<u8>, offset: i64, sizes: [u32; 2], parent: u32, name: &str) {
+        toc.extend_from_slice(&offset.to_le_bytes());
+        toc.extend_from_slice(&0x01D9_0000_0000_0000u64.to_le_bytes());
+        toc.extend_from_slice(&sizes[0].to_le_bytes());
+        toc.extend_from_slice(&sizes[1].to_le_bytes());
+        toc.extend_from_slice(&0u32.to_le_bytes());
+        toc.extend_from_slice(&parent.to_le_bytes());
+        let mut field = [0; NAME_LEN];
+        field[..name.len()].copy_from_slice(name.as_bytes());
+        toc.extend_from_slice(&field);
+    }
+
+    /// Blocks of |data|, with |block_type| in the header.
+    fn blocks(data: &[u8], block_type: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        for block in data.chunks(0x10000) {
+            let stream = kraken_stream(block);
+            let first = BLOCK_MARKER | (stream.len() as u32) << 2;
+            let second = (block.len() as u32) << 5 | block_type;
+            out.extend_from_slice(&first.to_be_bytes());
+            out.extend_from_slice(&second.to_be_bytes());
+            out.extend(stream);
+        }
+        out
+    }
+
+    #[test_log::test]
+    fn reads_cache() {
+        let texture = sample(100_000, 0);
+        let files = [
+            (
+                "Rock.png",
+                1,
+                texture.clone(),
+                blocks(&texture, BLOCK_OODLE),
+            ),
+            ("Stored.txt", 0, b"Stored".to_vec(), b"Stored".to_vec()),
+            ("Lz.bin", 2, vec![0; 100], vec![0x12, 0, 0, 0, 0, 0, 0, 0]),
+            ("Other.bin", 2, vec![0; 100], blocks(&[0; 100], 2)),
+        ];
+        let mut toc = MAGIC.to_le_bytes().to_vec();
+        toc.extend_from_slice(&VERSION.to_le_bytes());
+        record(&mut toc, DIRECTORY, [0, 0], 0, "Lotus");
+        record(&mut toc, DIRECTORY, [0, 0], 1, "Textures");
+        let mut cache = Vec::new();
+        for (name, parent, data, stored) in &files {
+            let sizes = [stored.len() as u32, data.len() as u32];
+            record(&mut toc, cache.len() as i64, sizes, *parent, name);
+            cache.extend_from_slice(stored);
+        }
+
+        let cache_data = cache.clone();
+        let mut cache = Cache::open(toc.as_slice(), Cursor::new(cache)).unwrap();
+        let paths: Vec<String> = cache.entries().iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/Lotus/Rock.png",
+                "/Stored.txt",
+                "/Lotus/Textures/Lz.bin",
+                "/Lotus/Textures/Other.bin"
+            ]
+        );
+        assert_eq!(cache.entries()[0].timestamp, 0x01D9_0000_0000_0000);
+        for (path, (_, _, data, _)) in paths.iter().zip(&files).take(2) {
+            let entry = cache.find(path).unwrap().clone();
+            assert_eq!(cache.read(&entry).unwrap(), *data, "{}", path);
+        }
+        for path in &paths[2..] {
+            let entry = cache.find(path).unwrap().clone();
+            let error = cache.read(&entry).unwrap_err().to_string();
+            assert!(error.contains("Unsupported block"), "{}", error);
+        }
+
+        let listed = cache.list();
+        assert_eq!(listed[0].compression, Compression::Oodle);
+        assert_eq!(listed[1].compression, Compression::None);
+        assert_eq!(listed[2].compression, Compression::Other("LZ".into()));
+        assert_eq!(
+            listed[3].compression,
+            Compression::Other("block type 2".into())
+        );
+        assert_eq!(cache.extract(0).unwrap(), texture);
+
+        // entries past the end of the cache are only reported when they're read
+        let mut short = cache_data;
+        short.truncate(files[0].3.len() + 6);
+        let mut cache = Cache::open(toc.as_slice(), Cursor::new(short)).unwrap();
+        let listed = cache.list();
+        assert_eq!(listed[0].compression, Compression::Oodle);
+        assert_eq!(
+            listed[2].compression,
+            Compression::Other("unreadable".into())
+        );
+        assert!(cache.extract(2).is_err());
+
+        toc[4] = 19;
+        assert!(Cache::open(toc.as_slice(), Cursor::new(Vec::new())).is_err());
+    }
+}